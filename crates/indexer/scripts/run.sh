@@ -8,5 +8,5 @@ if [[ -v $DATABASE_URL ]]; then
     --config-file crates/indexer/diesel.toml
 fi
 
-exec cargo watch -w crates -x "run --bin indexer"
+exec cargo watch -w crates -x "run --bin indexer -- --config crates/indexer/config/config.toml"
 
@@ -0,0 +1,204 @@
+use std::time::Instant;
+
+use stash_api::common::{ChangeId, SHARD_COUNT};
+
+use crate::config::{BackfillConfig, BackfillEnd};
+
+/// Re-indexes a window of the river, ie. after a sink outage, instead of following its head.
+#[derive(Debug)]
+pub struct Backfill {
+    start: ChangeId,
+    end: BackfillEnd,
+    started_at: Instant,
+    head: Option<ChangeId>,
+}
+
+/// What to do with a page the indexer handed out during a backfill.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackfillAction {
+    /// The page lies within the window.
+    Index,
+    /// The page lies past the window, which is complete now. The indexer has to be stopped.
+    Stop,
+    /// The page lies past the window and has been requested before the indexer stopped.
+    Skip,
+}
+
+impl Backfill {
+    pub fn new(config: &BackfillConfig, started_at: Instant) -> Self {
+        Self {
+            start: config.start.clone(),
+            end: config.end.clone(),
+            started_at,
+            head: None,
+        }
+    }
+
+    /// Ends the window at `head` as well, ie. the head of the river when the backfill started.
+    /// Pages are empty from the head on, so an end past it would never be reached.
+    pub fn set_head(&mut self, head: ChangeId) {
+        self.head = Some(head);
+    }
+
+    pub fn start(&self) -> &ChangeId {
+        &self.start
+    }
+
+    /// Whether `change_id`, ie. from the state file of an earlier run, lies within the window
+    /// and can be continued from.
+    pub fn contains(&self, change_id: &ChangeId) -> bool {
+        let is_behind_start = change_id.delta(&self.start).shards.iter().any(|d| *d < 0);
+        !is_behind_start && !self.is_complete(change_id)
+    }
+
+    /// Whether the window has been indexed once the indexer got to `change_id`.
+    pub fn is_complete(&self, change_id: &ChangeId) -> bool {
+        match &self.end {
+            BackfillEnd::ChangeId(end) => has_reached(change_id, &self.bounded_by_head(end)),
+            BackfillEnd::MaxRuntime(max_runtime) => {
+                self.started_at.elapsed() >= *max_runtime
+                    || self
+                        .head
+                        .as_ref()
+                        .is_some_and(|head| has_reached(change_id, head))
+            }
+        }
+    }
+
+    /// What to do with the page for `change_id`, given whether the indexer has been stopped.
+    pub fn on_tick(&self, change_id: &ChangeId, is_stopped: bool) -> BackfillAction {
+        match (self.is_complete(change_id), is_stopped) {
+            (false, _) => BackfillAction::Index,
+            (true, false) => BackfillAction::Stop,
+            (true, true) => BackfillAction::Skip,
+        }
+    }
+
+    /// How much of the window has been indexed, between `0.0` and `1.0`.
+    pub fn progress(&self, change_id: &ChangeId) -> f64 {
+        match &self.end {
+            BackfillEnd::ChangeId(end) => {
+                let total = self.bounded_by_head(end).delta(&self.start);
+                let done = change_id.delta(&self.start);
+                let (mut total_updates, mut done_updates) = (0, 0);
+                for shard in 0..SHARD_COUNT {
+                    let total = total.shards[shard].max(0);
+                    total_updates += total;
+                    done_updates += done.shards[shard].clamp(0, total);
+                }
+
+                if total_updates == 0 {
+                    return 1.0;
+                }
+                done_updates as f64 / total_updates as f64
+            }
+            BackfillEnd::MaxRuntime(max_runtime) => {
+                if max_runtime.is_zero() {
+                    return 1.0;
+                }
+                (self.started_at.elapsed().as_secs_f64() / max_runtime.as_secs_f64()).min(1.0)
+            }
+        }
+    }
+
+    /// `end`, with every shard that lies past the head moved back to it.
+    fn bounded_by_head(&self, end: &ChangeId) -> ChangeId {
+        let mut shards = *end.shards();
+        if let Some(head) = &self.head {
+            for (shard, head) in shards.iter_mut().zip(head.shards()) {
+                *shard = (*shard).min(*head);
+            }
+        }
+        ChangeId::from_shards(shards)
+    }
+}
+
+/// Whether every shard of `change_id` got to `target`.
+fn has_reached(change_id: &ChangeId, target: &ChangeId) -> bool {
+    target.delta(change_id).shards.iter().all(|d| *d <= 0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
+    use stash_api::common::ChangeId;
+
+    use super::{Backfill, BackfillAction};
+    use crate::config::{BackfillConfig, BackfillEnd};
+
+    fn id(s: &str) -> ChangeId {
+        ChangeId::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_backfill_between_change_ids() {
+        let backfill = Backfill::new(
+            &BackfillConfig {
+                start: id("100-100-100-100-100"),
+                end: BackfillEnd::ChangeId(id("110-100-130-100-100")),
+                state_path: "backfill_state.json".into(),
+            },
+            Instant::now(),
+        );
+
+        assert_eq!(backfill.progress(&id("100-100-100-100-100")), 0.0);
+        assert_eq!(backfill.progress(&id("110-105-100-100-100")), 0.25);
+        assert!(!backfill.is_complete(&id("120-100-120-100-100")));
+        assert!(backfill.contains(&id("120-100-120-100-100")));
+
+        assert!(backfill.is_complete(&id("120-100-130-101-100")));
+        assert_eq!(backfill.progress(&id("120-100-130-101-100")), 1.0);
+        assert!(!backfill.contains(&id("120-100-130-101-100")));
+        assert!(!backfill.contains(&id("99-100-100-100-100")));
+    }
+
+    #[test]
+    fn test_backfill_bounded_by_head() {
+        let mut backfill = Backfill::new(
+            &BackfillConfig {
+                start: id("100-100-100-100-100"),
+                end: BackfillEnd::ChangeId(id("110-100-130-100-100")),
+                state_path: "backfill_state.json".into(),
+            },
+            Instant::now(),
+        );
+        backfill.set_head(id("110-100-110-100-100"));
+
+        assert_eq!(backfill.progress(&id("105-100-105-100-100")), 0.5);
+        assert!(backfill.is_complete(&id("110-100-110-100-100")));
+        assert_eq!(
+            backfill.on_tick(&id("110-100-110-100-100"), false),
+            BackfillAction::Stop
+        );
+    }
+
+    #[test]
+    fn test_backfill_with_exceeded_max_runtime() {
+        let config = BackfillConfig {
+            start: id("100-100-100-100-100"),
+            end: BackfillEnd::MaxRuntime(Duration::from_secs(60)),
+            state_path: "backfill_state.json".into(),
+        };
+        let backfill = Backfill::new(&config, Instant::now());
+        assert_eq!(
+            backfill.on_tick(&id("100-100-100-100-100"), false),
+            BackfillAction::Index
+        );
+
+        // The page that arrives after the runtime is up stops the indexer, later ones are skipped
+        let backfill = Backfill::new(&config, Instant::now() - Duration::from_secs(61));
+        assert_eq!(
+            backfill.on_tick(&id("100-100-100-100-100"), false),
+            BackfillAction::Stop
+        );
+        assert_eq!(
+            backfill.on_tick(&id("101-100-100-100-100"), true),
+            BackfillAction::Skip
+        );
+        assert_eq!(backfill.progress(&id("101-100-100-100-100")), 1.0);
+    }
+}
@@ -1,43 +1,423 @@
-use self::user_config::UserConfiguration;
+use std::{fmt::Display, path::PathBuf, str::FromStr, time::Duration};
+
+use clap::Parser;
+use config::{File, Source};
+use serde::Deserialize;
+use stash_api::common::ChangeId;
+
+use self::user_config::{Filter, RestartMode, UserConfiguration};
+
+/// Where the configuration file is read from unless `--config` points somewhere else.
+const DEFAULT_CONFIG_PATH: &str = "config/config.toml";
+
+/// Environment variables and the settings they override.
+const ENV_VARS: [(&str, &str); 41] = [
+    ("RESTART_MODE", "restart_mode"),
+    ("DATABASE_URL", "database_url"),
+    ("METRICS_PORT", "metrics_port"),
+    ("POE_CLIENT_ID", "client_id"),
+    ("POE_CLIENT_SECRET", "client_secret"),
+    ("POE_API_BASE_URL", "api_base_url"),
+    ("MAX_IN_FLIGHT_REQUESTS", "max_in_flight_requests"),
+    ("MAX_FETCH_ATTEMPTS", "max_fetch_attempts"),
+    ("LAG_HEAD_URL", "lag_head_url"),
+    ("LAG_POLL_INTERVAL", "lag_poll_interval"),
+    ("PARALLEL_CATCH_UP", "parallel_catch_up"),
+    ("STATE_PATH", "state_path"),
+    ("CHECKPOINT_TICKS", "checkpoint_ticks"),
+    ("CHECKPOINT_SECONDS", "checkpoint_seconds"),
+    ("RABBITMQ_SINK_ENABLED", "rabbitmq.enabled"),
+    ("RABBITMQ_URL", "rabbitmq.url"),
+    (
+        "RABBITMQ_PRODUCER_ROUTING_KEY",
+        "rabbitmq.producer_routing_key",
+    ),
+    ("FILE_SINK_ENABLED", "file.enabled"),
+    ("FILE_SINK_DIRECTORY", "file.directory"),
+    ("FILE_SINK_MAX_BYTES", "file.max_bytes"),
+    ("FILE_SINK_ROTATE_HOURLY", "file.rotate_hourly"),
+    ("FILE_SINK_COMPRESSION", "file.compression"),
+    ("PARQUET_SINK_ENABLED", "parquet.enabled"),
+    ("PARQUET_SINK_DIRECTORY", "parquet.directory"),
+    ("PARQUET_SINK_MAX_ROWS", "parquet.max_rows"),
+    ("S3_SINK_ENABLED", "s3.enabled"),
+    ("S3_SINK_ENDPOINT", "s3.endpoint"),
+    ("S3_SINK_REGION", "s3.region"),
+    ("S3_SINK_BUCKET", "s3.bucket"),
+    ("S3_SINK_PREFIX", "s3.prefix"),
+    ("S3_SINK_ACCESS_KEY_ID", "s3.access_key_id"),
+    ("S3_SINK_SECRET_ACCESS_KEY", "s3.secret_access_key"),
+    ("S3_SINK_OBJECT_BYTES", "s3.object_bytes"),
+    ("S3_SINK_OBJECT_SECONDS", "s3.object_seconds"),
+    ("S3_SINK_PART_BYTES", "s3.part_bytes"),
+    ("S3_SINK_MAX_ATTEMPTS", "s3.max_attempts"),
+    ("S3_SINK_COMPRESSION", "s3.compression"),
+    ("BACKFILL_START", "backfill.start"),
+    ("BACKFILL_END", "backfill.end"),
+    ("BACKFILL_MAX_RUNTIME", "backfill.max_runtime"),
+    ("BACKFILL_STATE_PATH", "backfill.state_path"),
+];
+
+/// Environment variables holding comma separated lists and the settings they override.
+const LIST_ENV_VARS: [(&str, &str); 2] = [
+    ("FILTER_LEAGUES", "filter.leagues"),
+    ("FILTER_ITEM_CATEGORIES", "filter.item_categories"),
+];
+
+/// Indexes the public stash river into the configured sinks.
+///
+/// Settings are read from the configuration file first, then from environment variables and
+/// finally from `--set` flags, each overriding the former.
+#[derive(Debug, Default, Parser)]
+#[command(about)]
+pub struct Args {
+    /// The TOML file to read the configuration from [default: config/config.toml, if it exists]
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    /// Overrides a single setting, ie. `--set metrics_port=4001` or `--set rabbitmq.enabled=false`
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<(String, String)>,
+}
+
+impl Args {
+    /// The configuration file that is read, whether it exists or not.
+    pub fn config_path(&self) -> PathBuf {
+        self.config
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CONFIG_PATH.into())
+    }
+}
+
+fn parse_override(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_owned(), value.trim().to_owned()))
+        }
+        _ => Err(format!("Expected KEY=VALUE, got \"{s}\"")),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigurationError {
+    /// The configuration file, an environment variable or a flag could not be read or parsed
+    Load(config::ConfigError),
+    Missing(&'static str),
+    Invalid {
+        key: &'static str,
+        reason: String,
+    },
+}
+
+impl Display for ConfigurationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Load(e) => write!(f, "{e}"),
+            Self::Missing(key) => match env_var_of(key) {
+                Some(name) => write!(f, "Missing setting {key} (or environment variable {name})"),
+                None => write!(f, "Missing setting {key}"),
+            },
+            Self::Invalid { key, reason } => write!(f, "Invalid setting {key}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigurationError {}
+
+impl From<config::ConfigError> for ConfigurationError {
+    fn from(e: config::ConfigError) -> Self {
+        Self::Load(e)
+    }
+}
+
+fn env_var_of(key: &str) -> Option<&'static str> {
+    ENV_VARS
+        .iter()
+        .find(|(_, setting)| *setting == key)
+        .map(|(name, _)| *name)
+}
 
 #[derive(Debug)]
 pub struct Configuration {
     pub user_config: UserConfiguration,
     pub database_url: Option<String>,
     pub rabbitmq: Option<RabbitMqConfig>,
+    pub file: Option<FileSinkConfig>,
+    pub parquet: Option<ParquetSinkConfig>,
+    pub s3: Option<S3SinkConfig>,
     pub metrics_port: u32,
     pub client_id: String,
     pub client_secret: String,
+    /// Overrides the base URL of all remote APIs, ie. to run against a local mock river
+    pub api_base_url: Option<String>,
+    /// How many river pages are requested concurrently
+    pub max_in_flight_requests: Option<u32>,
+    /// How many attempts are made at a river page before the indexer gives up
+    pub max_fetch_attempts: Option<u32>,
+    /// Where to poll the latest change id from to tell how far behind the indexer is.
+    /// Defaults to poe.ninja
+    pub lag_head_url: Option<String>,
+    /// How often the latest change id is polled, in seconds
+    pub lag_poll_interval: u32,
+    /// Whether to catch up with the head of the river by walking all shards in parallel
+    pub parallel_catch_up: bool,
+    /// Re-indexes a window of the river and exits instead of following its head
+    pub backfill: Option<BackfillConfig>,
+    /// Where the resumption state is kept
+    pub state_path: String,
+    /// Writes the resumption state every that many ticks, zero only writes it on shutdown
+    pub checkpoint_ticks: u32,
+    /// Writes the resumption state every that many seconds, zero only writes it on shutdown
+    pub checkpoint_seconds: u64,
 }
 
 impl Configuration {
-    pub fn from_env() -> Result<Configuration, std::env::VarError> {
-        Ok(Configuration {
-            database_url: read_string_from_env("DATABASE_URL"),
-            metrics_port: read_int_from_env("METRICS_PORT").unwrap_or(4000),
-            rabbitmq: RabbitMqConfig::from_env()?,
-            user_config: UserConfiguration::default(),
-            client_id: ensure_string_from_env("POE_CLIENT_ID"),
-            client_secret: ensure_string_from_env("POE_CLIENT_SECRET"),
-        })
+    pub fn load(args: &Args) -> Result<Configuration, ConfigurationError> {
+        let file = match &args.config {
+            Some(path) => File::from(path.as_path()).required(true),
+            None => File::with_name(DEFAULT_CONFIG_PATH).required(false),
+        };
+
+        Self::from_sources(file, |name| std::env::var(name).ok(), &args.overrides)
+    }
+
+    fn from_sources(
+        file: impl Source + Send + Sync + 'static,
+        env: impl Fn(&str) -> Option<String>,
+        overrides: &[(String, String)],
+    ) -> Result<Configuration, ConfigurationError> {
+        let mut builder = config::Config::builder().add_source(file);
+
+        // Empty variables, ie. the placeholders of .env.template, count as unset
+        let env = |name: &str| env(name).filter(|value| !value.is_empty());
+        for (name, key) in ENV_VARS {
+            if let Some(value) = env(name) {
+                builder = builder.set_override(key, value)?;
+            }
+        }
+        for (name, key) in LIST_ENV_VARS {
+            if let Some(value) = env(name) {
+                let list = value
+                    .split(',')
+                    .map(|entry| entry.trim().to_owned())
+                    .filter(|entry| !entry.is_empty())
+                    .collect::<Vec<_>>();
+                builder = builder.set_override(key, list)?;
+            }
+        }
+        for (key, value) in overrides {
+            builder = builder.set_override(key.as_str(), value.as_str())?;
+        }
+
+        let settings = builder.build()?.try_deserialize::<Settings>()?;
+        settings.validate()
+    }
+}
+
+/// Everything that can be configured, before validation.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct Settings {
+    restart_mode: RestartMode,
+    filter: Filter,
+    database_url: Option<String>,
+    metrics_port: u32,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    api_base_url: Option<String>,
+    max_in_flight_requests: Option<u32>,
+    max_fetch_attempts: Option<u32>,
+    lag_head_url: Option<String>,
+    lag_poll_interval: u32,
+    parallel_catch_up: bool,
+    state_path: String,
+    checkpoint_ticks: u32,
+    checkpoint_seconds: u64,
+    rabbitmq: RabbitMqSettings,
+    file: FileSinkSettings,
+    parquet: ParquetSinkSettings,
+    s3: S3SinkSettings,
+    backfill: BackfillSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            restart_mode: RestartMode::Fresh,
+            filter: Filter::default(),
+            database_url: None,
+            metrics_port: 4000,
+            client_id: None,
+            client_secret: None,
+            api_base_url: None,
+            max_in_flight_requests: None,
+            max_fetch_attempts: None,
+            lag_head_url: None,
+            lag_poll_interval: 60,
+            parallel_catch_up: false,
+            state_path: "./indexer_state.json".into(),
+            checkpoint_ticks: 10,
+            checkpoint_seconds: 30,
+            rabbitmq: RabbitMqSettings::default(),
+            file: FileSinkSettings::default(),
+            parquet: ParquetSinkSettings::default(),
+            s3: S3SinkSettings::default(),
+            backfill: BackfillSettings::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RabbitMqSettings {
+    enabled: bool,
+    url: Option<String>,
+    producer_routing_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct FileSinkSettings {
+    enabled: bool,
+    directory: Option<String>,
+    max_bytes: u64,
+    rotate_hourly: bool,
+    compression: FileCompression,
+}
+
+impl Default for FileSinkSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: None,
+            max_bytes: 256 * 1024 * 1024,
+            rotate_hourly: true,
+            compression: FileCompression::None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct ParquetSinkSettings {
+    enabled: bool,
+    directory: Option<String>,
+    max_rows: usize,
+}
+
+impl Default for ParquetSinkSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: None,
+            max_rows: 1_000_000,
+        }
     }
 }
 
-fn ensure_string_from_env(name: &str) -> String {
-    std::env::var(name).unwrap_or_else(|_| panic!("Missing environment variable {}", name))
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct S3SinkSettings {
+    enabled: bool,
+    endpoint: Option<String>,
+    region: String,
+    bucket: Option<String>,
+    prefix: String,
+    access_key_id: Option<String>,
+    secret_access_key: Option<String>,
+    object_bytes: usize,
+    object_seconds: u64,
+    part_bytes: usize,
+    max_attempts: u32,
+    compression: FileCompression,
 }
 
-fn read_string_from_env(name: &str) -> Option<String> {
-    std::env::var(name).ok()
+impl Default for S3SinkSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: None,
+            region: "us-east-1".into(),
+            bucket: None,
+            prefix: String::new(),
+            access_key_id: None,
+            secret_access_key: None,
+            object_bytes: 64 * 1024 * 1024,
+            object_seconds: 300,
+            part_bytes: 16 * 1024 * 1024,
+            max_attempts: 5,
+            compression: FileCompression::Zstd,
+        }
+    }
 }
 
-#[allow(dead_code)]
-fn ensure_int_from_env(name: &str) -> u32 {
-    ensure_string_from_env(name).parse().unwrap()
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct BackfillSettings {
+    start: Option<String>,
+    end: Option<String>,
+    /// In seconds
+    max_runtime: Option<u64>,
+    state_path: String,
 }
 
-fn read_int_from_env(name: &str) -> Option<u32> {
-    std::env::var(name).map(|s| s.parse::<u32>().unwrap()).ok()
+impl Default for BackfillSettings {
+    fn default() -> Self {
+        Self {
+            start: None,
+            end: None,
+            max_runtime: None,
+            state_path: "./backfill_state.json".into(),
+        }
+    }
+}
+
+impl Settings {
+    fn validate(self) -> Result<Configuration, ConfigurationError> {
+        let client_id = self
+            .client_id
+            .ok_or(ConfigurationError::Missing("client_id"))?;
+        let client_secret = self
+            .client_secret
+            .ok_or(ConfigurationError::Missing("client_secret"))?;
+
+        if self.lag_poll_interval == 0 {
+            return Err(ConfigurationError::Invalid {
+                key: "lag_poll_interval",
+                reason: "has to be at least one second".into(),
+            });
+        }
+        if self.max_in_flight_requests == Some(0) {
+            return Err(ConfigurationError::Invalid {
+                key: "max_in_flight_requests",
+                reason: "has to be at least 1".into(),
+            });
+        }
+
+        Ok(Configuration {
+            user_config: UserConfiguration {
+                filter: self.filter,
+                restart_mode: self.restart_mode,
+            },
+            database_url: self.database_url.filter(|url| !url.is_empty()),
+            rabbitmq: self.rabbitmq.validate()?,
+            file: self.file.validate()?,
+            parquet: self.parquet.validate()?,
+            s3: self.s3.validate()?,
+            metrics_port: self.metrics_port,
+            client_id,
+            client_secret,
+            api_base_url: self.api_base_url,
+            max_in_flight_requests: self.max_in_flight_requests,
+            max_fetch_attempts: self.max_fetch_attempts,
+            lag_head_url: self.lag_head_url,
+            lag_poll_interval: self.lag_poll_interval,
+            parallel_catch_up: self.parallel_catch_up,
+            backfill: self.backfill.validate()?,
+            state_path: self.state_path,
+            checkpoint_ticks: self.checkpoint_ticks,
+            checkpoint_seconds: self.checkpoint_seconds,
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -46,28 +426,243 @@ pub struct RabbitMqConfig {
     pub producer_routing_key: String,
 }
 
-impl RabbitMqConfig {
-    pub fn from_env() -> Result<Option<RabbitMqConfig>, std::env::VarError> {
-        if let Ok(string) = std::env::var("RABBITMQ_SINK_ENABLED") {
-            if string.to_lowercase().eq("false") || string.eq("0") {
-                return Ok(None);
-            }
+impl RabbitMqSettings {
+    fn validate(self) -> Result<Option<RabbitMqConfig>, ConfigurationError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        Ok(Some(RabbitMqConfig {
+            connection_url: self
+                .url
+                .ok_or(ConfigurationError::Missing("rabbitmq.url"))?,
+            producer_routing_key: self
+                .producer_routing_key
+                .ok_or(ConfigurationError::Missing("rabbitmq.producer_routing_key"))?,
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileSinkConfig {
+    pub directory: PathBuf,
+    /// Starts a new file once the current one got this large
+    pub max_bytes: u64,
+    /// Starts a new file for every hour the records were created in
+    pub rotate_hourly: bool,
+    /// How closed files are compressed
+    pub compression: FileCompression,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FileCompression {
+    /// The extension appended to compressed files, without the leading dot.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            FileCompression::None => None,
+            FileCompression::Gzip => Some("gz"),
+            FileCompression::Zstd => Some("zst"),
+        }
+    }
+}
+
+impl FileSinkSettings {
+    fn validate(self) -> Result<Option<FileSinkConfig>, ConfigurationError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        if self.max_bytes == 0 {
+            return Err(ConfigurationError::Invalid {
+                key: "file.max_bytes",
+                reason: "has to be at least 1".into(),
+            });
+        }
+
+        Ok(Some(FileSinkConfig {
+            directory: self
+                .directory
+                .ok_or(ConfigurationError::Missing("file.directory"))?
+                .into(),
+            max_bytes: self.max_bytes,
+            rotate_hourly: self.rotate_hourly,
+            compression: self.compression,
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParquetSinkConfig {
+    pub directory: PathBuf,
+    /// Closes a file once it holds this many items
+    pub max_rows: usize,
+}
+
+impl ParquetSinkSettings {
+    fn validate(self) -> Result<Option<ParquetSinkConfig>, ConfigurationError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        if self.max_rows == 0 {
+            return Err(ConfigurationError::Invalid {
+                key: "parquet.max_rows",
+                reason: "has to be at least 1".into(),
+            });
+        }
 
-            let connection_url =
-                std::env::var("RABBITMQ_URL").expect("RABBITMQ_URL environment variable");
-            let producer_routing_key = std::env::var("RABBITMQ_PRODUCER_ROUTING_KEY")
-                .expect("RABBITMQ_PRODUCER_ROUTING_KEY environment variable");
+        Ok(Some(ParquetSinkConfig {
+            directory: self
+                .directory
+                .ok_or(ConfigurationError::Missing("parquet.directory"))?
+                .into(),
+            max_rows: self.max_rows,
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct S3SinkConfig {
+    /// The base URL of the S3-compatible service, objects are addressed path-style below it
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Prepended to every object key as is, ie. `stash-records/`
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Uploads the current object once it got this large, after compression
+    pub object_bytes: usize,
+    /// Uploads the current object once it has been collected for this long
+    pub object_seconds: u64,
+    /// Objects larger than this are uploaded in parts of this size
+    pub part_bytes: usize,
+    /// How many attempts are made at every request before the sink fails
+    pub max_attempts: u32,
+    pub compression: FileCompression,
+}
+
+/// The smallest part S3 accepts, besides the last one of an upload.
+const MIN_PART_BYTES: usize = 5 * 1024 * 1024;
+
+impl S3SinkSettings {
+    fn validate(self) -> Result<Option<S3SinkConfig>, ConfigurationError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        let endpoint = self
+            .endpoint
+            .ok_or(ConfigurationError::Missing("s3.endpoint"))?;
+        if let Err(e) = reqwest::Url::parse(&endpoint) {
+            return Err(ConfigurationError::Invalid {
+                key: "s3.endpoint",
+                reason: e.to_string(),
+            });
+        }
+
+        if self.part_bytes < MIN_PART_BYTES {
+            return Err(ConfigurationError::Invalid {
+                key: "s3.part_bytes",
+                reason: format!("has to be at least {MIN_PART_BYTES}"),
+            });
+        }
+
+        if self.object_bytes == 0 {
+            return Err(ConfigurationError::Invalid {
+                key: "s3.object_bytes",
+                reason: "has to be at least 1".into(),
+            });
+        }
 
-            Ok(Some(RabbitMqConfig {
-                connection_url,
-                producer_routing_key,
-            }))
-        } else {
-            Ok(None)
+        if self.max_attempts == 0 {
+            return Err(ConfigurationError::Invalid {
+                key: "s3.max_attempts",
+                reason: "has to be at least 1".into(),
+            });
         }
+
+        Ok(Some(S3SinkConfig {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            region: self.region,
+            bucket: self
+                .bucket
+                .ok_or(ConfigurationError::Missing("s3.bucket"))?,
+            prefix: self.prefix,
+            access_key_id: self
+                .access_key_id
+                .ok_or(ConfigurationError::Missing("s3.access_key_id"))?,
+            secret_access_key: self
+                .secret_access_key
+                .ok_or(ConfigurationError::Missing("s3.secret_access_key"))?,
+            object_bytes: self.object_bytes,
+            object_seconds: self.object_seconds,
+            part_bytes: self.part_bytes,
+            max_attempts: self.max_attempts,
+            compression: self.compression,
+        }))
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct BackfillConfig {
+    pub start: ChangeId,
+    pub end: BackfillEnd,
+    /// Where the progress of the backfill is kept, separate from the state of the live run
+    pub state_path: String,
+}
+
+/// Where a backfill stops.
+#[derive(Debug, Clone)]
+pub enum BackfillEnd {
+    /// Once every shard got to this change id
+    ChangeId(ChangeId),
+    /// Once the indexer has been running the backfill for this long, measured by the wall clock
+    /// rather than by the age of the indexed pages
+    MaxRuntime(Duration),
+}
+
+impl BackfillSettings {
+    fn validate(self) -> Result<Option<BackfillConfig>, ConfigurationError> {
+        let start = match self.start {
+            Some(start) => parse_change_id("backfill.start", &start)?,
+            None => return Ok(None),
+        };
+
+        let end = match (self.end, self.max_runtime) {
+            (Some(end), None) => BackfillEnd::ChangeId(parse_change_id("backfill.end", &end)?),
+            (None, Some(seconds)) => BackfillEnd::MaxRuntime(Duration::from_secs(seconds)),
+            _ => {
+                return Err(ConfigurationError::Invalid {
+                    key: "backfill",
+                    reason: "requires either backfill.end or backfill.max_runtime".into(),
+                })
+            }
+        };
+
+        Ok(Some(BackfillConfig {
+            start,
+            end,
+            state_path: self.state_path,
+        }))
+    }
+}
+
+fn parse_change_id(key: &'static str, value: &str) -> Result<ChangeId, ConfigurationError> {
+    ChangeId::from_str(value).map_err(|e| ConfigurationError::Invalid {
+        key,
+        reason: e.to_string(),
+    })
+}
+
 pub mod user_config {
 
     use serde::Deserialize;
@@ -78,7 +673,7 @@ pub mod user_config {
         pub restart_mode: RestartMode,
     }
 
-    #[derive(Debug, Deserialize, Clone)]
+    #[derive(Debug, Deserialize, Clone, Default)]
     pub struct Filter {
         pub item_categories: Option<Vec<String>>,
         pub leagues: Option<Vec<String>>,
@@ -99,10 +694,7 @@ pub mod user_config {
     impl Default for UserConfiguration {
         fn default() -> Self {
             Self {
-                filter: Filter {
-                    item_categories: None,
-                    leagues: None,
-                },
+                filter: Filter::default(),
                 restart_mode: RestartMode::Fresh,
             }
         }
@@ -167,3 +759,141 @@ pub mod user_config {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use config::{File, FileFormat};
+
+    use super::{
+        user_config::RestartMode, BackfillEnd, Configuration, ConfigurationError, FileCompression,
+    };
+
+    const CONFIG_TOML: &str = r#"
+        restart_mode = "Resume"
+        client_id = "file-client"
+        client_secret = "file-secret"
+        metrics_port = 4001
+
+        [filter]
+        leagues = ["Standard"]
+
+        [rabbitmq]
+        enabled = true
+        url = "amqp://localhost"
+        producer_routing_key = "stashes"
+    "#;
+
+    fn load(
+        toml: &str,
+        env: &[(&str, &str)],
+        overrides: &[(&str, &str)],
+    ) -> Result<Configuration, ConfigurationError> {
+        let overrides = overrides
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+
+        Configuration::from_sources(
+            File::from_str(toml, FileFormat::Toml),
+            |name| {
+                env.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, value)| value.to_string())
+            },
+            &overrides,
+        )
+    }
+
+    #[test]
+    fn test_layers_override_each_other() {
+        let config = load(
+            CONFIG_TOML,
+            &[
+                ("POE_CLIENT_ID", "env-client"),
+                ("METRICS_PORT", "4002"),
+                ("FILTER_ITEM_CATEGORIES", "currency, maps"),
+                ("DATABASE_URL", ""),
+            ],
+            &[
+                ("metrics_port", "4003"),
+                ("rabbitmq.enabled", "false"),
+                ("file.enabled", "true"),
+                ("file.directory", "archive"),
+                ("file.compression", "zstd"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.user_config.restart_mode, RestartMode::Resume);
+        assert_eq!(
+            config.user_config.filter.leagues,
+            Some(vec!["Standard".to_string()])
+        );
+        assert_eq!(
+            config.user_config.filter.item_categories,
+            Some(vec!["currency".to_string(), "maps".to_string()])
+        );
+        assert_eq!(config.client_id, "env-client");
+        assert_eq!(config.client_secret, "file-secret");
+        assert_eq!(config.metrics_port, 4003);
+        assert_eq!(config.lag_poll_interval, 60);
+        assert!(config.database_url.is_none());
+        assert!(config.rabbitmq.is_none());
+
+        let file = config.file.unwrap();
+        assert_eq!(file.compression, FileCompression::Zstd);
+        assert!(file.rotate_hourly);
+    }
+
+    #[test]
+    fn test_missing_settings_name_their_environment_variable() {
+        let error = load("", &[], &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Missing setting client_id (or environment variable POE_CLIENT_ID)"
+        );
+
+        let error = load(
+            "[rabbitmq]\nenabled = true",
+            &[("POE_CLIENT_ID", "client"), ("POE_CLIENT_SECRET", "secret")],
+            &[],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Missing setting rabbitmq.url (or environment variable RABBITMQ_URL)"
+        );
+    }
+
+    #[test]
+    fn test_invalid_settings_are_reported() {
+        let error = load(CONFIG_TOML, &[], &[("metrics_port", "many")]).unwrap_err();
+        assert!(matches!(error, ConfigurationError::Load(_)));
+
+        let error = load(CONFIG_TOML, &[("BACKFILL_START", "1-2-3-4")], &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid setting backfill.start: Malformed change id 1-2-3-4"
+        );
+
+        let backfill = [
+            ("BACKFILL_START", "1-2-3-4-5"),
+            ("BACKFILL_END", "2-2-3-4-5"),
+            ("BACKFILL_MAX_RUNTIME", "3600"),
+        ];
+        let error = load(CONFIG_TOML, &backfill, &[]).unwrap_err();
+        assert!(matches!(
+            error,
+            ConfigurationError::Invalid {
+                key: "backfill",
+                ..
+            }
+        ));
+
+        let config = load(CONFIG_TOML, &[backfill[0], backfill[2]], &[]).unwrap();
+        assert!(matches!(
+            config.backfill.unwrap().end,
+            BackfillEnd::MaxRuntime(_)
+        ));
+    }
+}
@@ -1,6 +1,8 @@
-use serde::Deserialize;
+use std::fmt::Display;
 
-use crate::{config::Configuration, stash_record::StashRecord};
+use stash_api::common::StashRecord;
+
+use crate::config::user_config::Filter;
 
 pub enum FilterResult {
     Filter { n_total: usize, n_retained: usize },
@@ -8,15 +10,10 @@ pub enum FilterResult {
     Pass,
 }
 
-pub fn filter_stash_record(stash_record: &mut StashRecord, config: &Configuration) -> FilterResult {
+pub fn filter_stash_record(stash_record: &mut StashRecord, filter: &Filter) -> FilterResult {
     // League filtering
     let league = stash_record.league.clone();
-    let allowed_leagues = config
-        .user_config
-        .filter
-        .leagues
-        .clone()
-        .unwrap_or_default();
+    let allowed_leagues = filter.leagues.clone().unwrap_or_default();
 
     if league.is_some()
         && !allowed_leagues.is_empty()
@@ -28,29 +25,16 @@ pub fn filter_stash_record(stash_record: &mut StashRecord, config: &Configuratio
     }
 
     // Item filtering
-    let allowed_item_categories = config
-        .user_config
-        .filter
-        .item_categories
-        .clone()
-        .unwrap_or_default();
+    let allowed_item_categories = filter.item_categories.clone().unwrap_or_default();
 
     if !allowed_item_categories.is_empty() {
-        let items =
-            serde_json::from_value::<Vec<serde_json::Value>>(stash_record.items.clone()).unwrap();
-
-        let n_total = items.len();
+        let n_total = stash_record.items.len();
 
-        let filtered = items
-            .into_iter()
-            .filter(|item| {
-                serde_json::from_value::<Item>(item.clone()).map_or(true, |i| {
-                    allowed_item_categories.contains(&i.extended.category)
-                })
-            })
-            .collect::<Vec<_>>();
+        stash_record
+            .items
+            .retain(|item| allowed_item_categories.contains(&item.extended.category));
 
-        let n_filtered = filtered.len();
+        let n_filtered = stash_record.items.len();
 
         if n_filtered == 0 {
             return FilterResult::Block {
@@ -58,8 +42,6 @@ pub fn filter_stash_record(stash_record: &mut StashRecord, config: &Configuratio
             };
         }
 
-        stash_record.items = serde_json::to_value(filtered).unwrap();
-
         return FilterResult::Filter {
             n_total,
             n_retained: n_filtered,
@@ -69,22 +51,98 @@ pub fn filter_stash_record(stash_record: &mut StashRecord, config: &Configuratio
     FilterResult::Pass
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Item {
-    pub type_line: String,
-    pub extended: ItemExtendedProp,
-    pub name: String,
+/// What changed between two filters, ie. after reloading the configuration.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FilterDiff {
+    pub added_leagues: Vec<String>,
+    pub removed_leagues: Vec<String>,
+    pub added_item_categories: Vec<String>,
+    pub removed_item_categories: Vec<String>,
+}
+
+impl FilterDiff {
+    pub fn between(old: &Filter, new: &Filter) -> Self {
+        let (added_leagues, removed_leagues) = diff_lists(&old.leagues, &new.leagues);
+        let (added_item_categories, removed_item_categories) =
+            diff_lists(&old.item_categories, &new.item_categories);
+
+        Self {
+            added_leagues,
+            removed_leagues,
+            added_item_categories,
+            removed_item_categories,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &FilterDiff::default()
+    }
+}
+
+/// Returns the entries only in `new` and the ones only in `old`. No list and an empty one both
+/// allow everything.
+fn diff_lists(old: &Option<Vec<String>>, new: &Option<Vec<String>>) -> (Vec<String>, Vec<String>) {
+    let old = old.as_deref().unwrap_or_default();
+    let new = new.as_deref().unwrap_or_default();
+
+    let added = new.iter().filter(|e| !old.contains(e)).cloned().collect();
+    let removed = old.iter().filter(|e| !new.contains(e)).cloned().collect();
+    (added, removed)
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ItemExtendedProp {
-    pub category: String,
-    pub base_type: String,
+impl Display for FilterDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let changes = [
+            ("leagues", "+", &self.added_leagues),
+            ("leagues", "-", &self.removed_leagues),
+            ("item categories", "+", &self.added_item_categories),
+            ("item categories", "-", &self.removed_item_categories),
+        ]
+        .into_iter()
+        .filter(|(_, _, entries)| !entries.is_empty())
+        .map(|(name, sign, entries)| format!("{name} {sign}{entries:?}"))
+        .collect::<Vec<_>>();
+
+        if changes.is_empty() {
+            return write!(f, "no changes");
+        }
+        write!(f, "{}", changes.join(", "))
+    }
 }
 
 // @todo add tests
 #[cfg(test)]
 mod test {
+    use super::FilterDiff;
+    use crate::config::user_config::Filter;
+
+    #[test]
+    fn test_filter_diff() {
+        let old = Filter {
+            item_categories: None,
+            leagues: Some(vec!["Standard".into(), "Hardcore".into()]),
+        };
+        let new = Filter {
+            item_categories: Some(vec!["currency".into()]),
+            leagues: Some(vec!["Standard".into(), "Ancestor".into()]),
+        };
+
+        let diff = FilterDiff::between(&old, &new);
+        assert_eq!(diff.added_leagues, vec!["Ancestor".to_string()]);
+        assert_eq!(diff.removed_leagues, vec!["Hardcore".to_string()]);
+        assert_eq!(diff.added_item_categories, vec!["currency".to_string()]);
+        assert_eq!(
+            diff.to_string(),
+            r#"leagues +["Ancestor"], leagues -["Hardcore"], item categories +["currency"]"#
+        );
+
+        let unrestricted = Filter {
+            item_categories: Some(vec![]),
+            leagues: None,
+        };
+        assert!(FilterDiff::between(&Filter::default(), &unrestricted).is_empty());
+    }
+
     #[test]
     fn test_league_filter() {}
 
@@ -0,0 +1,153 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use stash_api::{
+    common::{poe_ninja_client::PoeNinjaClient, ChangeId, ChangeIdDelta, SHARD_COUNT},
+    r#async::indexer::IndexerHandle,
+};
+use tokio::task::JoinHandle;
+
+use crate::metrics::LagMetrics;
+
+/// Tells where the head of the river currently is.
+#[async_trait]
+pub trait HeadSource: Send + Sync {
+    async fn latest_change_id(&self) -> Result<ChangeId, stash_api::Error>;
+}
+
+#[async_trait]
+impl HeadSource for PoeNinjaClient {
+    async fn latest_change_id(&self) -> Result<ChangeId, stash_api::Error> {
+        self.fetch_latest_change_id_async().await
+    }
+}
+
+/// How far the indexer is behind the head of the river.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lag {
+    pub delta: ChangeIdDelta,
+    /// How long ago the head of the river was where the indexer is now, once the speed of the
+    /// river is known.
+    pub seconds: Option<f64>,
+}
+
+/// Estimates the lag from consecutive samples of the river head.
+#[derive(Debug, Default)]
+pub struct LagEstimator {
+    previous_head: Option<(ChangeId, Instant)>,
+    /// Updates per second the head of the river moved on between the last two samples.
+    head_rate: Option<f64>,
+}
+
+impl LagEstimator {
+    pub fn update(&mut self, head: ChangeId, at: Instant, current: &ChangeId) -> Lag {
+        if let Some((previous_head, previous_at)) = &self.previous_head {
+            let elapsed = at.duration_since(*previous_at).as_secs_f64();
+            let moved = head.delta(previous_head).total();
+            if elapsed > 0.0 && moved > 0 {
+                self.head_rate = Some(moved as f64 / elapsed);
+            }
+        }
+
+        // Shards we are ahead on, ie. because the head source lags behind itself, count as zero
+        let mut delta = head.delta(current);
+        for shard in delta.shards.iter_mut() {
+            *shard = (*shard).max(0);
+        }
+        let seconds = self
+            .head_rate
+            .map(|head_rate| delta.total() as f64 / head_rate);
+
+        self.previous_head = Some((head, at));
+        Lag { delta, seconds }
+    }
+}
+
+/// Periodically compares the change id the indexer is at with the head of the river and reports
+/// the lag via `metrics`.
+pub fn spawn_lag_tracker(
+    source: impl HeadSource + 'static,
+    handle: IndexerHandle,
+    metrics: LagMetrics,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut estimator = LagEstimator::default();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        while !handle.is_stopped() {
+            ticker.tick().await;
+
+            let head = match source.latest_change_id().await {
+                Ok(head) => head,
+                Err(e) => {
+                    tracing::warn!("Lag: Fetching the latest change id failed: {}", e);
+                    continue;
+                }
+            };
+
+            let lag = estimator.update(head, Instant::now(), &handle.current_change_id());
+            tracing::debug!(
+                "Lag: {} updates behind, {:?}s",
+                lag.delta.total(),
+                lag.seconds
+            );
+
+            metrics.behind_updates.set(lag.delta.total());
+            for shard in 0..SHARD_COUNT {
+                metrics
+                    .behind_shard_updates
+                    .with_label_values(&[&shard.to_string()])
+                    .set(lag.delta.shards[shard]);
+            }
+            if let Some(seconds) = lag.seconds {
+                metrics.behind_seconds.set(seconds);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
+    use stash_api::common::ChangeId;
+
+    use super::LagEstimator;
+
+    fn id(s: &str) -> ChangeId {
+        ChangeId::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_lag_estimator() {
+        let mut estimator = LagEstimator::default();
+        let start = Instant::now();
+        let current = id("100-100-100-100-100");
+
+        let lag = estimator.update(id("110-100-120-100-100"), start, &current);
+        assert_eq!(lag.delta.shards, [10, 0, 20, 0, 0]);
+        assert_eq!(lag.seconds, None);
+
+        // The head moved 30 updates within 10s, so the 60 updates we are behind took 20s
+        let lag = estimator.update(
+            id("120-110-130-100-100"),
+            start + Duration::from_secs(10),
+            &current,
+        );
+        assert_eq!(lag.delta.total(), 60);
+        assert_eq!(lag.seconds, Some(20.0));
+
+        let lag = estimator.update(
+            id("120-110-130-100-100"),
+            start + Duration::from_secs(20),
+            &id("130-110-130-100-100"),
+        );
+        assert_eq!(lag.delta.shards, [0, 0, 0, 0, 0]);
+        assert_eq!(lag.seconds, Some(0.0));
+    }
+}
@@ -1,6 +1,9 @@
+mod backfill;
 mod config;
 mod filter;
+mod lag;
 mod metrics;
+mod reload;
 mod resumption;
 mod schema;
 mod sinks;
@@ -17,22 +20,33 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
+use crate::backfill::{Backfill, BackfillAction};
+use crate::lag::spawn_lag_tracker;
 use crate::metrics::setup_metrics;
+use crate::reload::spawn_filter_reloader;
+use crate::resumption::{find_resume_point, Checkpoints, StateWrapper};
 use crate::{
-    config::{user_config::RestartMode, Configuration},
+    config::{user_config::RestartMode, Args, Configuration},
     resumption::State,
     sinks::postgres::PostgresSink,
 };
-use crate::{filter::filter_stash_record, sinks::rabbitmq::RabbitMqSink};
-use crate::{resumption::StateWrapper, stash_record::map_to_stash_records};
+use crate::{
+    filter::filter_stash_record,
+    sinks::{file::FileSink, parquet::ParquetSink, rabbitmq::RabbitMqSink, s3::S3Sink},
+};
 
+use clap::Parser;
 use dotenv::dotenv;
+use futures::StreamExt;
 use sinks::sink::Sink;
 use stash_api::{
-    common::{poe_ninja_client::PoeNinjaClient, ChangeId},
-    r#async::indexer::{Indexer, IndexerMessage},
+    common::{
+        poe_ninja_client::PoeNinjaClient, retry::RetryPolicy, ChangeId, Endpoints, StashRecord,
+    },
+    r#async::indexer::{Indexer, IndexerEvent, IndexerHandle},
 };
 use trade_common::telemetry::setup_telemetry;
 
@@ -42,55 +56,165 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     setup_telemetry("indexer").expect("Telemetry setup");
 
-    let config = Configuration::from_env()?;
+    let args = Args::parse();
+    let config = match Configuration::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
     tracing::info!("Chosen configuration: {:#?}", config);
 
     let signal_flag = setup_signal_handlers()?;
     let metrics = setup_metrics(config.metrics_port)?;
     let sinks = setup_sinks(&config).await?;
+    let filters = spawn_filter_reloader(args, config.user_config.filter.clone())?;
     let client_id = config.client_id.clone();
     let client_secret = config.client_secret.clone();
 
-    let mut resumption = StateWrapper::load_from_file(&"./indexer_state.json");
-    let indexer = Indexer::new();
-    let mut rx = match (&config.user_config.restart_mode, &resumption.inner) {
-        (RestartMode::Fresh, _) => {
-            let latest_change_id = PoeNinjaClient::fetch_latest_change_id_async().await?;
+    let mut backfill = config
+        .backfill
+        .as_ref()
+        .map(|backfill| Backfill::new(backfill, Instant::now()));
+    let state_path = match &config.backfill {
+        Some(backfill) => backfill.state_path.clone(),
+        None => config.state_path.clone(),
+    };
+    let mut resumption = StateWrapper::load_from_file(&state_path);
+    let endpoints = match &config.api_base_url {
+        Some(url) => Endpoints::new().with_base_url(url),
+        None => Endpoints::default(),
+    };
+    let poe_ninja_client = PoeNinjaClient::new(endpoints.poe_ninja_stats());
+    if let Some(backfill) = &mut backfill {
+        match poe_ninja_client.fetch_latest_change_id_async().await {
+            Ok(head) => {
+                tracing::info!("Backfill: Stopping at the head of the river at {}", head);
+                backfill.set_head(head);
+            }
+            Err(e) => tracing::warn!("Backfill: Fetching the head of the river failed: {}", e),
+        }
+    }
+    let mut indexer = Indexer::new()
+        .with_endpoints(endpoints)
+        // Catching up with the head would run past the end of a backfill
+        .with_parallel_catch_up(config.parallel_catch_up && backfill.is_none());
+    if let Some(max_in_flight) = config.max_in_flight_requests {
+        indexer = indexer.with_max_in_flight(max_in_flight as usize);
+    }
+    if let Some(max_fetch_attempts) = config.max_fetch_attempts {
+        indexer =
+            indexer.with_retry_policy(RetryPolicy::new().with_max_attempts(max_fetch_attempts));
+    }
+    // The state file is lost along with the container, so durable sinks are asked as well
+    let resume_point = match (&backfill, &config.user_config.restart_mode) {
+        (None, RestartMode::Resume) => find_resume_point(&resumption, &sinks).await,
+        _ => None,
+    };
+    let mut next_chunk_id = match &resume_point {
+        Some(resume_point) => resume_point.next_chunk_id,
+        None => resumption.chunk_counter(),
+    };
+
+    let mut stream = match (&backfill, &config.user_config.restart_mode, resume_point) {
+        (Some(backfill), _, _) => {
+            // Continue an interrupted backfill of the same window
+            let previous = resumption
+                .resume_point()
+                .map(|resume_point| resume_point.next_change_id)
+                .filter(|next| backfill.contains(next));
+            let start = match previous {
+                Some(next) => {
+                    tracing::info!("Backfill: Continuing at {}", next);
+                    next
+                }
+                None => backfill.start().clone(),
+            };
+            indexer.start_at_change_id(client_id, client_secret, start)
+        }
+        (None, RestartMode::Fresh, _) => {
+            let latest_change_id = poe_ninja_client.fetch_latest_change_id_async().await?;
             indexer.start_at_change_id(client_id, client_secret, latest_change_id)
         }
-        (RestartMode::Resume, Some(next)) => indexer.start_at_change_id(
-            client_id,
-            client_secret,
-            ChangeId::from_str(&next.next_change_id).unwrap(),
-        ),
-        (RestartMode::Resume, None) => {
+        (None, RestartMode::Resume, Some(resume_point)) => {
+            tracing::info!("Resuming at {}", resume_point.next_change_id);
+            indexer.start_at_change_id(client_id, client_secret, resume_point.next_change_id)
+        }
+        (None, RestartMode::Resume, None) => {
             tracing::info!("No previous data found, falling back to RestartMode::Fresh");
-            let latest_change_id = PoeNinjaClient::fetch_latest_change_id_async().await?;
+            let latest_change_id = poe_ninja_client.fetch_latest_change_id_async().await?;
             indexer.start_at_change_id(client_id, client_secret, latest_change_id)
         }
     }
-    .await;
+    .await?;
 
-    let mut next_chunk_id = resumption.chunk_counter();
+    let handle = stream.handle();
 
-    while let Some(msg) = rx.recv().await {
-        if signal_flag.load(Ordering::Relaxed) {
+    let head_source = match &config.lag_head_url {
+        Some(url) => PoeNinjaClient::new(url),
+        None => poe_ninja_client.clone(),
+    };
+    if backfill.is_none() {
+        spawn_lag_tracker(
+            head_source,
+            handle.clone(),
+            metrics.lag.clone(),
+            Duration::from_secs(config.lag_poll_interval.into()),
+        );
+    }
+
+    let mut checkpoints = Checkpoints::new(
+        config.checkpoint_ticks,
+        Duration::from_secs(config.checkpoint_seconds),
+        Instant::now(),
+    );
+
+    while let Some(event) = stream.next().await {
+        if signal_flag.load(Ordering::Relaxed) && !handle.is_stopped() {
             tracing::info!("Shutdown signal detected. Shutting down gracefully.");
-            break;
+            handle.stop();
         }
 
-        match msg {
-            IndexerMessage::Stop => break,
-            IndexerMessage::RateLimited(timer) => {
-                tracing::info!("Rate limited for {} seconds...waiting", timer.as_secs());
+        match event {
+            IndexerEvent::Stop => break,
+            IndexerEvent::RateLimited(timer) => {
+                tracing::info!("Rate limited for {}ms...waiting", timer.as_millis());
                 metrics.rate_limited.inc();
             }
-            IndexerMessage::Tick {
+            IndexerEvent::Error(e) => {
+                tracing::error!("Indexer encountered error: {}", e);
+            }
+            IndexerEvent::RetriesExhausted {
+                change_id,
+                attempts,
+            } => {
+                tracing::error!(
+                    "Giving up on change id {} after {} attempts, shutting down",
+                    change_id,
+                    attempts
+                );
+            }
+            IndexerEvent::Tick {
                 change_id,
                 response,
                 created_at,
+                transfer,
                 ..
             } => {
+                if let Some(backfill) = &backfill {
+                    match backfill.on_tick(&change_id, handle.is_stopped()) {
+                        BackfillAction::Index => {}
+                        BackfillAction::Stop => {
+                            tracing::info!("Backfill: Complete, shutting down");
+                            handle.stop();
+                            continue;
+                        }
+                        // Pages that were requested before the backfill stopped
+                        BackfillAction::Skip => continue,
+                    }
+                }
+
                 tracing::info!(
                     "Processing {} ({} stashes)",
                     change_id,
@@ -101,33 +225,46 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .stashes_processed
                     .inc_by(response.stashes.len().try_into().unwrap());
                 metrics.chunks_processed.inc();
+                metrics.compressed_bytes.inc_by(transfer.compressed_bytes);
+                metrics.decoded_bytes.inc_by(transfer.decoded_bytes);
+                metrics
+                    .download_milliseconds
+                    .inc_by(transfer.download_time.as_millis() as u64);
+                metrics
+                    .reordered_pages
+                    .inc_by(indexer.reordered_pages() - metrics.reordered_pages.get());
 
                 let next_change_id = response.next_change_id.clone();
-                let stashes =
-                    map_to_stash_records(change_id.clone(), created_at, response, next_chunk_id)
-                        .filter_map(|mut stash| match filter_stash_record(&mut stash, &config) {
-                            filter::FilterResult::Block { reason } => {
-                                tracing::debug!("Filter: Blocked stash, reason: {}", reason);
-                                None
-                            }
-                            filter::FilterResult::Pass => Some(stash),
-                            filter::FilterResult::Filter {
-                                n_total,
+                let filter = filters.borrow().clone();
+                let stashes = StashRecord::from_response(
+                    change_id.clone(),
+                    created_at,
+                    response,
+                    next_chunk_id,
+                )
+                .filter_map(|mut stash| match filter_stash_record(&mut stash, &filter) {
+                    filter::FilterResult::Block { reason } => {
+                        tracing::debug!("Filter: Blocked stash, reason: {}", reason);
+                        None
+                    }
+                    filter::FilterResult::Pass => Some(stash),
+                    filter::FilterResult::Filter {
+                        n_total,
+                        n_retained,
+                    } => {
+                        let n_removed = n_total - n_retained;
+                        if n_removed > 0 {
+                            tracing::debug!(
+                                "Filter: Removed {} \t Retained {} \t Total {}",
+                                n_removed,
                                 n_retained,
-                            } => {
-                                let n_removed = n_total - n_retained;
-                                if n_removed > 0 {
-                                    tracing::debug!(
-                                        "Filter: Removed {} \t Retained {} \t Total {}",
-                                        n_removed,
-                                        n_retained,
-                                        n_total
-                                    );
-                                }
-                                Some(stash)
-                            }
-                        })
-                        .collect::<Vec<_>>();
+                                n_total
+                            );
+                        }
+                        Some(stash)
+                    }
+                })
+                .collect::<Vec<_>>();
 
                 if !stashes.is_empty() {
                     next_chunk_id += 1;
@@ -135,6 +272,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         sink.handle(&stashes).await?;
                     }
                 }
+                for sink in &sinks {
+                    sink.tick().await?;
+                }
+
+                if let Some(backfill) = &backfill {
+                    report_backfill_progress(backfill, &next_change_id, &handle);
+                }
 
                 // Update resumption state at the end of each tick
                 resumption.update(State {
@@ -142,11 +286,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     next_change_id,
                     chunk_counter: next_chunk_id,
                 });
+
+                // All sinks handled the page by now, failing ones shut the indexer down above
+                if checkpoints.tick(Instant::now()) {
+                    match resumption.save(&sinks).await {
+                        Ok(_) => tracing::debug!("Wrote checkpoint at {}", change_id),
+                        Err(e) => tracing::error!("Writing checkpoint failed: {}", e),
+                    }
+                }
             }
         }
     }
 
-    match resumption.save() {
+    for sink in &sinks {
+        if let Err(e) = sink.flush().await {
+            tracing::error!("Flushing sink failed: {}", e);
+        }
+    }
+
+    match resumption.save(&sinks).await {
         Ok(_) => tracing::info!("Saved resumption state"),
         Err(_) => tracing::error!("Saving resumption state failed"),
     }
@@ -154,6 +312,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Logs how far the backfill got and stops the indexer once it is complete.
+fn report_backfill_progress(backfill: &Backfill, next_change_id: &str, handle: &IndexerHandle) {
+    let next_change_id = match ChangeId::from_str(next_change_id) {
+        Ok(next_change_id) => next_change_id,
+        Err(_) => return,
+    };
+
+    tracing::info!(
+        "Backfill: {:.1}% done, at {}",
+        backfill.progress(&next_change_id) * 100.0,
+        next_change_id
+    );
+
+    if !handle.is_stopped() && backfill.is_complete(&next_change_id) {
+        tracing::info!("Backfill: Complete, shutting down");
+        handle.stop();
+    }
+}
+
 fn setup_signal_handlers() -> Result<Arc<AtomicBool>, Box<dyn std::error::Error>> {
     let signal_flag = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGINT, signal_flag.clone())?;
@@ -172,6 +349,21 @@ async fn setup_sinks<'a>(
         tracing::info!("Configured RabbitMQ fanout sink");
     }
 
+    if let Some(conf) = &config.file {
+        sinks.push(Box::new(FileSink::new(conf.clone())?));
+        tracing::info!("Configured file sink at {}", conf.directory.display());
+    }
+
+    if let Some(conf) = &config.parquet {
+        sinks.push(Box::new(ParquetSink::new(conf.clone())?));
+        tracing::info!("Configured parquet sink at {}", conf.directory.display());
+    }
+
+    if let Some(conf) = &config.s3 {
+        sinks.push(Box::new(S3Sink::new(conf.clone())));
+        tracing::info!("Configured S3 sink for {}/{}", conf.endpoint, conf.bucket);
+    }
+
     if let Some(url) = &config.database_url {
         if !url.is_empty() {
             sinks.push(Box::new(PostgresSink::connect(url).await));
@@ -1,9 +1,25 @@
-use prometheus_exporter::prometheus::core::{AtomicU64, GenericCounter};
+use prometheus_exporter::prometheus::{
+    core::{AtomicU64, GenericCounter},
+    Gauge, IntGauge, IntGaugeVec,
+};
 
 pub struct Metrics {
     pub chunks_processed: GenericCounter<AtomicU64>,
     pub stashes_processed: GenericCounter<AtomicU64>,
     pub rate_limited: GenericCounter<AtomicU64>,
+    pub reordered_pages: GenericCounter<AtomicU64>,
+    pub compressed_bytes: GenericCounter<AtomicU64>,
+    pub decoded_bytes: GenericCounter<AtomicU64>,
+    pub download_milliseconds: GenericCounter<AtomicU64>,
+    pub lag: LagMetrics,
+}
+
+/// How far the indexer is behind the head of the river, see [`crate::lag`].
+#[derive(Clone)]
+pub struct LagMetrics {
+    pub behind_updates: IntGauge,
+    pub behind_shard_updates: IntGaugeVec,
+    pub behind_seconds: Gauge,
 }
 
 pub fn setup_metrics(port: u32) -> Result<Metrics, Box<dyn std::error::Error>> {
@@ -19,9 +35,50 @@ pub fn setup_metrics(port: u32) -> Result<Metrics, Box<dyn std::error::Error>> {
     let rate_limited =
         prometheus_exporter::prometheus::register_int_counter!("rate_limited", "help")?;
 
+    let reordered_pages = prometheus_exporter::prometheus::register_int_counter!(
+        "reordered_pages",
+        "Pages that finished downloading before their predecessor"
+    )?;
+
+    let compressed_bytes = prometheus_exporter::prometheus::register_int_counter!(
+        "compressed_bytes",
+        "Bytes of river pages as sent over the wire"
+    )?;
+
+    let decoded_bytes = prometheus_exporter::prometheus::register_int_counter!(
+        "decoded_bytes",
+        "Bytes of river pages after decoding"
+    )?;
+
+    let download_milliseconds = prometheus_exporter::prometheus::register_int_counter!(
+        "download_milliseconds",
+        "Time spent downloading river pages"
+    )?;
+
+    let lag = LagMetrics {
+        behind_updates: prometheus_exporter::prometheus::register_int_gauge!(
+            "lag_updates",
+            "Updates the indexer is behind the head of the river, summed over all shards"
+        )?,
+        behind_shard_updates: prometheus_exporter::prometheus::register_int_gauge_vec!(
+            "lag_shard_updates",
+            "Updates the indexer is behind the head of the river per shard",
+            &["shard"]
+        )?,
+        behind_seconds: prometheus_exporter::prometheus::register_gauge!(
+            "lag_seconds",
+            "Estimated seconds the indexer is behind the head of the river"
+        )?,
+    };
+
     Ok(Metrics {
         chunks_processed,
         stashes_processed,
         rate_limited,
+        reordered_pages,
+        compressed_bytes,
+        decoded_bytes,
+        download_milliseconds,
+        lag,
     })
 }
@@ -0,0 +1,115 @@
+use std::{
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use tokio::{sync::watch, time::MissedTickBehavior};
+
+use crate::{
+    config::{user_config::Filter, Args, Configuration},
+    filter::FilterDiff,
+};
+
+/// How often the configuration file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reloads the filters whenever the configuration file changes or, on Unix, the indexer receives a
+/// SIGHUP.
+///
+/// The configuration is loaded the same way as at startup, but only its filters are swapped in,
+/// so the indexer keeps its position in the river. Invalid configurations are logged and ignored.
+pub fn spawn_filter_reloader(
+    args: Args,
+    initial: Filter,
+) -> Result<watch::Receiver<Filter>, std::io::Error> {
+    let (tx, rx) = watch::channel(initial);
+    let mut hangup = Hangup::new()?;
+    let path = args.config_path();
+
+    tokio::spawn(async move {
+        let mut modified = modified_at(&path);
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        while !tx.is_closed() {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    let now = modified_at(&path);
+                    if now == modified {
+                        continue;
+                    }
+                    modified = now;
+                    tracing::info!("Reload: {} changed", path.display());
+                }
+                received = hangup.recv() => {
+                    if !received {
+                        break;
+                    }
+                    tracing::info!("Reload: Received SIGHUP");
+                }
+            }
+
+            reload(&args, &tx);
+        }
+    });
+
+    Ok(rx)
+}
+
+fn reload(args: &Args, tx: &watch::Sender<Filter>) {
+    let filter = match Configuration::load(args) {
+        Ok(config) => config.user_config.filter,
+        Err(e) => {
+            tracing::error!("Reload: Keeping the current filters, {}", e);
+            return;
+        }
+    };
+
+    let diff = FilterDiff::between(&tx.borrow(), &filter);
+    if diff.is_empty() {
+        tracing::info!("Reload: Filters are unchanged");
+        return;
+    }
+
+    tracing::info!("Reload: Updated filters, {}", diff);
+    tx.send_replace(filter);
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// SIGHUP, which only exists on Unix.
+#[cfg(unix)]
+struct Hangup(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Result<Self, std::io::Error> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        signal(SignalKind::hangup()).map(Self)
+    }
+
+    /// Waits for the next SIGHUP, `false` once no more can be received.
+    async fn recv(&mut self) -> bool {
+        self.0.recv().await.is_some()
+    }
+}
+
+/// Elsewhere the configuration file is still polled, but there is no signal to wait for.
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Result<Self, std::io::Error> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) -> bool {
+        std::future::pending().await
+    }
+}
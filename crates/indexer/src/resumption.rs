@@ -2,9 +2,14 @@ use std::{
     fs::File,
     io::{BufReader, Write},
     path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
 };
 
 use serde::{Deserialize, Serialize};
+use stash_api::common::{ChangeId, SHARD_COUNT};
+
+use crate::sinks::sink::Sink;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct State {
@@ -21,22 +26,63 @@ pub struct StateWrapper<'a> {
 
 impl<'a> StateWrapper<'a> {
     pub fn load_from_file(path: &'a dyn AsRef<Path>) -> Self {
-        let inner = {
-            if path.as_ref().exists() {
-                let reader = BufReader::new(File::open(path.as_ref()).unwrap());
-                serde_json::from_reader(reader).unwrap()
-            } else {
-                None
+        let inner = if path.as_ref().exists() {
+            match Self::read(path.as_ref()) {
+                Ok(inner) => inner,
+                Err(e) => {
+                    tracing::error!(
+                        "Ignoring unreadable state file {}: {}",
+                        path.as_ref().display(),
+                        e
+                    );
+                    None
+                }
             }
+        } else {
+            None
         };
 
         Self { inner, path }
     }
 
-    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut f = File::create(self.path.as_ref())?;
-        let serialized = serde_json::to_vec_pretty(&self.inner)?;
+    fn read(path: &Path) -> Result<Option<State>, Box<dyn std::error::Error>> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Writes the state without getting ahead of the sinks: if any of them buffered pages
+    /// without persisting them yet, the state file points back to the earliest of these pages,
+    /// which is indexed again after a crash.
+    pub async fn save(
+        &self,
+        sinks: &[Box<dyn Sink + '_>],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut unpersisted = vec![];
+        for sink in sinks {
+            if let Some(change_id) = sink.unpersisted_change_id().await {
+                unpersisted.push(ChangeId::from_str(&change_id)?);
+            }
+        }
+
+        let state = self
+            .inner
+            .clone()
+            .map(|state| hold_back(state, &unpersisted));
+        self.write(state.as_ref())
+    }
+
+    /// Writes `state` to a temporary file next to the state file first and renames it
+    /// afterwards, so that crashing in between leaves the previous state file intact.
+    fn write(&self, state: Option<&State>) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.path.as_ref();
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+
+        let mut f = File::create(&temp_path)?;
+        let serialized = serde_json::to_vec_pretty(&state)?;
         f.write_all(&serialized)?;
+        f.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
 
         Ok(())
     }
@@ -52,4 +98,271 @@ impl<'a> StateWrapper<'a> {
             0
         }
     }
+
+    pub fn resume_point(&self) -> Option<ResumePoint> {
+        let state = self.inner.as_ref()?;
+        Some(ResumePoint {
+            next_change_id: ChangeId::from_str(&state.next_change_id).ok()?,
+            next_chunk_id: self.chunk_counter(),
+        })
+    }
+}
+
+/// Moves `state` back to the earliest of the `unpersisted` pages, shard by shard, if it points
+/// past any of them.
+fn hold_back(mut state: State, unpersisted: &[ChangeId]) -> State {
+    if unpersisted.is_empty() {
+        return state;
+    }
+    let next_change_id = ChangeId::from_str(&state.next_change_id).ok();
+    if let Some(earliest) = shard_wise_min(unpersisted.iter().chain(next_change_id.as_ref())) {
+        state.next_change_id = earliest.to_string();
+    }
+    state
+}
+
+/// Decides when to write the state file while indexing, besides on shutdown.
+#[derive(Debug)]
+pub struct Checkpoints {
+    /// Every that many ticks, if not zero
+    every_ticks: u32,
+    /// Every that long, if not zero
+    every: Duration,
+    ticks: u32,
+    last_at: Instant,
+}
+
+impl Checkpoints {
+    pub fn new(every_ticks: u32, every: Duration, now: Instant) -> Self {
+        Self {
+            every_ticks,
+            every,
+            ticks: 0,
+            last_at: now,
+        }
+    }
+
+    /// Counts a tick whose page all sinks handled and tells whether to write a checkpoint.
+    pub fn tick(&mut self, now: Instant) -> bool {
+        self.ticks += 1;
+
+        let is_due = (self.every_ticks > 0 && self.ticks >= self.every_ticks)
+            || (!self.every.is_zero() && now.duration_since(self.last_at) >= self.every);
+        if is_due {
+            self.ticks = 0;
+            self.last_at = now;
+        }
+        is_due
+    }
+}
+
+/// Where the indexer can continue from, according to a sink or the state file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumePoint {
+    pub next_change_id: ChangeId,
+    pub next_chunk_id: i64,
+}
+
+/// Asks every durable sink how far it got and reconciles their answers with the state file, see
+/// [`reconcile`]. Sinks that cannot be asked are left out.
+pub async fn find_resume_point(
+    state: &StateWrapper<'_>,
+    sinks: &[Box<dyn Sink + '_>],
+) -> Option<ResumePoint> {
+    let mut sink_points = vec![];
+    for sink in sinks.iter().filter_map(|sink| sink.resume()) {
+        let next_change_id = match sink.get_next_change_id().await {
+            Ok(Some(next_change_id)) => next_change_id,
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::warn!("Resume: Asking sink for its next change id failed: {}", e);
+                continue;
+            }
+        };
+        let next_change_id = match ChangeId::from_str(&next_change_id) {
+            Ok(next_change_id) => next_change_id,
+            Err(e) => {
+                tracing::warn!("Resume: Sink returned an invalid change id: {}", e);
+                continue;
+            }
+        };
+        let next_chunk_id = match sink.get_next_chunk_id().await {
+            Ok(next_chunk_id) => next_chunk_id.unwrap_or_default(),
+            Err(e) => {
+                tracing::warn!("Resume: Asking sink for its next chunk id failed: {}", e);
+                continue;
+            }
+        };
+
+        sink_points.push(ResumePoint {
+            next_change_id,
+            next_chunk_id,
+        });
+    }
+
+    let state_point = state.resume_point();
+    tracing::info!(
+        "Resume: State file at {:?}, sinks at {:?}",
+        state_point,
+        sink_points
+    );
+    reconcile(state_point, &sink_points)
+}
+
+/// Picks the most recent position that no sink is missing any data of.
+///
+/// Sinks holding no data yet are not passed in. Everything before the earliest position of the
+/// others, shard by shard, has been written to all of them. The state file never points past
+/// pages that a sink has not persisted yet, see [`StateWrapper::save`], so it wins whenever it is
+/// ahead of that, ie. because the latest pages were filtered out entirely and never reached any
+/// sink. Chunk ids keep counting from the highest one seen anywhere.
+pub fn reconcile(state: Option<ResumePoint>, sinks: &[ResumePoint]) -> Option<ResumePoint> {
+    let next_chunk_id = sinks
+        .iter()
+        .chain(state.iter())
+        .map(|point| point.next_chunk_id)
+        .max()?;
+
+    let consistent = shard_wise_min(sinks.iter().map(|point| &point.next_change_id));
+
+    let next_change_id = match (state, consistent) {
+        (Some(state), Some(consistent)) if state.next_change_id >= consistent => {
+            state.next_change_id
+        }
+        (_, Some(consistent)) => consistent,
+        (Some(state), None) => state.next_change_id,
+        (None, None) => return None,
+    };
+
+    Some(ResumePoint {
+        next_change_id,
+        next_chunk_id,
+    })
+}
+
+/// The earliest offset of every shard among `change_ids`.
+fn shard_wise_min<'a>(change_ids: impl IntoIterator<Item = &'a ChangeId>) -> Option<ChangeId> {
+    change_ids
+        .into_iter()
+        .map(|change_id| *change_id.shards())
+        .reduce(|mut earliest, shards| {
+            for shard in 0..SHARD_COUNT {
+                earliest[shard] = earliest[shard].min(shards[shard]);
+            }
+            earliest
+        })
+        .map(ChangeId::from_shards)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use stash_api::common::ChangeId;
+
+    use std::time::{Duration, Instant};
+
+    use super::{hold_back, reconcile, Checkpoints, ResumePoint, State, StateWrapper};
+    use crate::sinks::test_util::temp_dir;
+
+    fn point(change_id: &str, next_chunk_id: i64) -> ResumePoint {
+        ResumePoint {
+            next_change_id: ChangeId::from_str(change_id).unwrap(),
+            next_chunk_id,
+        }
+    }
+
+    #[test]
+    fn test_reconcile_picks_position_all_sinks_reached() {
+        let sinks = [point("10-20-30-40-50", 7), point("12-18-30-40-50", 8)];
+
+        // A stale state file, ie. from before the container was recreated
+        assert_eq!(
+            reconcile(Some(point("5-5-5-5-5", 2)), &sinks),
+            Some(point("10-18-30-40-50", 8))
+        );
+        // The latest pages never reached any sink
+        assert_eq!(
+            reconcile(Some(point("15-20-30-40-50", 8)), &sinks),
+            Some(point("15-20-30-40-50", 8))
+        );
+        assert_eq!(
+            reconcile(None, &sinks[..1]),
+            Some(point("10-20-30-40-50", 7))
+        );
+        assert_eq!(
+            reconcile(Some(point("1-1-1-1-1", 3)), &[]),
+            Some(point("1-1-1-1-1", 3))
+        );
+        assert_eq!(reconcile(None, &[]), None);
+    }
+
+    #[test]
+    fn test_checkpoints_every_ticks_or_seconds() {
+        let start = Instant::now();
+        let mut checkpoints = Checkpoints::new(3, Duration::from_secs(10), start);
+
+        let due = (1..=4)
+            .map(|second| checkpoints.tick(start + Duration::from_secs(second)))
+            .collect::<Vec<_>>();
+        assert_eq!(due, vec![false, false, true, false]);
+        assert!(checkpoints.tick(start + Duration::from_secs(13)));
+
+        let mut checkpoints = Checkpoints::new(0, Duration::ZERO, start);
+        assert!(!checkpoints.tick(start + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_hold_back_to_unpersisted_pages() {
+        let state = State {
+            change_id: "9-9-9-9-9".into(),
+            next_change_id: "10-10-10-10-10".into(),
+            chunk_counter: 4,
+        };
+        let id = |s| ChangeId::from_str(s).unwrap();
+
+        let held_back = hold_back(state.clone(), &[id("8-8-8-8-8"), id("5-5-5-5-5")]);
+        assert_eq!(held_back.next_change_id, "5-5-5-5-5");
+        assert_eq!(held_back.chunk_counter, 4);
+        assert_eq!(
+            hold_back(state.clone(), &[id("10-10-10-10-10")]).next_change_id,
+            "10-10-10-10-10"
+        );
+        assert_eq!(hold_back(state, &[]).next_change_id, "10-10-10-10-10");
+
+        // Pages of shards that progressed unevenly are not ordered, every shard is held back on its own
+        let state = State {
+            change_id: "9-9-9-9-9".into(),
+            next_change_id: "10-3-10-10-10".into(),
+            chunk_counter: 4,
+        };
+        let held_back = hold_back(state, &[id("5-8-5-5-5"), id("8-5-8-8-2")]);
+        assert_eq!(held_back.next_change_id, "5-3-5-5-2");
+    }
+
+    #[tokio::test]
+    async fn test_state_file_roundtrip() {
+        let dir = temp_dir("state");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+
+        let mut state = StateWrapper::load_from_file(&path);
+        assert!(state.inner.is_none());
+        state.update(State {
+            change_id: "1-1-1-1-1".into(),
+            next_change_id: "2-2-2-2-2".into(),
+            chunk_counter: 4,
+        });
+        state.save(&[]).await.unwrap();
+        assert!(!dir.join("state.json.tmp").exists());
+
+        let loaded = StateWrapper::load_from_file(&path);
+        assert_eq!(loaded.resume_point(), Some(point("2-2-2-2-2", 5)));
+
+        // A corrupt state file is ignored instead of failing the indexer
+        std::fs::write(&path, "{\"change_id\": ").unwrap();
+        assert!(StateWrapper::load_from_file(&path).inner.is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,410 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Timelike};
+use stash_api::common::StashRecord;
+use tokio::sync::Mutex;
+
+use crate::config::{FileCompression, FileSinkConfig};
+
+use super::sink::{Sink, SinkResume};
+
+const EXTENSION: &str = "ndjson";
+
+/// Writes stash records to newline-delimited JSON files, one record per line.
+///
+/// Files are named by the creation time and change id of their first record, ie.
+/// `20230801T140512_2000000000-2000000001-2000000002-2000000003-2000000004.ndjson`, so that
+/// sorting them by name sorts them by time. A new file is started once the current one exceeds
+/// `max_bytes` or, if `rotate_hourly` is set, once a record of the next hour arrives. Closed files
+/// are compressed if configured. The file being written when the indexer stops is compressed
+/// on its next start, after its last page was dropped since a crash may have cut it short.
+pub struct FileSink {
+    config: FileSinkConfig,
+    segment: Mutex<Option<Segment>>,
+}
+
+struct Segment {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    bytes: u64,
+    /// The hour the first record was created in
+    hour: NaiveDateTime,
+}
+
+impl FileSink {
+    pub fn new(config: FileSinkConfig) -> Result<Self, std::io::Error> {
+        fs::create_dir_all(&config.directory)?;
+
+        let mut paths = list_files(&config.directory)?;
+        paths.sort();
+        // Only the file being written when the indexer stopped can end with an incomplete page
+        let latest = paths
+            .iter()
+            .rev()
+            .find(|path| path.extension().is_some_and(|e| e == EXTENSION));
+        if let Some(latest) = latest {
+            drop_last_page(latest)?;
+        }
+
+        for path in list_files(&config.directory)? {
+            match path.extension().and_then(|e| e.to_str()) {
+                // Left behind by crashing while compressing
+                Some("tmp") => fs::remove_file(&path)?,
+                // Left behind by the previous run
+                Some(EXTENSION) if config.compression != FileCompression::None => {
+                    compress(&path, config.compression)?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            config,
+            segment: Mutex::new(None),
+        })
+    }
+
+    fn create_segment(&self, first: &StashRecord) -> Result<Segment, std::io::Error> {
+        let name = format!(
+            "{}_{}.{EXTENSION}",
+            first.created_at.format("%Y%m%dT%H%M%S"),
+            first.change_id
+        );
+        let path = self.config.directory.join(name);
+        tracing::info!("File: Starting {}", path.display());
+
+        Ok(Segment {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            bytes: 0,
+            hour: hour_of(&first.created_at),
+        })
+    }
+
+    async fn close_segment(&self, segment: Segment) -> Result<(), std::io::Error> {
+        let file = segment.writer.into_inner()?;
+        file.sync_all()?;
+
+        let compression = self.config.compression;
+        if compression != FileCompression::None {
+            let path = segment.path;
+            tokio::task::spawn_blocking(move || compress(&path, compression))
+                .await
+                .expect("Compressing file")?;
+        }
+        Ok(())
+    }
+
+    /// The last record in the latest file, if any.
+    fn latest_record(&self) -> Result<Option<StashRecord>, std::io::Error> {
+        let mut paths = list_files(&self.config.directory)?;
+        paths.sort();
+
+        for path in paths.iter().rev() {
+            let reader: Box<dyn Read> = match path.extension().and_then(|e| e.to_str()) {
+                Some(EXTENSION) => Box::new(File::open(path)?),
+                Some("gz") => Box::new(flate2::read::GzDecoder::new(File::open(path)?)),
+                Some("zst") => Box::new(zstd::Decoder::new(File::open(path)?)?),
+                _ => continue,
+            };
+
+            let latest = BufReader::new(reader)
+                .lines()
+                .map_while(Result::ok)
+                .filter_map(|line| serde_json::from_str::<StashRecord>(&line).ok())
+                .last();
+            if latest.is_some() {
+                return Ok(latest);
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl Sink for FileSink {
+    #[tracing::instrument(skip(self, payload), name = "handle-file")]
+    async fn handle(&self, payload: &[StashRecord]) -> Result<usize, Box<dyn std::error::Error>> {
+        let first = match payload.first() {
+            Some(first) => first,
+            None => return Ok(0),
+        };
+
+        let mut segment = self.segment.lock().await;
+        let is_next_hour = segment.as_ref().is_some_and(|segment| {
+            self.config.rotate_hourly && segment.hour != hour_of(&first.created_at)
+        });
+        if is_next_hour {
+            if let Some(closed) = segment.take() {
+                self.close_segment(closed).await?;
+            }
+        }
+
+        let current = match segment.as_mut() {
+            Some(current) => current,
+            None => segment.insert(self.create_segment(first)?),
+        };
+        for record in payload {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            current.writer.write_all(&line)?;
+            current.bytes += line.len() as u64;
+        }
+        // Complete lines only, so that the records are on disk once the sink acknowledged them
+        current.writer.flush()?;
+        current.writer.get_ref().sync_data()?;
+
+        if current.bytes >= self.config.max_bytes {
+            if let Some(closed) = segment.take() {
+                self.close_segment(closed).await?;
+            }
+        }
+
+        Ok(payload.len())
+    }
+
+    fn resume(&self) -> Option<&dyn SinkResume> {
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl SinkResume for FileSink {
+    #[tracing::instrument(skip(self))]
+    async fn get_next_chunk_id(&self) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+        Ok(self.latest_record()?.map(|record| record.chunk_id + 1))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_next_change_id(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        Ok(self.latest_record()?.map(|record| record.next_change_id))
+    }
+}
+
+fn hour_of(created_at: &NaiveDateTime) -> NaiveDateTime {
+    created_at
+        .date()
+        .and_hms_opt(created_at.hour(), 0, 0)
+        .expect("Valid hour")
+}
+
+fn list_files(directory: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    Ok(paths)
+}
+
+/// Truncates the file at `path` to before the records of its last page, which are written in one
+/// go but may not have reached the disk completely, and removes it if nothing is left.
+///
+/// The resumed indexer then continues at that page and writes it again.
+fn drop_last_page(path: &Path) -> Result<(), std::io::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = vec![];
+    let (mut offset, mut last_page_at) = (0, 0);
+    let mut last_change_id = None;
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)? as u64;
+        if !line.ends_with(b"\n") {
+            break;
+        }
+        let record = match serde_json::from_slice::<StashRecord>(&line) {
+            Ok(record) => record,
+            Err(_) => break,
+        };
+        if last_change_id.as_ref() != Some(&record.change_id) {
+            last_page_at = offset;
+            last_change_id = Some(record.change_id);
+        }
+        offset += read;
+    }
+
+    if last_page_at == 0 {
+        fs::remove_file(path)?;
+        tracing::info!(
+            "File: Removed {} to write its only page again",
+            path.display()
+        );
+    } else {
+        let file = fs::OpenOptions::new().write(true).open(path)?;
+        file.set_len(last_page_at)?;
+        file.sync_all()?;
+        tracing::info!(
+            "File: Truncated {} to write its last page again",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Compresses the file at `path` next to it and removes it afterwards.
+fn compress(path: &Path, compression: FileCompression) -> Result<(), std::io::Error> {
+    let extension = match compression.extension() {
+        Some(extension) => extension,
+        None => return Ok(()),
+    };
+    let mut target = OsString::from(path.as_os_str());
+    target.push(format!(".{extension}"));
+    let target = PathBuf::from(target);
+    let mut temp = target.clone().into_os_string();
+    temp.push(".tmp");
+
+    let mut reader = File::open(path)?;
+    let writer = BufWriter::new(File::create(&temp)?);
+    let file = match compression {
+        FileCompression::None => unreachable!(),
+        FileCompression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            std::io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?.into_inner()?
+        }
+        FileCompression::Zstd => {
+            let mut encoder = zstd::Encoder::new(writer, 0)?;
+            std::io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?.into_inner()?
+        }
+    };
+    file.sync_all()?;
+
+    fs::rename(&temp, &target)?;
+    fs::remove_file(path)?;
+    tracing::info!("File: Compressed {}", target.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use stash_api::common::StashRecord;
+
+    use super::FileSink;
+    use crate::{
+        config::{FileCompression, FileSinkConfig},
+        sinks::{
+            sink::{Sink, SinkResume},
+            test_util::{self, temp_dir},
+        },
+    };
+
+    fn record(hour: u32, change_id: &str, chunk_id: i64) -> StashRecord {
+        StashRecord {
+            chunk_id,
+            ..test_util::record(1, hour, change_id)
+        }
+    }
+
+    fn file_names(dir: &PathBuf) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_rotates_and_compresses() {
+        let dir = temp_dir("file-sink");
+        let sink = FileSink::new(FileSinkConfig {
+            directory: dir.clone(),
+            max_bytes: 10_000,
+            rotate_hourly: true,
+            compression: FileCompression::Zstd,
+        })
+        .unwrap();
+
+        sink.handle(&[record(14, "1-1-1-1-1", 0), record(14, "1-1-1-1-1", 0)])
+            .await
+            .unwrap();
+        sink.handle(&[record(14, "2-2-2-2-2", 1)]).await.unwrap();
+        // A record of the next hour closes the first file
+        sink.handle(&[record(15, "3-3-3-3-3", 2)]).await.unwrap();
+        sink.handle(&[record(15, "4-4-4-4-4", 3)]).await.unwrap();
+
+        assert_eq!(
+            file_names(&dir),
+            vec![
+                "20230801T140512_1-1-1-1-1.ndjson.zst",
+                "20230801T150512_3-3-3-3-3.ndjson"
+            ]
+        );
+        assert_eq!(
+            sink.get_next_change_id().await.unwrap(),
+            Some("4-4-4-4-4-next".into())
+        );
+        assert_eq!(sink.get_next_chunk_id().await.unwrap(), Some(4));
+
+        // Restarting compresses the file left behind without its last page
+        drop(sink);
+        let sink = FileSink::new(FileSinkConfig {
+            directory: dir.clone(),
+            max_bytes: 1,
+            rotate_hourly: false,
+            compression: FileCompression::Gzip,
+        })
+        .unwrap();
+        assert_eq!(file_names(&dir)[1], "20230801T150512_3-3-3-3-3.ndjson.gz");
+        assert_eq!(
+            sink.get_next_change_id().await.unwrap(),
+            Some("3-3-3-3-3-next".into())
+        );
+
+        // Exceeding max_bytes closes the file right away
+        sink.handle(&[record(15, "4-4-4-4-4", 3)]).await.unwrap();
+        assert_eq!(file_names(&dir)[2], "20230801T150512_4-4-4-4-4.ndjson.gz");
+        assert_eq!(sink.get_next_chunk_id().await.unwrap(), Some(4));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_drops_truncated_last_page() {
+        let dir = temp_dir("file-sink-truncated");
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = FileSinkConfig {
+            directory: dir.clone(),
+            max_bytes: 10_000,
+            rotate_hourly: false,
+            compression: FileCompression::None,
+        };
+
+        let line = |record| serde_json::to_string(&record).unwrap() + "\n";
+        let first_page = line(record(14, "1-1-1-1-1", 0)) + &line(record(14, "1-1-1-1-1", 0));
+        // A crash cut the second page short in the middle of a line
+        let second_page = line(record(14, "2-2-2-2-2", 1)) + &line(record(14, "2-2-2-2-2", 1));
+        let second_page = &second_page[..second_page.len() - 10];
+        let path = dir.join("20230801T140512_1-1-1-1-1.ndjson");
+        std::fs::write(&path, first_page.clone() + second_page).unwrap();
+
+        // The second page is written again, right after the first
+        let sink = FileSink::new(config.clone()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), first_page);
+        assert_eq!(
+            sink.get_next_change_id().await.unwrap(),
+            Some("1-1-1-1-1-next".into())
+        );
+        assert_eq!(sink.get_next_chunk_id().await.unwrap(), Some(1));
+
+        // A file holding nothing but an incomplete page is removed
+        drop(sink);
+        std::fs::write(&path, second_page).unwrap();
+        FileSink::new(config).unwrap();
+        assert!(file_names(&dir).is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
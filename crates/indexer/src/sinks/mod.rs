@@ -1,3 +1,8 @@
+pub mod file;
+pub mod parquet;
 pub mod postgres;
 pub mod rabbitmq;
+pub mod s3;
 pub mod sink;
+#[cfg(test)]
+pub mod test_util;
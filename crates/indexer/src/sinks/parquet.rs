@@ -0,0 +1,600 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use arrow_array::{
+    cast::AsArray, types::Int64Type, ArrayRef, BooleanArray, Int64Array, RecordBatch, StringArray,
+    TimestampMillisecondArray, UInt32Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter, ProjectionMask},
+    basic::{Compression, ZstdLevel},
+    file::properties::WriterProperties,
+};
+use stash_api::common::{ChangeId, Item, StashRecord, SHARD_COUNT};
+use tokio::sync::Mutex;
+
+use crate::config::ParquetSinkConfig;
+
+use super::sink::{Sink, SinkResume};
+
+const EXTENSION: &str = "parquet";
+
+/// Writes stash records to zstd compressed Parquet files with one row per item, for querying
+/// them offline, ie. with DuckDB or Polars.
+///
+/// Besides the item, every row holds the stash, account, league and change id it was indexed
+/// with as well as the raw item JSON. Stashes without items, ie. ones that were emptied, are
+/// written as a single row without item columns. Files are partitioned by league and the date
+/// the records were created at, ie.
+/// `league=Standard/date=2023-08-01/20230801T140512_2000000000-2000000001-2000000002-2000000003-2000000004.parquet`.
+/// A file is closed once it holds `max_rows` items, once the records reach the next day or when the
+/// indexer shuts down. Until then it is written with a `.tmp` suffix, as Parquet files can only be
+/// read once they are complete, and holds checkpoints back to its first page.
+///
+/// Files left incomplete by a crash are removed on the next start, which then resumes at the first
+/// page of the earliest of them. Otherwise it resumes after the latest records of the latest date.
+pub struct ParquetSink {
+    config: ParquetSinkConfig,
+    schema: SchemaRef,
+    partitions: Mutex<HashMap<Partition, PartitionWriter>>,
+    /// The change id of the first page of the earliest incomplete file that has been removed
+    incomplete: Option<ChangeId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Partition {
+    league: Option<String>,
+    date: NaiveDate,
+}
+
+struct PartitionWriter {
+    path: PathBuf,
+    writer: ArrowWriter<File>,
+    rows: usize,
+    first_change_id: String,
+}
+
+impl ParquetSink {
+    pub fn new(config: ParquetSinkConfig) -> Result<Self, std::io::Error> {
+        fs::create_dir_all(&config.directory)?;
+        // Left behind by the previous run, their footer is missing
+        let incomplete = remove_incomplete_files(&config.directory)?
+            .into_iter()
+            .reduce(|earliest, change_id| earliest_of(&earliest, &change_id));
+
+        Ok(Self {
+            config,
+            schema: Arc::new(schema()),
+            partitions: Mutex::new(HashMap::new()),
+            incomplete,
+        })
+    }
+
+    fn create_writer(
+        &self,
+        partition: &Partition,
+        first: &StashRecord,
+    ) -> Result<PartitionWriter, Box<dyn std::error::Error>> {
+        let directory = self
+            .config
+            .directory
+            .join(format!(
+                "league={}",
+                partition_value(partition.league.as_deref())
+            ))
+            .join(format!("date={}", partition.date.format("%Y-%m-%d")));
+        fs::create_dir_all(&directory)?;
+
+        let path = directory.join(format!(
+            "{}_{}.{EXTENSION}",
+            first.created_at.format("%Y%m%dT%H%M%S"),
+            first.change_id
+        ));
+        tracing::info!("Parquet: Starting {}", path.display());
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .build();
+        let writer = ArrowWriter::try_new(
+            File::create(temp_path(&path))?,
+            self.schema.clone(),
+            Some(properties),
+        )?;
+
+        Ok(PartitionWriter {
+            path,
+            writer,
+            rows: 0,
+            first_change_id: first.change_id.clone(),
+        })
+    }
+
+    /// The next change id and chunk id after the latest records in the files of the latest date.
+    /// Records are written in river order, so earlier dates only hold earlier records.
+    fn latest_position(&self) -> Result<Option<(ChangeId, i64)>, Box<dyn std::error::Error>> {
+        let mut dates = vec![];
+        for league in list_entries(&self.config.directory, true)? {
+            dates.extend(list_entries(&league, true)?);
+        }
+        let latest_date = dates.iter().filter_map(|date| date.file_name()).max();
+
+        let mut latest: Option<([u64; SHARD_COUNT], i64)> = None;
+        for date in dates.iter().filter(|date| date.file_name() == latest_date) {
+            let latest_file = list_entries(date, false)?
+                .into_iter()
+                .filter(|path| path.extension().is_some_and(|e| e == EXTENSION))
+                .max();
+            let position = match latest_file {
+                Some(latest_file) => read_latest_position(&latest_file)?,
+                None => None,
+            };
+            let (next_change_id, chunk_id) = match position {
+                Some(position) => position,
+                None => continue,
+            };
+
+            // The latest page may only have reached some of the leagues
+            let (shards, max_chunk_id) = latest.get_or_insert(([0; SHARD_COUNT], chunk_id));
+            for (shard, next) in shards.iter_mut().zip(next_change_id.shards()) {
+                *shard = (*shard).max(*next);
+            }
+            *max_chunk_id = (*max_chunk_id).max(chunk_id);
+        }
+
+        Ok(latest.map(|(shards, chunk_id)| (ChangeId::from_shards(shards), chunk_id + 1)))
+    }
+}
+
+impl PartitionWriter {
+    /// Writes the footer and moves the file to where readers pick it up.
+    fn close(self) -> Result<(), Box<dyn std::error::Error>> {
+        let file = self.writer.into_inner()?;
+        file.sync_all()?;
+        fs::rename(temp_path(&self.path), &self.path)?;
+        tracing::info!(
+            "Parquet: Closed {} ({} rows)",
+            self.path.display(),
+            self.rows
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for ParquetSink {
+    #[tracing::instrument(skip(self, payload), name = "handle-parquet")]
+    async fn handle(&self, payload: &[StashRecord]) -> Result<usize, Box<dyn std::error::Error>> {
+        let latest_date = match payload.iter().map(|r| r.created_at.date()).max() {
+            Some(date) => date,
+            None => return Ok(0),
+        };
+
+        let mut groups: HashMap<Partition, Vec<&StashRecord>> = HashMap::new();
+        for record in payload {
+            let partition = Partition {
+                league: record.league.clone(),
+                date: record.created_at.date(),
+            };
+            groups.entry(partition).or_default().push(record);
+        }
+
+        let mut partitions = self.partitions.lock().await;
+
+        // Records are written in river order, so earlier days are complete
+        let finished = partitions
+            .keys()
+            .filter(|partition| partition.date < latest_date)
+            .cloned()
+            .collect::<Vec<_>>();
+        for partition in finished {
+            if let Some(writer) = partitions.remove(&partition) {
+                writer.close()?;
+            }
+        }
+
+        for (partition, records) in groups {
+            let batch = record_batch(&self.schema, &records)?;
+            let mut writer = match partitions.remove(&partition) {
+                Some(writer) => writer,
+                None => self.create_writer(&partition, records[0])?,
+            };
+            writer.writer.write(&batch)?;
+            writer.rows += batch.num_rows();
+
+            if writer.rows >= self.config.max_rows {
+                writer.close()?;
+            } else {
+                partitions.insert(partition, writer);
+            }
+        }
+
+        Ok(payload.len())
+    }
+
+    async fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut partitions = self.partitions.lock().await;
+        for (_, writer) in partitions.drain() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+
+    async fn unpersisted_change_id(&self) -> Option<String> {
+        let partitions = self.partitions.lock().await;
+        partitions
+            .values()
+            .filter_map(|writer| ChangeId::from_str(&writer.first_change_id).ok())
+            .reduce(|earliest, change_id| earliest_of(&earliest, &change_id))
+            .map(|earliest| earliest.to_string())
+    }
+
+    fn resume(&self) -> Option<&dyn SinkResume> {
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl SinkResume for ParquetSink {
+    #[tracing::instrument(skip(self))]
+    async fn get_next_chunk_id(&self) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+        Ok(self
+            .latest_position()?
+            .map(|(_, next_chunk_id)| next_chunk_id))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_next_change_id(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        // The records of incomplete files are gone, so they have to be indexed again
+        if let Some(incomplete) = &self.incomplete {
+            return Ok(Some(incomplete.to_string()));
+        }
+        Ok(self
+            .latest_position()?
+            .map(|(next_change_id, _)| next_change_id.to_string()))
+    }
+}
+
+fn schema() -> Schema {
+    Schema::new(vec![
+        Field::new(
+            "created_at",
+            DataType::Timestamp(TimeUnit::Millisecond, None),
+            false,
+        ),
+        Field::new("change_id", DataType::Utf8, false),
+        Field::new("next_change_id", DataType::Utf8, false),
+        Field::new("chunk_id", DataType::Int64, false),
+        Field::new("stash_id", DataType::Utf8, false),
+        Field::new("stash_type", DataType::Utf8, false),
+        Field::new("stash_name", DataType::Utf8, true),
+        Field::new("public", DataType::Boolean, false),
+        Field::new("account_name", DataType::Utf8, true),
+        Field::new("last_character_name", DataType::Utf8, true),
+        Field::new("league", DataType::Utf8, true),
+        Field::new("item_id", DataType::Utf8, true),
+        Field::new("name", DataType::Utf8, true),
+        Field::new("type_line", DataType::Utf8, true),
+        Field::new("base_type", DataType::Utf8, true),
+        Field::new("category", DataType::Utf8, true),
+        Field::new("frame_type", DataType::UInt32, true),
+        Field::new("ilvl", DataType::UInt32, true),
+        Field::new("stack_size", DataType::UInt32, true),
+        Field::new("identified", DataType::Boolean, true),
+        Field::new("corrupted", DataType::Boolean, true),
+        Field::new("note", DataType::Utf8, true),
+        Field::new("item", DataType::Utf8, true),
+    ])
+}
+
+/// Flattens `records` into one row per item, or a single row for stashes without items.
+fn record_batch(
+    schema: &SchemaRef,
+    records: &[&StashRecord],
+) -> Result<RecordBatch, Box<dyn std::error::Error>> {
+    let rows = records
+        .iter()
+        .flat_map(|record| {
+            let items: Vec<Option<&Item>> = if record.items.is_empty() {
+                vec![None]
+            } else {
+                record.items.iter().map(Some).collect()
+            };
+            items.into_iter().map(move |item| (*record, item))
+        })
+        .collect::<Vec<_>>();
+
+    let raw_items = rows
+        .iter()
+        .map(|(_, item)| item.map(serde_json::to_string).transpose())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let stash_column = |value: fn(&StashRecord) -> Option<&str>| -> ArrayRef {
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|(record, _)| value(record)),
+        ))
+    };
+    let item_column = |value: fn(&Item) -> Option<&str>| -> ArrayRef {
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|(_, item)| item.and_then(value)),
+        ))
+    };
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(TimestampMillisecondArray::from_iter_values(
+            rows.iter()
+                .map(|(record, _)| record.created_at.and_utc().timestamp_millis()),
+        )),
+        stash_column(|r| Some(&r.change_id)),
+        stash_column(|r| Some(&r.next_change_id)),
+        Arc::new(Int64Array::from_iter_values(
+            rows.iter().map(|(record, _)| record.chunk_id),
+        )),
+        stash_column(|r| Some(&r.stash_id)),
+        stash_column(|r| Some(&r.stash_type)),
+        stash_column(|r| r.stash_name.as_deref()),
+        Arc::new(BooleanArray::from_iter(
+            rows.iter().map(|(record, _)| Some(record.public)),
+        )),
+        stash_column(|r| r.account_name.as_deref()),
+        stash_column(|r| r.last_character_name.as_deref()),
+        stash_column(|r| r.league.as_deref()),
+        item_column(|i| Some(&i.id)),
+        item_column(|i| Some(&i.name)),
+        item_column(|i| Some(&i.type_line)),
+        item_column(|i| Some(&i.base_type)),
+        item_column(|i| Some(&i.extended.category)),
+        Arc::new(UInt32Array::from_iter(
+            rows.iter().map(|(_, item)| item.map(|i| i.frame_type)),
+        )),
+        Arc::new(UInt32Array::from_iter(
+            rows.iter().map(|(_, item)| item.map(|i| i.ilvl)),
+        )),
+        Arc::new(UInt32Array::from_iter(
+            rows.iter().map(|(_, item)| item.and_then(|i| i.stack_size)),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            rows.iter().map(|(_, item)| item.map(|i| i.identified)),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            rows.iter().map(|(_, item)| item.and_then(|i| i.corrupted)),
+        )),
+        item_column(|i| i.note.as_deref()),
+        Arc::new(StringArray::from_iter(raw_items)),
+    ];
+
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+/// League names are used as directory names, so anything besides letters, digits, spaces and
+/// dashes is replaced.
+fn partition_value(league: Option<&str>) -> String {
+    match league {
+        Some(league) => league
+            .chars()
+            .map(|c| match c {
+                c if c.is_alphanumeric() || c == ' ' || c == '-' => c,
+                _ => '_',
+            })
+            .collect(),
+        None => "__HIVE_DEFAULT_PARTITION__".to_string(),
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    PathBuf::from(temp)
+}
+
+/// Removes incomplete files below `directory` and returns the change ids of their first pages,
+/// as told by their names.
+fn remove_incomplete_files(directory: &Path) -> Result<Vec<ChangeId>, std::io::Error> {
+    let mut change_ids = vec![];
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            change_ids.extend(remove_incomplete_files(&path)?);
+        } else if path.extension().is_some_and(|e| e == "tmp") {
+            tracing::warn!("Parquet: Removing incomplete {}", path.display());
+            fs::remove_file(&path)?;
+
+            let change_id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(&format!(".{EXTENSION}.tmp")))
+                .and_then(|name| name.split_once('_'))
+                .and_then(|(_, change_id)| ChangeId::from_str(change_id).ok());
+            change_ids.extend(change_id);
+        }
+    }
+    Ok(change_ids)
+}
+
+/// The directories or files directly below `directory`.
+fn list_entries(directory: &Path, dirs: bool) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() == dirs {
+            paths.push(entry.path());
+        }
+    }
+    Ok(paths)
+}
+
+/// The next change id and chunk id of the last row in the file at `path`, if any.
+fn read_latest_position(
+    path: &Path,
+) -> Result<Option<(ChangeId, i64)>, Box<dyn std::error::Error>> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    let projection = ProjectionMask::roots(builder.parquet_schema(), [2, 3]);
+
+    let mut latest = None;
+    for batch in builder.with_projection(projection).build()? {
+        let batch = batch?;
+        let last = match batch.num_rows().checked_sub(1) {
+            Some(last) => last,
+            None => continue,
+        };
+        let next_change_id = batch.column(0).as_string::<i32>().value(last);
+        let chunk_id = batch.column(1).as_primitive::<Int64Type>().value(last);
+        latest = Some((ChangeId::from_str(next_change_id)?, chunk_id));
+    }
+    Ok(latest)
+}
+
+/// Pages are handed to the sinks in river order, so their change ids are comparable.
+fn earliest_of(a: &ChangeId, b: &ChangeId) -> ChangeId {
+    if b < a {
+        b.clone()
+    } else {
+        a.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, path::PathBuf};
+
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use stash_api::common::{Item, StashRecord};
+
+    use super::ParquetSink;
+    use crate::{
+        config::ParquetSinkConfig,
+        sinks::{
+            sink::{Sink, SinkResume},
+            test_util::{self, temp_dir},
+        },
+    };
+
+    fn item(id: &str) -> Item {
+        serde_json::from_value(serde_json::json!({
+            "id": id, "name": "", "typeLine": "Chaos Orb", "baseType": "Chaos Orb",
+            "ilvl": 0, "frameType": 5, "stackSize": 10, "icon": "",
+            "extended": { "category": "currency" }
+        }))
+        .unwrap()
+    }
+
+    fn record(day: u32, league: &str, change_id: &str, items: Vec<Item>) -> StashRecord {
+        StashRecord {
+            league: Some(league.into()),
+            items,
+            ..test_util::record(day, 23, change_id)
+        }
+    }
+
+    fn rows(path: PathBuf) -> usize {
+        ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .map(|batch| batch.unwrap().num_rows())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn test_parquet_sink_partitions_by_league_and_date() {
+        let dir = temp_dir("parquet");
+        let sink = ParquetSink::new(ParquetSinkConfig {
+            directory: dir.clone(),
+            max_rows: 1000,
+        })
+        .unwrap();
+
+        sink.handle(&[
+            record(1, "Standard", "1-1-1-1-1", vec![item("a"), item("b")]),
+            record(1, "Hardcore Ancestor", "1-1-1-1-1", vec![item("c")]),
+        ])
+        .await
+        .unwrap();
+        sink.handle(&[record(1, "Standard", "2-2-2-2-2", vec![])])
+            .await
+            .unwrap();
+
+        let standard =
+            dir.join("league=Standard/date=2023-08-01/20230801T230512_1-1-1-1-1.parquet");
+        assert!(!standard.exists(), "Incomplete files are written aside");
+
+        // The next day closes the files of the previous one
+        sink.handle(&[record(2, "Standard", "3-3-3-3-3", vec![item("a")])])
+            .await
+            .unwrap();
+        assert_eq!(rows(standard), 3);
+        assert_eq!(
+            rows(dir.join(
+                "league=Hardcore Ancestor/date=2023-08-01/20230801T230512_1-1-1-1-1.parquet"
+            )),
+            1
+        );
+
+        sink.flush().await.unwrap();
+        assert_eq!(
+            rows(dir.join("league=Standard/date=2023-08-02/20230802T230512_3-3-3-3-3.parquet")),
+            1
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_parquet_sink_resumes_and_holds_back_checkpoints() {
+        let dir = temp_dir("parquet-resume");
+        let config = ParquetSinkConfig {
+            directory: dir.clone(),
+            max_rows: 1000,
+        };
+        let page = |league, change_id: &str, next_change_id: &str, chunk_id| {
+            let mut record = record(1, league, change_id, vec![item("a")]);
+            record.next_change_id = next_change_id.into();
+            record.chunk_id = chunk_id;
+            record
+        };
+
+        let sink = ParquetSink::new(config.clone()).unwrap();
+        assert_eq!(sink.get_next_change_id().await.unwrap(), None);
+        sink.handle(&[page("Standard", "1-1-1-1-1", "2-2-2-2-2", 0)])
+            .await
+            .unwrap();
+        sink.handle(&[page("Hardcore", "2-2-2-2-2", "3-3-3-3-3", 1)])
+            .await
+            .unwrap();
+        assert_eq!(
+            sink.unpersisted_change_id().await.as_deref(),
+            Some("1-1-1-1-1")
+        );
+        sink.flush().await.unwrap();
+        assert_eq!(sink.unpersisted_change_id().await, None);
+
+        // Both leagues count, whichever the latest page reached
+        let sink = ParquetSink::new(config.clone()).unwrap();
+        assert_eq!(
+            sink.get_next_change_id().await.unwrap().as_deref(),
+            Some("3-3-3-3-3")
+        );
+        assert_eq!(sink.get_next_chunk_id().await.unwrap(), Some(2));
+
+        // Crashing loses the incomplete file, which is indexed again
+        sink.handle(&[page("Standard", "3-3-3-3-3", "4-4-4-4-4", 2)])
+            .await
+            .unwrap();
+        drop(sink);
+        let sink = ParquetSink::new(config).unwrap();
+        assert_eq!(
+            sink.get_next_change_id().await.unwrap().as_deref(),
+            Some("3-3-3-3-3")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,13 +1,15 @@
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
-use diesel::{dsl::max, ExpressionMethods, QueryDsl, QueryResult};
+use diesel::{dsl::max, ExpressionMethods, QueryDsl};
 use diesel_async::{
     pooled_connection::{bb8::Pool, AsyncDieselConnectionManager},
     AsyncPgConnection, RunQueryDsl,
 };
 
 use crate::schema::stash_records::dsl::*;
-use crate::stash_record::StashRecord;
+use stash_api::common::StashRecord;
+
+use crate::stash_record::StashRecordRow;
 
 use super::sink::{Sink, SinkResume};
 
@@ -32,32 +34,41 @@ impl PostgresSink {
 impl Sink for PostgresSink {
     #[tracing::instrument(skip(self, records), name = "handle-postgres")]
     async fn handle(&self, records: &[StashRecord]) -> Result<usize, Box<dyn std::error::Error>> {
+        let rows = records
+            .iter()
+            .map(StashRecordRow::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
         let mut conn = self.pool.get().await?;
 
         diesel::insert_into(stash_records)
-            .values(records)
+            .values(&rows)
             .execute(&mut conn)
             .await
             .map_err(|e| e.into())
     }
+
+    fn resume(&self) -> Option<&dyn SinkResume> {
+        Some(self)
+    }
 }
 
 #[async_trait]
 impl SinkResume for PostgresSink {
     #[tracing::instrument(skip(self))]
-    async fn get_next_chunk_id(&self) -> QueryResult<usize> {
-        let mut conn = self.pool.get().await.unwrap();
+    async fn get_next_chunk_id(&self) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get().await?;
 
-        stash_records
+        let latest_chunk_id = stash_records
             .select(max(chunk_id))
-            .limit(1)
-            .execute(&mut conn)
-            .await
+            .first::<Option<i64>>(&mut conn)
+            .await?;
+
+        Ok(latest_chunk_id.map(|latest_chunk_id| latest_chunk_id + 1))
     }
 
     #[tracing::instrument(skip(self))]
-    async fn get_next_change_id(&self) -> QueryResult<String> {
-        let mut conn = self.pool.get().await.unwrap();
+    async fn get_next_change_id(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get().await?;
 
         let latest_created_at = stash_records
             .select(max(created_at))
@@ -66,13 +77,14 @@ impl SinkResume for PostgresSink {
 
         match latest_created_at {
             Some(last_created_at) => {
-                stash_records
+                let latest_next_change_id = stash_records
                     .select(next_change_id)
                     .filter(created_at.eq(&last_created_at))
-                    .first(&mut conn)
-                    .await
+                    .first::<String>(&mut conn)
+                    .await?;
+                Ok(Some(latest_next_change_id))
             }
-            None => Err(diesel::result::Error::NotFound),
+            None => Ok(None),
         }
     }
 }
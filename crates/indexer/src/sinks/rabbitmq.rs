@@ -1,12 +1,26 @@
 use async_trait::async_trait;
-use lapin::{options::BasicPublishOptions, BasicProperties, Channel, Connection};
+use lapin::{
+    options::{BasicGetOptions, BasicNackOptions, BasicPublishOptions, QueueDeclareOptions},
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel, Connection,
+};
+use serde::{Deserialize, Serialize};
 
-use crate::{config::RabbitMqConfig, stash_record::StashRecord};
+use stash_api::common::StashRecord;
 
-use super::sink::Sink;
+use crate::config::RabbitMqConfig;
+
+use super::sink::{Sink, SinkResume};
 
 const EXCHANGE: &str = "amq.fanout";
 
+/// Where the indexer got to with the latest payload that was published.
+#[derive(Debug, Serialize, Deserialize)]
+struct Position {
+    next_change_id: String,
+    next_chunk_id: i64,
+}
+
 pub struct RabbitMqSink {
     #[allow(dead_code)]
     connection: Connection,
@@ -25,11 +39,79 @@ impl RabbitMqSink {
 
         let channel = connection.create_channel().await?;
 
-        Ok(Self {
+        let sink = Self {
             connection,
             channel,
             config,
-        })
+        };
+        sink.declare_position_queue().await?;
+        Ok(sink)
+    }
+
+    /// The published stashes are consumed by others, so the position of the latest payload is
+    /// kept in a durable queue of its own that only ever holds a single message.
+    fn position_queue(&self) -> String {
+        format!("{}.position", self.config.producer_routing_key)
+    }
+
+    async fn declare_position_queue(&self) -> Result<(), lapin::Error> {
+        let mut arguments = FieldTable::default();
+        arguments.insert("x-max-length".into(), AMQPValue::LongInt(1));
+
+        self.channel
+            .queue_declare(
+                &self.position_queue(),
+                QueueDeclareOptions {
+                    durable: true,
+                    ..QueueDeclareOptions::default()
+                },
+                arguments,
+            )
+            .await
+            .map(|_| ())
+    }
+
+    async fn publish_position(
+        &self,
+        position: &Position,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_vec(position)?;
+
+        self.channel
+            .basic_publish(
+                "",
+                &self.position_queue(),
+                BasicPublishOptions::default(),
+                &serialized,
+                // Persistent, so the position survives restarts of the broker
+                BasicProperties::default().with_delivery_mode(2),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Peeks at the latest position without removing it from its queue.
+    async fn latest_position(&self) -> Result<Option<Position>, Box<dyn std::error::Error>> {
+        let message = self
+            .channel
+            .basic_get(&self.position_queue(), BasicGetOptions { no_ack: false })
+            .await?;
+
+        match message {
+            Some(message) => {
+                let position = serde_json::from_slice(&message.delivery.data);
+                message
+                    .delivery
+                    .acker
+                    .nack(BasicNackOptions {
+                        requeue: true,
+                        ..BasicNackOptions::default()
+                    })
+                    .await?;
+                Ok(Some(position?))
+            }
+            None => Ok(None),
+        }
     }
 }
 
@@ -47,8 +129,39 @@ impl Sink for RabbitMqSink {
                 serialized.as_bytes(),
                 BasicProperties::default(),
             )
-            .await
-            .map(|_| payload.len())
-            .map_err(|e| e.into())
+            .await?;
+
+        if let Some(last) = payload.last() {
+            self.publish_position(&Position {
+                next_change_id: last.next_change_id.clone(),
+                next_chunk_id: last.chunk_id + 1,
+            })
+            .await?;
+        }
+
+        Ok(payload.len())
+    }
+
+    fn resume(&self) -> Option<&dyn SinkResume> {
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl SinkResume for RabbitMqSink {
+    #[tracing::instrument(skip(self))]
+    async fn get_next_chunk_id(&self) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+        Ok(self
+            .latest_position()
+            .await?
+            .map(|position| position.next_chunk_id))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_next_change_id(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        Ok(self
+            .latest_position()
+            .await?
+            .map(|position| position.next_change_id))
     }
 }
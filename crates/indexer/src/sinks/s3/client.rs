@@ -0,0 +1,348 @@
+use std::{future::Future, time::Duration};
+
+use bytes::Bytes;
+use chrono::Utc;
+use reqwest::{header::AUTHORIZATION, Method, Response, Url};
+use stash_api::common::retry::Backoff;
+
+use crate::config::S3SinkConfig;
+
+use super::signing::{amz_date, sha256_hex, uri_encode, Signer};
+
+/// Everything that can go wrong while talking to an S3-compatible service.
+#[derive(Debug)]
+pub enum S3Error {
+    /// The request could not be sent or its response body could not be read.
+    Transport(reqwest::Error),
+    /// The service responded with an unexpected status code.
+    HttpStatus { status: u16, body: String },
+    /// The service responded with 200, but reported an error in the body, as S3 does when
+    /// completing a multipart upload fails late.
+    ErrorResponse { code: String, body: String },
+    /// The service responded successfully, but not with what was asked for.
+    UnexpectedResponse(String),
+}
+
+impl S3Error {
+    /// Whether sending the request again may succeed.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            Self::Transport(_) => true,
+            // S3 asks to slow down with 503, some compatible services with 429
+            Self::HttpStatus { status, .. } => *status >= 500 || *status == 429,
+            // S3 asks to retry these
+            Self::ErrorResponse { .. } => true,
+            Self::UnexpectedResponse(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for S3Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "Transport error: {e}"),
+            Self::HttpStatus { status, body } => write!(f, "HTTP status {status}: {body}"),
+            Self::ErrorResponse { code, body } => write!(f, "Error {code}: {body}"),
+            Self::UnexpectedResponse(body) => write!(f, "Unexpected response: {body}"),
+        }
+    }
+}
+
+impl std::error::Error for S3Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// What is stored below a prefix, see [`S3Client::list`].
+#[derive(Debug, Default)]
+pub struct Listing {
+    /// The keys of all objects, ascending
+    pub keys: Vec<String>,
+    /// The prefixes up to the next delimiter of the keys that continue past one, ascending
+    pub common_prefixes: Vec<String>,
+}
+
+/// Uploads objects to a single bucket, addressed path-style, ie. `<endpoint>/<bucket>/<key>`.
+pub struct S3Client {
+    http: reqwest::Client,
+    endpoint: String,
+    bucket: String,
+    signer: Signer,
+    backoff: Backoff,
+}
+
+impl S3Client {
+    pub fn new(config: &S3SinkConfig) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            endpoint: config.endpoint.clone(),
+            bucket: config.bucket.clone(),
+            signer: Signer::new(
+                &config.access_key_id,
+                &config.secret_access_key,
+                &config.region,
+            ),
+            backoff: Backoff::new(
+                config.max_attempts,
+                Duration::from_millis(200),
+                Duration::from_secs(30),
+            ),
+        }
+    }
+
+    /// Uploads `body` to `key`, in parts of `part_bytes` if it is any larger.
+    pub async fn upload(&self, key: &str, body: Bytes, part_bytes: usize) -> Result<(), S3Error> {
+        if body.len() <= part_bytes {
+            self.send(Method::PUT, key, "", body).await?;
+            return Ok(());
+        }
+
+        let upload_id = self.create_multipart_upload(key).await?;
+        match self.upload_parts(key, &upload_id, body, part_bytes).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // Parts of abandoned uploads are stored, and billed, until they are aborted
+                if let Err(abort_error) = self.abort_multipart_upload(key, &upload_id).await {
+                    tracing::warn!("S3: Aborting upload of {} failed: {}", key, abort_error);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Bytes, S3Error> {
+        let response = self.send(Method::GET, key, "", Bytes::new()).await?;
+        response.bytes().await.map_err(S3Error::Transport)
+    }
+
+    /// Lists everything below `prefix`, following continuation tokens until the listing is
+    /// complete. Keys that continue past `delimiter` are rolled up into common prefixes.
+    pub async fn list(&self, prefix: &str, delimiter: Option<&str>) -> Result<Listing, S3Error> {
+        let mut listing = Listing::default();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            // Canonical form, ie. sorted by name
+            let mut query = vec![];
+            if let Some(token) = &continuation_token {
+                query.push(format!("continuation-token={}", uri_encode(token, true)));
+            }
+            if let Some(delimiter) = delimiter {
+                query.push(format!("delimiter={}", uri_encode(delimiter, true)));
+            }
+            query.push("list-type=2".to_string());
+            query.push(format!("prefix={}", uri_encode(prefix, true)));
+
+            let response = self
+                .send(Method::GET, "", &query.join("&"), Bytes::new())
+                .await?;
+            let body = response.text().await.map_err(S3Error::Transport)?;
+
+            listing
+                .keys
+                .extend(xml_elements(&body, "Key").map(str::to_string));
+            for common_prefix in xml_elements(&body, "CommonPrefixes") {
+                listing
+                    .common_prefixes
+                    .extend(xml_element(common_prefix, "Prefix").map(str::to_string));
+            }
+
+            match xml_element(&body, "NextContinuationToken") {
+                Some(token) if xml_element(&body, "IsTruncated") == Some("true") => {
+                    continuation_token = Some(token.to_string());
+                }
+                _ => return Ok(listing),
+            }
+        }
+    }
+
+    async fn create_multipart_upload(&self, key: &str) -> Result<String, S3Error> {
+        let response = self
+            .send(Method::POST, key, "uploads=", Bytes::new())
+            .await?;
+        let body = response.text().await.map_err(S3Error::Transport)?;
+        xml_element(&body, "UploadId")
+            .map(str::to_string)
+            .ok_or(S3Error::UnexpectedResponse(body))
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        body: Bytes,
+        part_bytes: usize,
+    ) -> Result<(), S3Error> {
+        let upload_id = uri_encode(upload_id, true);
+
+        let mut completion = String::from("<CompleteMultipartUpload>");
+        for (index, offset) in (0..body.len()).step_by(part_bytes).enumerate() {
+            let part_number = index + 1;
+            let part = body.slice(offset..(offset + part_bytes).min(body.len()));
+            let query = format!("partNumber={part_number}&uploadId={upload_id}");
+            let response = self.send(Method::PUT, key, &query, part).await?;
+
+            let etag = response
+                .headers()
+                .get("etag")
+                .and_then(|etag| etag.to_str().ok())
+                .ok_or_else(|| S3Error::UnexpectedResponse("Part without ETag".into()))?;
+            completion.push_str(&format!(
+                "<Part><PartNumber>{part_number}</PartNumber><ETag>{etag}</ETag></Part>"
+            ));
+        }
+        completion.push_str("</CompleteMultipartUpload>");
+
+        let query = format!("uploadId={upload_id}");
+        let completion = Bytes::from(completion);
+        self.retry(&Method::POST, key, || async {
+            let response = self
+                .try_send(Method::POST, key, &query, completion.clone())
+                .await?;
+            // Completing an upload may fail after the service already responded with 200
+            let body = response.text().await.map_err(S3Error::Transport)?;
+            match xml_element(&body, "Code") {
+                Some(code) => Err(S3Error::ErrorResponse {
+                    code: code.to_string(),
+                    body,
+                }),
+                None => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn abort_multipart_upload(&self, key: &str, upload_id: &str) -> Result<(), S3Error> {
+        let query = format!("uploadId={}", uri_encode(upload_id, true));
+        self.send(Method::DELETE, key, &query, Bytes::new()).await?;
+        Ok(())
+    }
+
+    /// Sends a signed request, again after a backoff as long as it fails with a retriable error
+    /// and attempts are left. `query` has to be in its canonical form, see [`Signer`].
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &str,
+        body: Bytes,
+    ) -> Result<Response, S3Error> {
+        self.retry(&method, key, || {
+            self.try_send(method.clone(), key, query, body.clone())
+        })
+        .await
+    }
+
+    /// Runs `attempt` again after a backoff as long as it fails with a retriable error and
+    /// attempts are left.
+    async fn retry<T, F, Fut>(&self, method: &Method, key: &str, attempt: F) -> Result<T, S3Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, S3Error>>,
+    {
+        let mut failed_attempts = 0;
+        loop {
+            let error = match attempt().await {
+                Ok(result) => return Ok(result),
+                Err(e) => e,
+            };
+
+            failed_attempts += 1;
+            if !error.is_retriable() || failed_attempts >= self.backoff.max_attempts {
+                return Err(error);
+            }
+
+            let delay = self.backoff.delay(failed_attempts);
+            tracing::warn!(
+                "S3: {} {} failed ({}), retrying in {}ms",
+                method,
+                key,
+                error,
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn try_send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &str,
+        body: Bytes,
+    ) -> Result<Response, S3Error> {
+        let mut url = format!("{}/{}", self.endpoint, uri_encode(&self.bucket, true));
+        // Requests without a key address the bucket itself, ie. to list it
+        if !key.is_empty() {
+            url.push('/');
+            url.push_str(&uri_encode(key, false));
+        }
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(query);
+        }
+        let url = Url::parse(&url).expect("Endpoint was validated with the configuration");
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let now = Utc::now();
+        let date = amz_date(now);
+        let payload_hash = sha256_hex(&body);
+        let authorization = self.signer.authorization(
+            method.as_str(),
+            url.path(),
+            query,
+            &[
+                ("host", &host),
+                ("x-amz-content-sha256", &payload_hash),
+                ("x-amz-date", &date),
+            ],
+            &payload_hash,
+            now,
+        );
+
+        let response = self
+            .http
+            .request(method, url)
+            .header("x-amz-date", date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(AUTHORIZATION, authorization)
+            .body(body)
+            .send()
+            .await
+            .map_err(S3Error::Transport)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(S3Error::HttpStatus {
+                status: status.as_u16(),
+                body,
+            });
+        }
+        Ok(response)
+    }
+}
+
+/// The text of the first `<name>` element in `xml`, good enough for the flat responses of S3.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    xml_elements(xml, name).next()
+}
+
+/// The texts of all `<name>` elements in `xml`, in order.
+fn xml_elements<'a>(xml: &'a str, name: &str) -> impl Iterator<Item = &'a str> {
+    let (open, close) = (format!("<{name}>"), format!("</{name}>"));
+    let mut rest = xml;
+    std::iter::from_fn(move || {
+        let start = rest.find(&open)? + open.len();
+        let end = start + rest[start..].find(&close)?;
+        let text = &rest[start..end];
+        rest = &rest[end + close.len()..];
+        Some(text)
+    })
+}
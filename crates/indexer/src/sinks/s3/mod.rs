@@ -0,0 +1,619 @@
+mod client;
+mod signing;
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::NaiveDateTime;
+use stash_api::common::StashRecord;
+use tokio::sync::Mutex;
+
+use crate::config::{FileCompression, S3SinkConfig};
+
+use self::client::S3Client;
+use super::sink::{Sink, SinkResume};
+
+const EXTENSION: &str = "ndjson";
+
+/// Collects stash records into compressed NDJSON objects and uploads them to an S3-compatible
+/// service, ie. AWS S3 or MinIO.
+///
+/// Objects are keyed by the change id range they cover, from the change id of their first record
+/// up to the next change id of their last one, below a directory per day, ie.
+/// `<prefix>2023/08/01/20230801T140512_2000000000-2000000001-2000000002-2000000003-2000000004_2000000010-2000000011-2000000012-2000000013-2000000014.ndjson.zst`.
+/// The end of one object is the start of the next one, unless the pages in between were filtered
+/// out entirely. An object is uploaded once it exceeds `object_bytes`, has been collected for
+/// `object_seconds` or when the indexer shuts down. Until then it only exists in memory, so
+/// checkpoints are held back to its first page. The indexer resumes after the newest object.
+pub struct S3Sink {
+    config: S3SinkConfig,
+    client: S3Client,
+    object: Mutex<Option<Object>>,
+}
+
+struct Object {
+    created_at: NaiveDateTime,
+    first_change_id: String,
+    last_next_change_id: String,
+    records: usize,
+    encoder: Encoder,
+    started_at: Instant,
+}
+
+/// Compresses records in memory as they arrive.
+enum Encoder {
+    None(Vec<u8>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(compression: FileCompression) -> Result<Self, std::io::Error> {
+        Ok(match compression {
+            FileCompression::None => Encoder::None(vec![]),
+            FileCompression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                vec![],
+                flate2::Compression::default(),
+            )),
+            FileCompression::Zstd => Encoder::Zstd(zstd::Encoder::new(vec![], 0)?),
+        })
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), std::io::Error> {
+        match self {
+            Encoder::None(buffer) => buffer.write_all(buf),
+            Encoder::Gzip(encoder) => encoder.write_all(buf),
+            Encoder::Zstd(encoder) => encoder.write_all(buf),
+        }
+    }
+
+    /// The size of the output so far, without what the encoder still holds back.
+    fn compressed_bytes(&self) -> usize {
+        match self {
+            Encoder::None(buffer) => buffer.len(),
+            Encoder::Gzip(encoder) => encoder.get_ref().len(),
+            Encoder::Zstd(encoder) => encoder.get_ref().len(),
+        }
+    }
+
+    fn finish(self) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Encoder::None(buffer) => Ok(buffer),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl S3Sink {
+    pub fn new(config: S3SinkConfig) -> Self {
+        Self {
+            client: S3Client::new(&config),
+            config,
+            object: Mutex::new(None),
+        }
+    }
+
+    fn key(&self, object: &Object) -> String {
+        let extension = match self.config.compression.extension() {
+            Some(extension) => format!(".{extension}"),
+            None => String::new(),
+        };
+        format!(
+            "{}{}/{}_{}_{}.{EXTENSION}{extension}",
+            self.config.prefix,
+            object.created_at.format("%Y/%m/%d"),
+            object.created_at.format("%Y%m%dT%H%M%S"),
+            object.first_change_id,
+            object.last_next_change_id
+        )
+    }
+
+    /// The key of the newest object. Keys sort by time, so it is the last one of the latest day.
+    async fn latest_key(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        // Year, month and day
+        let mut prefix = self.config.prefix.clone();
+        for _ in 0..3 {
+            let listing = self.client.list(&prefix, Some("/")).await?;
+            let latest = listing
+                .common_prefixes
+                .into_iter()
+                .rev()
+                .find(|common_prefix| {
+                    common_prefix[prefix.len()..]
+                        .trim_end_matches('/')
+                        .bytes()
+                        .all(|b| b.is_ascii_digit())
+                });
+            prefix = match latest {
+                Some(latest) => latest,
+                None => return Ok(None),
+            };
+        }
+
+        let listing = self.client.list(&prefix, None).await?;
+        Ok(listing
+            .keys
+            .into_iter()
+            .filter(|key| parse_next_change_id(key).is_some())
+            .max())
+    }
+
+    fn is_overdue(&self, object: &Object) -> bool {
+        object.started_at.elapsed() >= Duration::from_secs(self.config.object_seconds)
+    }
+
+    async fn upload(&self, object: Object) -> Result<(), Box<dyn std::error::Error>> {
+        let key = self.key(&object);
+        let records = object.records;
+        let body = object.encoder.finish()?;
+        let bytes = body.len();
+
+        self.client
+            .upload(&key, Bytes::from(body), self.config.part_bytes)
+            .await?;
+        tracing::info!(
+            "S3: Uploaded {} ({} records, {} bytes)",
+            key,
+            records,
+            bytes
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for S3Sink {
+    #[tracing::instrument(skip(self, payload), name = "handle-s3")]
+    async fn handle(&self, payload: &[StashRecord]) -> Result<usize, Box<dyn std::error::Error>> {
+        let (first, last) = match (payload.first(), payload.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(0),
+        };
+
+        let mut object = self.object.lock().await;
+        let current = match object.as_mut() {
+            Some(current) => current,
+            None => object.insert(Object {
+                created_at: first.created_at,
+                first_change_id: first.change_id.clone(),
+                last_next_change_id: last.next_change_id.clone(),
+                records: 0,
+                encoder: Encoder::new(self.config.compression)?,
+                started_at: Instant::now(),
+            }),
+        };
+
+        for record in payload {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            current.encoder.write_all(&line)?;
+        }
+        current.records += payload.len();
+        current.last_next_change_id = last.next_change_id.clone();
+
+        let is_complete = current.encoder.compressed_bytes() >= self.config.object_bytes
+            || self.is_overdue(current);
+        if is_complete {
+            if let Some(complete) = object.take() {
+                self.upload(complete).await?;
+            }
+        }
+
+        Ok(payload.len())
+    }
+
+    /// Uploads the object once it is overdue, even if the pages since were filtered out entirely.
+    async fn tick(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut object = self.object.lock().await;
+        if object
+            .as_ref()
+            .is_some_and(|object| self.is_overdue(object))
+        {
+            if let Some(overdue) = object.take() {
+                self.upload(overdue).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(object) = self.object.lock().await.take() {
+            self.upload(object).await?;
+        }
+        Ok(())
+    }
+
+    async fn unpersisted_change_id(&self) -> Option<String> {
+        let object = self.object.lock().await;
+        object.as_ref().map(|object| object.first_change_id.clone())
+    }
+
+    fn resume(&self) -> Option<&dyn SinkResume> {
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl SinkResume for S3Sink {
+    /// Downloads the newest object, as chunk ids are not part of the key.
+    #[tracing::instrument(skip(self))]
+    async fn get_next_chunk_id(&self) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+        let key = match self.latest_key().await? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+
+        let body = self.client.get(&key).await?;
+        let reader: Box<dyn Read + '_> = if key.ends_with(".gz") {
+            Box::new(flate2::read::GzDecoder::new(body.as_ref()))
+        } else if key.ends_with(".zst") {
+            Box::new(zstd::Decoder::new(body.as_ref())?)
+        } else {
+            Box::new(body.as_ref())
+        };
+
+        let latest = BufReader::new(reader)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str::<StashRecord>(&line).ok())
+            .last();
+        Ok(latest.map(|record| record.chunk_id + 1))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_next_change_id(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let key = self.latest_key().await?;
+        Ok(key
+            .as_deref()
+            .and_then(parse_next_change_id)
+            .map(str::to_string))
+    }
+}
+
+/// The next change id an object ends at, ie. `2-2-2-2-2` for
+/// `<prefix>2023/08/01/20230801T140512_1-1-1-1-1_2-2-2-2-2.ndjson.zst`.
+fn parse_next_change_id(key: &str) -> Option<&str> {
+    let name = key.rsplit('/').next()?;
+    let (name, _) = name.split_once(&format!(".{EXTENSION}"))?;
+    let (_, next_change_id) = name.rsplit_once('_')?;
+    Some(next_change_id)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        io::Read,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use axum::{
+        body::Bytes,
+        extract::{Path, Query, State},
+        http::{HeaderMap, Method, StatusCode},
+        response::{IntoResponse, Response},
+        routing::{any, get},
+        Router,
+    };
+    use stash_api::common::StashRecord;
+
+    use super::S3Sink;
+    use crate::{
+        config::{FileCompression, S3SinkConfig},
+        sinks::{
+            sink::{Sink, SinkResume},
+            test_util,
+        },
+    };
+
+    /// Keeps objects in memory, like a bucket of MinIO would.
+    #[derive(Default)]
+    struct Bucket {
+        objects: BTreeMap<String, Vec<u8>>,
+        uploads: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+        /// Requests to fail with 503 before accepting any
+        failures: usize,
+        /// Multipart uploads to fail completing with 200 and an error, before completing any
+        failed_completions: usize,
+        requests: Vec<String>,
+    }
+
+    type SharedBucket = Arc<Mutex<Bucket>>;
+
+    async fn handle_request(
+        State(bucket): State<SharedBucket>,
+        method: Method,
+        Path((bucket_name, key)): Path<(String, String)>,
+        Query(query): Query<HashMap<String, String>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let mut bucket = bucket.lock().unwrap();
+        bucket.requests.push(format!("{method} {key}"));
+        assert_eq!(bucket_name, "records");
+        let authorization = headers["authorization"].to_str().unwrap();
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=minio/"));
+
+        if bucket.failures > 0 {
+            bucket.failures -= 1;
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "<Error><Code>SlowDown</Code></Error>",
+            )
+                .into_response();
+        }
+
+        match (method, query.get("uploadId")) {
+            (Method::GET, None) => match bucket.objects.get(&key) {
+                Some(object) => object.clone().into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            },
+            (Method::PUT, None) => {
+                bucket.objects.insert(key, body.to_vec());
+                StatusCode::OK.into_response()
+            }
+            (Method::POST, None) => {
+                assert!(query.contains_key("uploads"));
+                bucket.uploads.insert("upload-1".into(), BTreeMap::new());
+                "<InitiateMultipartUploadResult><UploadId>upload-1</UploadId></InitiateMultipartUploadResult>"
+                    .into_response()
+            }
+            (Method::PUT, Some(upload_id)) => {
+                let part_number: u32 = query["partNumber"].parse().unwrap();
+                let upload = bucket.uploads.get_mut(upload_id).unwrap();
+                upload.insert(part_number, body.to_vec());
+                ([("etag", format!("\"etag-{part_number}\""))], "").into_response()
+            }
+            (Method::POST, Some(_)) if bucket.failed_completions > 0 => {
+                bucket.failed_completions -= 1;
+                "<Error><Code>InternalError</Code></Error>".into_response()
+            }
+            (Method::POST, Some(upload_id)) => {
+                let upload = bucket.uploads.remove(upload_id).unwrap();
+                let completion = String::from_utf8(body.to_vec()).unwrap();
+                for part_number in upload.keys() {
+                    assert!(completion.contains(&format!(
+                        "<PartNumber>{part_number}</PartNumber><ETag>\"etag-{part_number}\"</ETag>"
+                    )));
+                }
+                bucket
+                    .objects
+                    .insert(key, upload.into_values().flatten().collect());
+                "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".into_response()
+            }
+            _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        }
+    }
+
+    /// Lists two entries per page, to make the client follow continuation tokens.
+    async fn list_objects(
+        State(bucket): State<SharedBucket>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> Response {
+        let bucket = bucket.lock().unwrap();
+        assert_eq!(query["list-type"], "2");
+        let prefix = &query["prefix"];
+
+        let mut entries = vec![];
+        for key in bucket.objects.keys().filter(|key| key.starts_with(prefix)) {
+            let rest = &key[prefix.len()..];
+            let entry = match (query.get("delimiter"), rest.find('/')) {
+                (Some(_), Some(end)) => format!(
+                    "<CommonPrefixes><Prefix>{prefix}{}</Prefix></CommonPrefixes>",
+                    &rest[..=end]
+                ),
+                _ => format!("<Contents><Key>{key}</Key></Contents>"),
+            };
+            if !entries.contains(&entry) {
+                entries.push(entry);
+            }
+        }
+
+        let start: usize = query
+            .get("continuation-token")
+            .map_or(0, |token| token.parse().unwrap());
+        let end = (start + 2).min(entries.len());
+        let continuation = if end < entries.len() {
+            format!("<IsTruncated>true</IsTruncated><NextContinuationToken>{end}</NextContinuationToken>")
+        } else {
+            "<IsTruncated>false</IsTruncated>".to_string()
+        };
+        format!(
+            "<ListBucketResult><Prefix>{prefix}</Prefix>{}{continuation}</ListBucketResult>",
+            entries[start..end].concat()
+        )
+        .into_response()
+    }
+
+    async fn serve(bucket: SharedBucket) -> SocketAddr {
+        let app = Router::new()
+            .route("/:bucket", get(list_objects))
+            .route("/:bucket/*key", any(handle_request))
+            .with_state(bucket);
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn config(addr: SocketAddr, compression: FileCompression) -> S3SinkConfig {
+        S3SinkConfig {
+            endpoint: format!("http://{addr}"),
+            region: "us-east-1".into(),
+            bucket: "records".into(),
+            prefix: "river/".into(),
+            access_key_id: "minio".into(),
+            secret_access_key: "minio-secret".into(),
+            object_bytes: 1000,
+            object_seconds: 300,
+            part_bytes: 600,
+            max_attempts: 3,
+            compression,
+        }
+    }
+
+    fn record(change_id: &str, next_change_id: &str) -> StashRecord {
+        record_on(1, change_id, next_change_id)
+    }
+
+    fn record_on(day: u32, change_id: &str, next_change_id: &str) -> StashRecord {
+        StashRecord {
+            next_change_id: next_change_id.into(),
+            ..test_util::record(day, 14, change_id)
+        }
+    }
+
+    fn lines(object: &[u8]) -> Vec<StashRecord> {
+        object
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_s3_sink_uploads_change_id_ranges() {
+        let bucket = SharedBucket::default();
+        let addr = serve(bucket.clone()).await;
+        let sink = S3Sink::new(config(addr, FileCompression::None));
+
+        bucket.lock().unwrap().failures = 1;
+        bucket.lock().unwrap().failed_completions = 1;
+        sink.handle(&[record("1-1-1-1-1", "2-2-2-2-2")])
+            .await
+            .unwrap();
+        assert!(bucket.lock().unwrap().objects.is_empty());
+
+        // Exceeding object_bytes uploads the object, in parts as it is larger than part_bytes
+        sink.handle(&[
+            record("2-2-2-2-2", "3-3-3-3-3"),
+            record("2-2-2-2-2", "3-3-3-3-3"),
+            record("2-2-2-2-2", "3-3-3-3-3"),
+        ])
+        .await
+        .unwrap();
+        // The last object is uploaded when the indexer shuts down
+        sink.handle(&[record("3-3-3-3-3", "4-4-4-4-4")])
+            .await
+            .unwrap();
+        sink.flush().await.unwrap();
+
+        let bucket = bucket.lock().unwrap();
+        assert_eq!(
+            bucket.objects.keys().collect::<Vec<_>>(),
+            vec![
+                "river/2023/08/01/20230801T140512_1-1-1-1-1_3-3-3-3-3.ndjson",
+                "river/2023/08/01/20230801T140512_3-3-3-3-3_4-4-4-4-4.ndjson",
+            ]
+        );
+        let first = &bucket.objects["river/2023/08/01/20230801T140512_1-1-1-1-1_3-3-3-3-3.ndjson"];
+        assert_eq!(lines(first).len(), 4);
+        // Starting and completing the upload failed once each, then it was completed after
+        // uploading the parts
+        let requests = |method: &str| {
+            let request =
+                format!("{method} river/2023/08/01/20230801T140512_1-1-1-1-1_3-3-3-3-3.ndjson");
+            bucket.requests.iter().filter(|r| **r == request).count()
+        };
+        assert_eq!(requests("POST"), 4);
+        assert!(requests("PUT") > 1);
+        assert!(bucket.uploads.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_s3_sink_compresses_objects() {
+        let bucket = SharedBucket::default();
+        let addr = serve(bucket.clone()).await;
+        let sink = S3Sink::new(config(addr, FileCompression::Zstd));
+
+        sink.handle(&[record("1-1-1-1-1", "2-2-2-2-2")])
+            .await
+            .unwrap();
+        sink.flush().await.unwrap();
+
+        let bucket = bucket.lock().unwrap();
+        let object =
+            &bucket.objects["river/2023/08/01/20230801T140512_1-1-1-1-1_2-2-2-2-2.ndjson.zst"];
+        let mut decoded = vec![];
+        zstd::Decoder::new(object.as_slice())
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(lines(&decoded)[0].change_id, "1-1-1-1-1");
+    }
+
+    #[tokio::test]
+    async fn test_s3_sink_uploads_overdue_objects() {
+        let bucket = SharedBucket::default();
+        let addr = serve(bucket.clone()).await;
+        let sink = S3Sink::new(S3SinkConfig {
+            object_seconds: 60,
+            ..config(addr, FileCompression::None)
+        });
+
+        sink.handle(&[record("1-1-1-1-1", "2-2-2-2-2")])
+            .await
+            .unwrap();
+        sink.tick().await.unwrap();
+        assert!(bucket.lock().unwrap().objects.is_empty());
+
+        // Later pages that were filtered out entirely still count towards its age
+        sink.object.lock().await.as_mut().unwrap().started_at -= Duration::from_secs(60);
+        sink.tick().await.unwrap();
+        assert_eq!(sink.unpersisted_change_id().await, None);
+        assert_eq!(
+            bucket.lock().unwrap().objects.keys().collect::<Vec<_>>(),
+            vec!["river/2023/08/01/20230801T140512_1-1-1-1-1_2-2-2-2-2.ndjson"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_s3_sink_resumes_after_newest_object() {
+        let bucket = SharedBucket::default();
+        let addr = serve(bucket.clone()).await;
+        let sink = S3Sink::new(config(addr, FileCompression::Gzip));
+        assert_eq!(sink.get_next_change_id().await.unwrap(), None);
+
+        // Objects only exist in memory until they are uploaded
+        sink.handle(&[record_on(9, "1-1-1-1-1", "2-2-2-2-2")])
+            .await
+            .unwrap();
+        assert_eq!(
+            sink.unpersisted_change_id().await.as_deref(),
+            Some("1-1-1-1-1")
+        );
+        sink.flush().await.unwrap();
+        assert_eq!(sink.unpersisted_change_id().await, None);
+
+        for (day, change_id, next_change_id) in [
+            (10, "2-2-2-2-2", "3-3-3-3-3"),
+            (10, "3-3-3-3-3", "4-4-4-4-4"),
+            (11, "4-4-4-4-4", "5-5-5-5-5"),
+        ] {
+            let mut record = record_on(day, change_id, next_change_id);
+            record.chunk_id = day.into();
+            sink.handle(&[record]).await.unwrap();
+            sink.flush().await.unwrap();
+        }
+        bucket
+            .lock()
+            .unwrap()
+            .objects
+            .insert("river/notes.txt".into(), vec![]);
+
+        let sink = S3Sink::new(config(addr, FileCompression::Gzip));
+        assert_eq!(
+            sink.get_next_change_id().await.unwrap().as_deref(),
+            Some("5-5-5-5-5")
+        );
+        assert_eq!(sink.get_next_chunk_id().await.unwrap(), Some(12));
+    }
+}
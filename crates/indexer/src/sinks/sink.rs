@@ -1,18 +1,40 @@
 use async_trait::async_trait;
-use diesel::QueryResult;
-
-use crate::stash_record::StashRecord;
+use stash_api::common::StashRecord;
 
 #[async_trait]
-pub trait Sink {
+pub trait Sink: Sync {
     /// Handles processing a slice of `StashRecord`.
     async fn handle(&self, payload: &[StashRecord]) -> Result<usize, Box<dyn std::error::Error>>;
+
+    /// Called after every page, including those that were filtered out entirely and never
+    /// reached `handle`.
+    async fn tick(&self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    /// Writes out whatever the sink buffered, called once before the indexer shuts down.
+    async fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    /// The change id of the earliest page the sink buffered records of without persisting them
+    /// yet, or `None` if everything it handled is persisted. The state file is held back to it.
+    async fn unpersisted_change_id(&self) -> Option<String> {
+        None
+    }
+
+    /// Durable sinks tell how far they got, so that the indexer can resume from them.
+    fn resume(&self) -> Option<&dyn SinkResume> {
+        None
+    }
 }
 
 #[async_trait]
-pub trait SinkResume {
-    /// Returns the next chunk id to continue from counting chunks of `StashTabResponse`.
-    async fn get_next_chunk_id(&self) -> QueryResult<usize>;
-    /// Returns the next change id to continue from based on previously fetched data.
-    async fn get_next_change_id(&self) -> QueryResult<String>;
+pub trait SinkResume: Sync {
+    /// Returns the next chunk id to continue from counting chunks of `StashTabResponse`, or
+    /// `None` if the sink holds no data yet.
+    async fn get_next_chunk_id(&self) -> Result<Option<i64>, Box<dyn std::error::Error>>;
+    /// Returns the next change id to continue from based on previously fetched data, or `None`
+    /// if the sink holds no data yet.
+    async fn get_next_change_id(&self) -> Result<Option<String>, Box<dyn std::error::Error>>;
 }
@@ -0,0 +1,36 @@
+//! Fixtures shared by the tests of the sinks.
+
+use std::path::PathBuf;
+
+use chrono::NaiveDate;
+use stash_api::common::StashRecord;
+
+/// A record of an empty public stash in Standard, indexed on 2023-08-`day` at `hour`:05:12 from
+/// the page at `change_id`. Tests override whatever else they need.
+pub fn record(day: u32, hour: u32, change_id: &str) -> StashRecord {
+    StashRecord {
+        version: 1,
+        created_at: NaiveDate::from_ymd_opt(2023, 8, day)
+            .unwrap()
+            .and_hms_opt(hour, 5, 12)
+            .unwrap(),
+        change_id: change_id.into(),
+        next_change_id: format!("{change_id}-next"),
+        stash_id: "stash".into(),
+        stash_type: "PremiumStash".into(),
+        items: vec![],
+        public: true,
+        account_name: Some("account".into()),
+        last_character_name: None,
+        stash_name: None,
+        league: Some("Standard".into()),
+        chunk_id: 0,
+    }
+}
+
+/// An empty directory of the system's temp directory, unique to `name` and this test run.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("indexer-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
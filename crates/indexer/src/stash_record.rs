@@ -1,15 +1,13 @@
 #![allow(clippy::extra_unused_lifetimes)]
 
-use std::time::SystemTime;
-
 use crate::schema::stash_records;
-use chrono::{DateTime, NaiveDateTime, Utc};
-use serde::Serialize;
-use stash_api::{common::ChangeId, common::StashTabResponse};
+use chrono::NaiveDateTime;
+use stash_api::common::StashRecord;
 
-#[derive(Serialize, Insertable, Queryable)]
+/// The row a `StashRecord` is stored as in the `stash_records` table.
+#[derive(Insertable, Queryable)]
 #[diesel(table_name = stash_records)]
-pub struct StashRecord {
+pub struct StashRecordRow {
     pub created_at: NaiveDateTime,
     pub change_id: String,
     pub next_change_id: String,
@@ -24,31 +22,23 @@ pub struct StashRecord {
     pub chunk_id: i64,
 }
 
-pub fn map_to_stash_records(
-    change_id: ChangeId,
-    created_at: SystemTime,
-    payload: StashTabResponse,
-    chunk_id: i64,
-) -> impl Iterator<Item = StashRecord> {
-    let next_change_id = payload.next_change_id;
+impl TryFrom<&StashRecord> for StashRecordRow {
+    type Error = serde_json::Error;
 
-    payload
-        .stashes
-        .into_iter()
-        // Ignore stash tabs flagged as private, whose updates are always empty
-        .filter(|stash| stash.public)
-        .map(move |stash| StashRecord {
-            account_name: stash.account_name,
-            last_character_name: stash.last_character_name,
-            stash_id: stash.id,
-            stash_name: stash.stash,
-            stash_type: stash.stash_type,
-            items: serde_json::to_value(stash.items).expect("Serialization failed"),
-            public: stash.public,
-            league: stash.league,
-            change_id: change_id.clone().into(),
-            created_at: DateTime::<Utc>::from(created_at).naive_utc(),
-            next_change_id: next_change_id.clone(),
-            chunk_id,
+    fn try_from(record: &StashRecord) -> Result<Self, Self::Error> {
+        Ok(Self {
+            created_at: record.created_at,
+            change_id: record.change_id.clone(),
+            next_change_id: record.next_change_id.clone(),
+            stash_id: record.stash_id.clone(),
+            stash_type: record.stash_type.clone(),
+            items: serde_json::to_value(&record.items)?,
+            public: record.public,
+            account_name: record.account_name.clone(),
+            last_character_name: record.last_character_name.clone(),
+            stash_name: record.stash_name.clone(),
+            league: record.league.clone(),
+            chunk_id: record.chunk_id,
         })
+    }
 }
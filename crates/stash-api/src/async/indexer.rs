@@ -1,205 +1,722 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context as TaskContext, Poll},
+    time::Instant,
+};
 
-use bytes::BytesMut;
-use reqwest::StatusCode;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::RwLock;
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender},
+    oneshot, watch,
+};
+use tokio::task::{JoinError, JoinHandle};
 use tracing::{debug, error, error_span, info, trace, trace_span};
 use trade_common::telemetry::generate_http_client;
 
-use crate::common::parse::parse_change_id_from_bytes;
-use crate::common::poe_api::{get_oauth_token, user_agent, OAuthResponse};
-use crate::common::{ChangeId, StashTabResponse};
+use crate::common::encoding::TransferStats;
+use crate::common::indexing::{IndexerCore, Prefetch, Recovery, EMPTY_PAGE_DELAY};
+use crate::common::poe_ninja_client::PoeNinjaClient;
+use crate::common::reorder::ReorderBuffer;
+use crate::common::retry::RetryPolicy;
+use crate::common::{ChangeId, Endpoints, StashTabResponse};
+use crate::Error;
+
+pub use crate::common::indexing::IndexerEvent;
+
+use super::shards::ShardWalk;
+use super::stream::StashStream;
 
-#[derive(Default, Debug)]
-pub struct Indexer;
+/// How many river pages are requested concurrently by default.
+const DEFAULT_MAX_IN_FLIGHT: usize = 3;
+const CHANNEL_CAPACITY: usize = 42;
+
+#[derive(Debug)]
+pub struct Indexer {
+    endpoints: Endpoints,
+    max_in_flight: usize,
+    reordered_pages: Arc<AtomicU64>,
+    retry_policy: RetryPolicy,
+    parallel_catch_up: bool,
+}
+
+impl Default for Indexer {
+    fn default() -> Self {
+        Self {
+            endpoints: Endpoints::default(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            reordered_pages: Arc::new(AtomicU64::new(0)),
+            retry_policy: RetryPolicy::default(),
+            parallel_catch_up: false,
+        }
+    }
+}
 
 #[cfg(feature = "async")]
 impl Indexer {
     pub fn new() -> Self {
-        Self {}
+        Self::default()
+    }
+
+    /// Overrides the endpoints to fetch river pages, OAuth tokens and the latest change id from.
+    pub fn with_endpoints(mut self, endpoints: Endpoints) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    /// Limits how many river pages are requested concurrently. Defaults to 3.
+    ///
+    /// The next page is requested as soon as the next change id is known, which is long before
+    /// the current page finished downloading. Pages are still delivered in river order and count
+    /// against the limit until they are, so a consumer that does not keep up eventually stops
+    /// new requests from being sent.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    /// Overrides how often and how long to wait before failed requests are sent again.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Catches up with the head of the river by walking every shard with its own chain of
+    /// requests, all sharing the rate limit, before continuing in river order. Disabled by default.
+    ///
+    /// Pages of different shards are handed out as they arrive. The change ids of their ticks and
+    /// [`IndexerHandle::current_change_id`] are where all shards are at so far, so restarting at
+    /// them neither skips nor repeats any updates. Relies on the API not returning any updates
+    /// for shards whose offset is beyond the head of the river.
+    pub fn with_parallel_catch_up(mut self, parallel_catch_up: bool) -> Self {
+        self.parallel_catch_up = parallel_catch_up;
+        self
+    }
+
+    /// How many pages finished downloading before the page preceding them in the river and had
+    /// to be held back to be delivered in order.
+    pub fn reordered_pages(&self) -> u64 {
+        self.reordered_pages.load(Ordering::Relaxed)
+    }
+
+    /// Start the indexer with the latest change_id from poe.ninja
+    pub async fn start_with_latest(
+        &self,
+        client_id: String,
+        client_secret: String,
+    ) -> Result<IndexerStream, Error> {
+        let latest_change_id = PoeNinjaClient::new(self.endpoints.poe_ninja_stats())
+            .fetch_latest_change_id_async()
+            .await?;
+        info!("Fetched latest change id: {}", latest_change_id);
+        self.start_at_change_id(client_id, client_secret, latest_change_id)
+            .await
     }
 
     /// Start the indexer with a given change_id
+    ///
+    /// Fails if no OAuth token can be obtained for the given credentials. The indexer runs until
+    /// it is stopped via [`IndexerHandle::stop`] or the returned stream is dropped.
     pub async fn start_at_change_id(
         &self,
         client_id: String,
         client_secret: String,
         change_id: ChangeId,
-    ) -> Receiver<IndexerMessage> {
+    ) -> Result<IndexerStream, Error> {
         // Workaround to not have to use [tracing::instrument]
-        trace_span!("start_at_change_id", change_id = change_id.inner.as_str());
+        trace_span!("start_at_change_id", change_id = change_id.as_str());
 
         info!("Starting at change id: {}", change_id);
 
-        let credentials = get_oauth_token(&client_id, &client_secret)
-            .await
-            .expect("Fetch OAuth credentials");
+        let core = IndexerCore::new(self.endpoints.clone(), client_id, client_secret)
+            .with_retry_policy(self.retry_policy.clone());
+        core.token_provider().access_token().await?;
+
+        let catch_up_to = if self.parallel_catch_up {
+            let head = PoeNinjaClient::new(self.endpoints.poe_ninja_stats())
+                .fetch_latest_change_id_async()
+                .await;
+            match head {
+                Ok(head) => Some(head),
+                Err(e) => {
+                    error!(
+                        "Not catching up in parallel, fetching the head failed: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let (tx, rx) = channel(CHANNEL_CAPACITY);
+        let (control_tx, control_rx) = watch::channel(Control::Run);
 
-        let (tx, rx) = channel(42);
+        let context = Arc::new(Context { tx, core });
+        tokio::spawn(run(
+            context,
+            change_id.clone(),
+            catch_up_to,
+            control_rx,
+            self.max_in_flight,
+            self.reordered_pages.clone(),
+        ));
 
-        schedule_job(
-            tx,
-            change_id,
-            client_id,
-            client_secret,
-            Arc::new(RwLock::new(Some(credentials))),
-        );
-        rx
+        Ok(IndexerStream {
+            rx,
+            handle: IndexerHandle {
+                shared: Arc::new(Shared {
+                    control: control_tx,
+                    current_change_id: Mutex::new(change_id),
+                }),
+            },
+            is_terminated: false,
+        })
     }
 }
 
-fn schedule_job(
-    tx: Sender<IndexerMessage>,
-    next_change_id: ChangeId,
-    client_id: String,
-    client_secret: String,
-    config: Arc<RwLock<Option<OAuthResponse>>>,
+/// What the consumer asked a running indexer to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Run,
+    Pause,
+    Stop,
+}
+
+/// State shared between the stream of a running indexer and its handles.
+#[derive(Debug)]
+struct Shared {
+    control: watch::Sender<Control>,
+    current_change_id: Mutex<ChangeId>,
+}
+
+/// Controls a running indexer. Obtained via [`IndexerStream::handle`] and cheap to clone.
+#[derive(Debug, Clone)]
+pub struct IndexerHandle {
+    shared: Arc<Shared>,
+}
+
+impl IndexerHandle {
+    /// Stops the indexer. Pages that were already handed out are still delivered, followed by
+    /// `IndexerEvent::Stop`, after which the stream ends. Pages still being downloaded are
+    /// discarded, so resuming from [`IndexerHandle::current_change_id`] does not skip any.
+    pub fn stop(&self) {
+        self.shared.control.send_replace(Control::Stop);
+    }
+
+    /// Stops requesting new pages until [`IndexerHandle::resume`] is called. Pages that are
+    /// already being downloaded are still delivered.
+    pub fn pause(&self) {
+        self.transition(Control::Run, Control::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.transition(Control::Pause, Control::Run);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.shared.control.borrow() == Control::Pause
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.shared.control.borrow() == Control::Stop
+    }
+
+    /// The change id to continue from, ie. the next change id of the last page the stream
+    /// yielded, or the change id the indexer was started at.
+    pub fn current_change_id(&self) -> ChangeId {
+        self.shared.current_change_id.lock().unwrap().clone()
+    }
+
+    fn transition(&self, from: Control, to: Control) {
+        self.shared.control.send_if_modified(|control| {
+            let modified = *control == from;
+            if modified {
+                *control = to;
+            }
+            modified
+        });
+    }
+}
+
+/// The events of a running indexer, in river order. Ends after `IndexerEvent::Stop`.
+#[derive(Debug)]
+pub struct IndexerStream {
+    rx: Receiver<IndexerEvent>,
+    handle: IndexerHandle,
+    is_terminated: bool,
+}
+
+impl IndexerStream {
+    pub fn handle(&self) -> IndexerHandle {
+        self.handle.clone()
+    }
+}
+
+impl Stream for IndexerStream {
+    type Item = IndexerEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_terminated {
+            return Poll::Ready(None);
+        }
+
+        let event = futures::ready!(self.rx.poll_recv(cx));
+        match &event {
+            Some(IndexerEvent::Tick { response, .. }) => {
+                if let Ok(next_change_id) = ChangeId::from_str(&response.next_change_id) {
+                    *self.handle.shared.current_change_id.lock().unwrap() = next_change_id;
+                }
+            }
+            Some(IndexerEvent::Stop) | None => self.is_terminated = true,
+            _ => {}
+        }
+
+        Poll::Ready(event)
+    }
+}
+
+/// Everything the fetches of a running indexer share.
+struct Context {
+    tx: Sender<IndexerEvent>,
+    core: IndexerCore,
+}
+
+/// A page that has been read completely.
+struct Page {
+    response: StashTabResponse,
+    transfer: TransferStats,
+}
+
+/// A page being fetched, resolving to the change id of the page before it, its own change id and
+/// its content. The content is `None` if the indexer has to stop.
+type Fetch = JoinHandle<(ChangeId, ChangeId, Option<Page>)>;
+
+enum PipelineEvent {
+    /// The consumer paused, resumed or stopped the indexer.
+    Control(Control),
+    NextChangeId(Option<ChangeId>),
+    Fetched(Result<(ChangeId, ChangeId, Option<Page>), JoinError>),
+}
+
+/// Drives the river: requests the next page as soon as its change id is known, while keeping at
+/// most `max_in_flight` pages in flight or waiting to be handed out in river order.
+///
+/// Catches up with `catch_up_to` shard by shard first, if given.
+async fn run(
+    context: Arc<Context>,
+    change_id: ChangeId,
+    catch_up_to: Option<ChangeId>,
+    mut control: watch::Receiver<Control>,
+    max_in_flight: usize,
+    reordered_pages: Arc<AtomicU64>,
 ) {
-    tokio::spawn(process(
-        next_change_id,
-        tx,
-        client_id,
-        client_secret,
-        config,
+    let mut previous_change_id = change_id.clone();
+    let change_id = match catch_up_to {
+        Some(head) => {
+            match catch_up(
+                &context,
+                &change_id,
+                &head,
+                &mut control,
+                &mut previous_change_id,
+            )
+            .await
+            {
+                Some(change_id) => change_id,
+                None => return,
+            }
+        }
+        None => change_id,
+    };
+
+    let mut in_flight: FuturesUnordered<Fetch> = FuturesUnordered::new();
+    let mut reorder_buffer = ReorderBuffer::new(change_id.clone());
+    let mut next_change_id = Some(spawn_fetch(
+        &context,
+        change_id.clone(),
+        change_id.clone(),
+        &mut in_flight,
     ));
+    let mut last_requested_change_id = change_id;
+
+    'pipeline: loop {
+        let can_fetch_next = next_change_id.is_some()
+            && *control.borrow() == Control::Run
+            && in_flight.len() + reorder_buffer.len() < max_in_flight;
+
+        let event = tokio::select! {
+            biased;
+            // All handles are gone once the stream is dropped
+            changed = control.changed() => match changed {
+                Ok(()) => PipelineEvent::Control(*control.borrow_and_update()),
+                Err(_) => PipelineEvent::Control(Control::Stop),
+            },
+            Some(fetched) = in_flight.next() => PipelineEvent::Fetched(fetched),
+            next = async { next_change_id.as_mut().unwrap().await.ok() }, if can_fetch_next => {
+                PipelineEvent::NextChangeId(next)
+            }
+            else => break,
+        };
+
+        let (page_previous_change_id, page_change_id, page) = match event {
+            PipelineEvent::Control(Control::Stop) => {
+                info!("Stopping as requested");
+                let _ = context.tx.send(IndexerEvent::Stop).await;
+                break;
+            }
+            PipelineEvent::Control(state) => {
+                info!("Indexer is now {:?}", state);
+                continue;
+            }
+            PipelineEvent::NextChangeId(Some(change_id)) => {
+                next_change_id = Some(spawn_fetch(
+                    &context,
+                    std::mem::replace(&mut last_requested_change_id, change_id.clone()),
+                    change_id,
+                    &mut in_flight,
+                ));
+                continue;
+            }
+            PipelineEvent::NextChangeId(None) => {
+                next_change_id = None;
+                continue;
+            }
+            PipelineEvent::Fetched(Ok(fetched)) => fetched,
+            PipelineEvent::Fetched(Err(e)) => {
+                error!("Fetching river page failed: {}", e);
+                let _ = context.tx.send(IndexerEvent::Stop).await;
+                break;
+            }
+        };
+
+        let before = reorder_buffer.reordered();
+        let ready = reorder_buffer.push(page_previous_change_id, page_change_id, page);
+        if reorder_buffer.reordered() > before {
+            debug!("Page arrived before its predecessor, waiting for it");
+            reordered_pages.fetch_add(1, Ordering::Relaxed);
+        }
+
+        for (change_id, page) in ready {
+            let Page { response, transfer } = match page {
+                Some(page) => page,
+                None => {
+                    let _ = context.tx.send(IndexerEvent::Stop).await;
+                    break 'pipeline;
+                }
+            };
+            debug!(
+                "Read response {} with {} stashes",
+                response.next_change_id,
+                response.stashes.len()
+            );
+            trace!(number_stashes = ?response.stashes.len());
+
+            // Waits for the consumer to catch up if the channel is full
+            let tick = IndexerEvent::Tick {
+                response,
+                change_id: change_id.clone(),
+                previous_change_id: previous_change_id.clone(),
+                created_at: std::time::SystemTime::now(),
+                transfer,
+            };
+            if context.tx.send(tick).await.is_err() {
+                break 'pipeline;
+            }
+            previous_change_id = change_id;
+        }
+    }
+
+    for handle in in_flight.iter() {
+        handle.abort();
+    }
 }
 
-#[tracing::instrument(skip(tx))]
-async fn process(
+/// Walks every shard that is behind `head` with its own chain of requests until all of them caught
+/// up and returns where the river continues, or `None` if the indexer has to stop.
+///
+/// Continues serially from where all shards are at as soon as a request of a chain fails or its
+/// page cannot be used, see [`ShardWalk::advance`].
+///
+/// `previous_change_id` is the change id of the last tick that has been handed out.
+async fn catch_up(
+    context: &Arc<Context>,
+    change_id: &ChangeId,
+    head: &ChangeId,
+    control: &mut watch::Receiver<Control>,
+    previous_change_id: &mut ChangeId,
+) -> Option<ChangeId> {
+    let mut walk = ShardWalk::new(change_id, head);
+    let mut waiting = walk.behind_shards();
+    let mut in_flight: FuturesUnordered<JoinHandle<(usize, Option<Page>)>> =
+        FuturesUnordered::new();
+    info!("Catching up with {} on shards {:?}", head, waiting);
+
+    let continue_at = loop {
+        if *control.borrow() == Control::Run {
+            for shard in waiting.drain(..) {
+                let context = context.clone();
+                let change_id = walk.request(shard);
+                in_flight.push(tokio::spawn(async move {
+                    (shard, fetch_pinned(context, change_id).await)
+                }));
+            }
+        }
+
+        if in_flight.is_empty() && waiting.is_empty() {
+            let position = walk.position();
+            info!("Caught up at {}", position);
+            return Some(position);
+        }
+
+        let event = tokio::select! {
+            biased;
+            changed = control.changed() => match changed {
+                Ok(()) => PipelineEvent::Control(*control.borrow_and_update()),
+                Err(_) => PipelineEvent::Control(Control::Stop),
+            },
+            Some(fetched) = in_flight.next() => {
+                let (shard, page) = match fetched {
+                    Ok((shard, Some(page))) => (shard, page),
+                    Ok((shard, None)) => {
+                        info!("Walking shard {} failed, continuing serially", shard);
+                        break Some(walk.position());
+                    }
+                    Err(e) => {
+                        error!("Fetching river page failed: {}", e);
+                        break Some(walk.position());
+                    }
+                };
+
+                let position = walk.position();
+                let next_change_id = ChangeId::from_str(&page.response.next_change_id)
+                    .ok()
+                    .and_then(|next_change_id| walk.advance(shard, &next_change_id));
+                let Some(next_change_id) = next_change_id else {
+                    info!(
+                        "Page of shard {} points to {}, continuing serially",
+                        shard, page.response.next_change_id
+                    );
+                    break Some(position);
+                };
+                if !walk.is_caught_up(shard) {
+                    waiting.push(shard);
+                }
+
+                let tick = IndexerEvent::Tick {
+                    response: StashTabResponse {
+                        next_change_id: next_change_id.to_string(),
+                        stashes: page.response.stashes,
+                    },
+                    change_id: position.clone(),
+                    previous_change_id: std::mem::replace(previous_change_id, position),
+                    created_at: std::time::SystemTime::now(),
+                    transfer: page.transfer,
+                };
+                if context.tx.send(tick).await.is_err() {
+                    break None;
+                }
+                continue;
+            }
+        };
+
+        match event {
+            PipelineEvent::Control(Control::Stop) => {
+                let _ = context.tx.send(IndexerEvent::Stop).await;
+                break None;
+            }
+            PipelineEvent::Control(state) => info!("Indexer is now {:?}", state),
+            _ => {}
+        }
+    };
+
+    for handle in in_flight.iter() {
+        handle.abort();
+    }
+    continue_at
+}
+
+/// Requests a page of a shard walk once. Whether the API keeps pinned offsets is not documented,
+/// so errors are not retried but end the walk.
+async fn fetch_pinned(context: Arc<Context>, change_id: ChangeId) -> Option<Page> {
+    // Only pages of the regular river are requested ahead of time
+    match fetch_page(&context, &change_id, &mut None).await {
+        Ok(page) => page,
+        Err(e) => {
+            error!("Fetching pinned change id {} failed: {}", change_id, e);
+            let _ = context.tx.send(IndexerEvent::Error(e)).await;
+            None
+        }
+    }
+}
+
+/// Starts fetching `change_id`, returning a receiver for the change id of the following page.
+fn spawn_fetch(
+    context: &Arc<Context>,
+    previous_change_id: ChangeId,
     change_id: ChangeId,
-    tx: Sender<IndexerMessage>,
-    client_id: String,
-    client_secret: String,
-    config: Arc<RwLock<Option<OAuthResponse>>>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // check if stopping
-    if tx.is_closed() {
-        return Ok(());
-    }
-
-    let url = format!(
-        "https://api.pathofexile.com/public-stash-tabs?id={}",
-        &change_id
-    );
+    in_flight: &mut FuturesUnordered<Fetch>,
+) -> oneshot::Receiver<ChangeId> {
+    let (next_tx, next_rx) = oneshot::channel();
+    let context = context.clone();
+    in_flight.push(tokio::spawn(async move {
+        let page = fetch(context, change_id.clone(), next_tx).await;
+        (previous_change_id, change_id, page)
+    }));
+    next_rx
+}
+
+/// Fetches `change_id` until it succeeds or the retry budget is used up, reporting errors along
+/// the way.
+#[tracing::instrument(skip(context, next_tx))]
+async fn fetch(
+    context: Arc<Context>,
+    change_id: ChangeId,
+    next_tx: oneshot::Sender<ChangeId>,
+) -> Option<Page> {
+    let mut next_tx = Some(next_tx);
+    let mut failed_attempts = 0;
+
+    loop {
+        let e = match fetch_page(&context, &change_id, &mut next_tx).await {
+            Ok(Some(page)) => return Some(page),
+            Ok(None) => continue,
+            Err(e) => e,
+        };
+
+        match context.core.recovery(&e, &mut failed_attempts) {
+            Recovery::Stop => {
+                error!("Stopping due to {}", e);
+                let _ = context.tx.send(IndexerEvent::Error(e)).await;
+                return None;
+            }
+            Recovery::Exhausted(attempts) => {
+                error!("Giving up after {} attempts due to {}", attempts, e);
+                let _ = context.tx.send(IndexerEvent::Error(e)).await;
+                let _ = context
+                    .tx
+                    .send(IndexerEvent::RetriesExhausted {
+                        change_id,
+                        attempts,
+                    })
+                    .await;
+                return None;
+            }
+            Recovery::Retry(delay) => {
+                info!("Rescheduling in {}ms due to {}", delay.as_millis(), e);
+                if context.tx.send(IndexerEvent::Error(e)).await.is_err() {
+                    return None;
+                }
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Requests and reads a single page. Returns `None` if the page is empty, ie. because we
+/// reached the head of the river, and has to be requested again.
+///
+/// The change id of the following page is sent to `next_tx` as soon as it is read. Without
+/// `next_tx`, pages are returned as they are.
+async fn fetch_page(
+    context: &Context,
+    change_id: &ChangeId,
+    next_tx: &mut Option<oneshot::Sender<ChangeId>>,
+) -> Result<Option<Page>, Error> {
+    let access_token = context.core.token_provider().access_token().await?;
+
+    // Pace requests so that we stay within every rate limit window the API told us about
+    while let Err(wait_time) = context.core.try_acquire() {
+        debug!("Throttling for {}ms", wait_time.as_millis());
+        let _ = context.tx.send(IndexerEvent::RateLimited(wait_time)).await;
+        tokio::time::sleep(wait_time).await;
+    }
+
+    let url = context.core.url(change_id);
     debug!("Requesting {}", url);
 
     // TODO: static client somewhere
     let client = generate_http_client();
-    let response = client
-        .get(url)
-        .header("Accept", "application/json")
-        .header("User-Agent", user_agent(&client_id))
-        .header(
-            "Authorization",
-            format!(
-                "Bearer {}",
-                config
-                    .read()
-                    .await
-                    .as_ref()
-                    .expect("OAuth credentials are set")
-                    .access_token
-            )
-            .as_str(),
-        )
-        .send()
-        .await;
+    let mut request = client.get(url);
+    for (name, value) in context.core.headers(&access_token) {
+        request = request.header(name, value);
+    }
 
-    let mut response = match response {
+    let requested_at = Instant::now();
+    let response = match request.send().await {
         Err(e) => {
             error!("Error when fetching change_id {}: {:?}", change_id, e);
             error_span!("handle_fetch_error").in_scope(|| {
                 error!("Error response: {:?}", e);
                 error!(fetch_error = ?e);
-                schedule_job(tx, change_id, client_id, client_secret, config);
             });
-            return Ok(());
+            return Err(Error::Transport(e.to_string()));
         }
         Ok(data) => data,
     };
 
-    if response.status() != StatusCode::OK {
-        info!(
-            "Rescheduling in 60s due to HTTP response status code {}",
-            response.status().as_u16()
-        );
-        tokio::time::sleep(Duration::from_secs(60)).await;
-        schedule_job(tx, change_id, client_id, client_secret, config);
-        return Ok(());
-    }
-
-    let mut bytes = BytesMut::new();
-    let mut prefetch_done = false;
-    while let Some(chunk) = response.chunk().await? {
-        bytes.extend_from_slice(&chunk);
-
-        if bytes.len() > 60 && !prefetch_done {
-            if seems_empty(&bytes) {
-                info!("Rescheduling in 4s due to empty response");
-                tokio::time::sleep(Duration::from_secs(4)).await;
-                schedule_job(tx, change_id, client_id, client_secret, config);
-                return Ok(());
-            } else {
-                let next_change_id = parse_change_id_from_bytes(&bytes).unwrap();
-                tracing::trace!(next_change_id = ?next_change_id);
-                prefetch_done = true;
-                schedule_job(
-                    tx.clone(),
-                    next_change_id,
-                    client_id.clone(),
-                    client_secret.clone(),
-                    config.clone(),
-                );
+    let content_encoding = context.core.on_response(
+        change_id,
+        &access_token,
+        response.status().as_u16(),
+        |name| response.headers().get(name)?.to_str().ok(),
+    )?;
+
+    // Stashes are deserialized while the body is still downloading so that the request for the
+    // next page can be sent as soon as its change id has been read
+    let mut stream = StashStream::from_response(change_id.clone(), response)
+        .with_content_encoding(content_encoding, requested_at);
+    let mut stashes = vec![];
+    loop {
+        let stash = stream.next().await;
+
+        if next_tx.is_some() {
+            match stream.prefetched()? {
+                Prefetch::Pending => {}
+                Prefetch::Empty => {
+                    info!("Rescheduling in 4s due to empty response");
+                    tokio::time::sleep(EMPTY_PAGE_DELAY).await;
+                    return Ok(None);
+                }
+                Prefetch::Next(next_change_id) => {
+                    tracing::trace!(next_change_id = ?next_change_id);
+                    if let Some(next_tx) = next_tx.take() {
+                        let _ = next_tx.send(next_change_id);
+                    }
+                }
             }
         }
-    }
 
-    let deserialised = match serde_json::from_slice::<StashTabResponse>(&bytes) {
-        Ok(deserialised) => deserialised,
-        Err(e) => {
-            info!(
-                "Rescheduling in 5s due to deserialization issue {}",
-                e.to_string()
-            );
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            schedule_job(tx, change_id, client_id, client_secret, config);
-            return Ok(());
+        match stash {
+            Some(stash) => stashes.push(stash?),
+            None => break,
         }
-    };
+    }
+
+    let transfer = *stream.transfer();
     debug!(
-        "Read response {} with {} stashes",
-        deserialised.next_change_id,
-        deserialised.stashes.len()
+        "Downloaded {} bytes ({} decoded, {}) in {}ms",
+        transfer.compressed_bytes,
+        transfer.decoded_bytes,
+        transfer.content_encoding,
+        transfer.download_time.as_millis()
     );
-    trace!(number_stashes = ?deserialised.stashes.len());
-
-    tx.try_send(IndexerMessage::Tick {
-        response: deserialised,
-        previous_change_id: change_id.clone(),
-        change_id,
-        created_at: std::time::SystemTime::now(),
-    })
-    .expect("Sending IndexerMessage failed");
-
-    Ok(())
-}
-
-fn seems_empty(bytes: &[u8]) -> bool {
-    match std::str::from_utf8(bytes) {
-        Ok(text) => text.contains("stashes:[]") || text.contains("\"stashes\":[]"),
-        Err(_) => false,
-    }
-}
 
-#[derive(Debug, Clone)]
-pub enum IndexerMessage {
-    Tick {
-        response: StashTabResponse,
-        change_id: ChangeId,
-        previous_change_id: ChangeId,
-        created_at: std::time::SystemTime,
-    },
-    RateLimited(Duration),
-    Stop,
+    let next_change_id = stream.finish()?;
+    Ok(Some(Page {
+        response: StashTabResponse {
+            next_change_id: next_change_id.to_string(),
+            stashes,
+        },
+        transfer,
+    }))
 }
@@ -1 +1,3 @@
 pub mod indexer;
+mod shards;
+pub mod stream;
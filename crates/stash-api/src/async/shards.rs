@@ -0,0 +1,138 @@
+//! Bookkeeping for catching up with the river by walking every shard on its own.
+//!
+//! Pages contain the updates of all shards from the offsets in the requested change id on. A
+//! chain of requests that only walks a single shard pins the offsets of all other shards far
+//! beyond the head of the river, so that its pages only contain updates of that one shard.
+//! Chains of different shards thus never overlap and can be requested concurrently.
+//!
+//! How the API treats offsets beyond the head is not documented. It may clamp them to the head,
+//! in which case pages contain updates of other shards as well. Pages that do not keep all other
+//! shards pinned are thus never taken, and the indexer walks the river serially instead.
+
+use crate::common::{ChangeId, SHARD_COUNT};
+
+/// The offset the shards that are not walked by a chain are pinned to. The river is not going to
+/// reach it, so pages never contain any updates of these shards as long as the API keeps it.
+const PINNED_OFFSET: u64 = i64::MAX as u64;
+
+/// Where every shard is at while catching up with `head`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ShardWalk {
+    position: [u64; SHARD_COUNT],
+    head: [u64; SHARD_COUNT],
+}
+
+impl ShardWalk {
+    pub(crate) fn new(start: &ChangeId, head: &ChangeId) -> Self {
+        Self {
+            position: *start.shards(),
+            head: *head.shards(),
+        }
+    }
+
+    /// The shards that still have to catch up.
+    pub(crate) fn behind_shards(&self) -> Vec<usize> {
+        (0..SHARD_COUNT)
+            .filter(|shard| !self.is_caught_up(*shard))
+            .collect()
+    }
+
+    pub(crate) fn is_caught_up(&self, shard: usize) -> bool {
+        self.position[shard] >= self.head[shard]
+    }
+
+    /// The change id to request next for `shard`.
+    pub(crate) fn request(&self, shard: usize) -> ChangeId {
+        let mut shards = [PINNED_OFFSET; SHARD_COUNT];
+        shards[shard] = self.position[shard];
+        ChangeId::from_shards(shards)
+    }
+
+    /// Moves `shard` on to where the page requested for it points to and returns the new
+    /// position, or `None` if the page did not move `shard` on or did not keep all other shards
+    /// pinned. Such a page may contain updates of other shards and must not be used.
+    pub(crate) fn advance(&mut self, shard: usize, next_change_id: &ChangeId) -> Option<ChangeId> {
+        let next = next_change_id.shards();
+        let is_pinned = (0..SHARD_COUNT)
+            .filter(|other| *other != shard)
+            .all(|other| next[other] == PINNED_OFFSET);
+        if !is_pinned || next[shard] <= self.position[shard] {
+            return None;
+        }
+
+        self.position[shard] = next[shard];
+        Some(self.position())
+    }
+
+    /// Every shard up to where all pages that have been walked so far end, ie. where the river
+    /// continues without skipping or repeating any updates.
+    pub(crate) fn position(&self) -> ChangeId {
+        ChangeId::from_shards(self.position)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::{ShardWalk, PINNED_OFFSET};
+    use crate::common::ChangeId;
+
+    fn id(s: &str) -> ChangeId {
+        ChangeId::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_shard_walk() {
+        let mut walk = ShardWalk::new(&id("10-20-30-40-50"), &id("12-20-35-40-49"));
+        assert_eq!(walk.behind_shards(), vec![0, 2]);
+        assert_eq!(
+            walk.request(2).shards(),
+            &[
+                PINNED_OFFSET,
+                PINNED_OFFSET,
+                30,
+                PINNED_OFFSET,
+                PINNED_OFFSET
+            ]
+        );
+
+        let next = ChangeId::from_shards([
+            PINNED_OFFSET,
+            PINNED_OFFSET,
+            33,
+            PINNED_OFFSET,
+            PINNED_OFFSET,
+        ]);
+        assert_eq!(walk.advance(2, &next), Some(id("10-20-33-40-50")));
+        assert!(!walk.is_caught_up(2));
+
+        let next = ChangeId::from_shards([
+            13,
+            PINNED_OFFSET,
+            PINNED_OFFSET,
+            PINNED_OFFSET,
+            PINNED_OFFSET,
+        ]);
+        assert_eq!(walk.advance(0, &next), Some(id("13-20-33-40-50")));
+        assert_eq!(walk.behind_shards(), vec![2]);
+    }
+
+    #[test]
+    fn test_shard_walk_rejects_clamped_pages() {
+        let mut walk = ShardWalk::new(&id("10-20-30-40-50"), &id("12-20-35-40-49"));
+
+        // Other shards clamped to the head
+        assert_eq!(walk.advance(0, &id("11-20-35-40-49")), None);
+        // The walked shard did not move on
+        let next = ChangeId::from_shards([
+            10,
+            PINNED_OFFSET,
+            PINNED_OFFSET,
+            PINNED_OFFSET,
+            PINNED_OFFSET,
+        ]);
+        assert_eq!(walk.advance(0, &next), None);
+        assert_eq!(walk.position(), id("10-20-30-40-50"));
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use futures::stream::{BoxStream, Stream, StreamExt};
+
+use crate::common::{
+    encoding::{BodyDecoder, ContentEncoding, TransferStats},
+    indexing::Prefetch,
+    stream::StashParser,
+    ChangeId, Stash,
+};
+use crate::Error;
+
+/// Yields the `Stash`es of a river page while its body is still being downloaded.
+///
+/// The change id of the following page is available via [`StashStream::next_change_id`] as soon
+/// as it has been read, and via [`StashStream::finish`] once all stashes have been yielded.
+pub struct StashStream<S> {
+    body: S,
+    decoder: BodyDecoder,
+    parser: StashParser,
+    is_exhausted: bool,
+}
+
+impl StashStream<BoxStream<'static, Result<Bytes, reqwest::Error>>> {
+    /// Streams the body of the response to the request for `change_id`.
+    pub fn from_response(change_id: ChangeId, response: reqwest::Response) -> Self {
+        let body = futures::stream::unfold(response, |mut response| async move {
+            let chunk = response.chunk().await.transpose()?;
+            Some((chunk, response))
+        });
+        Self::new(change_id, body.boxed())
+    }
+}
+
+impl<S, B, E> StashStream<S>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: std::fmt::Display,
+{
+    pub fn new(change_id: ChangeId, body: S) -> Self {
+        Self {
+            body,
+            decoder: BodyDecoder::new(ContentEncoding::Identity, Instant::now()),
+            parser: StashParser::new(change_id),
+            is_exhausted: false,
+        }
+    }
+
+    /// Decodes the body according to its `Content-Encoding`. `requested_at` is when the request
+    /// for the page was sent, which the download time in [`StashStream::transfer`] starts from.
+    pub fn with_content_encoding(
+        mut self,
+        content_encoding: ContentEncoding,
+        requested_at: Instant,
+    ) -> Self {
+        self.decoder = BodyDecoder::new(content_encoding, requested_at);
+        self
+    }
+
+    /// How the body has been transferred so far. Complete once all stashes have been yielded.
+    pub fn transfer(&self) -> &TransferStats {
+        self.decoder.stats()
+    }
+
+    pub fn next_change_id(&self) -> Option<&ChangeId> {
+        self.parser.next_change_id()
+    }
+
+    /// Whether the page contains any stashes, as soon as this is known.
+    pub fn has_stashes(&self) -> Option<bool> {
+        self.parser.has_stashes()
+    }
+
+    pub(crate) fn prefetched(&self) -> Result<Prefetch, Error> {
+        self.parser.prefetched()
+    }
+
+    pub fn finish(self) -> Result<ChangeId, Error> {
+        self.parser.finish()
+    }
+}
+
+impl<S, B, E> Stream for StashStream<S>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: std::fmt::Display,
+{
+    type Item = Result<Stash, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        loop {
+            match this.parser.next_stash() {
+                Ok(Some(stash)) => return Poll::Ready(Some(Ok(stash))),
+                Err(e) => return Poll::Ready(Some(Err(e))),
+                Ok(None) if this.is_exhausted => return Poll::Ready(None),
+                // Keeps reading after the end of the page to decode the rest of the body
+                Ok(None) => {}
+            }
+
+            let decoded = match futures::ready!(this.body.poll_next_unpin(cx)) {
+                Some(Ok(chunk)) => this
+                    .decoder
+                    .decode(chunk.as_ref())
+                    .map(|decoded| this.parser.feed(&decoded)),
+                Some(Err(e)) => Err(Error::Transport(e.to_string())),
+                None => {
+                    this.is_exhausted = true;
+                    this.decoder.finish().map(|rest| this.parser.feed(&rest))
+                }
+            };
+
+            if let Err(e) = decoded {
+                this.is_exhausted = true;
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use futures::StreamExt;
+
+    use super::StashStream;
+    use crate::common::ChangeId;
+
+    #[tokio::test]
+    async fn test_stash_stream() {
+        let page = br#"{"next_change_id": "2-2-2-2-2", "stashes": [
+            {"accountName": "a", "id": "1", "stashType": "PremiumStash", "items": [], "public": true, "league": null},
+            {"accountName": null, "id": "2", "stashType": "PremiumStash", "items": [], "public": false, "league": "Standard"}
+        ]}"#;
+        let chunks = page
+            .chunks(16)
+            .map(Ok::<_, std::io::Error>)
+            .collect::<Vec<_>>();
+
+        let mut stream = StashStream::new(
+            ChangeId::from_str("1-1-1-1-1").unwrap(),
+            futures::stream::iter(chunks),
+        );
+
+        let mut ids = vec![];
+        while let Some(stash) = stream.next().await {
+            ids.push(stash.unwrap().id);
+        }
+
+        assert_eq!(ids, vec!["1", "2"]);
+        assert_eq!(
+            stream.finish(),
+            Ok(ChangeId::from_str("2-2-2-2-2").unwrap())
+        );
+    }
+}
@@ -0,0 +1,24 @@
+use std::net::SocketAddr;
+
+use stash_api::mock::{MockRiver, MockRiverConfig};
+use trade_common::telemetry::setup_telemetry;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+
+    setup_telemetry("mock-river").expect("Telemetry setup");
+
+    let config = MockRiverConfig::from_env()?;
+    let port = match std::env::var("MOCK_RIVER_PORT") {
+        Ok(port) => port.parse::<u16>()?,
+        Err(_) => 8080,
+    };
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+
+    let river = MockRiver::new(config)?;
+    tracing::info!("mock-river: Listening on {}", addr);
+    river.serve(addr).await?;
+
+    Ok(())
+}
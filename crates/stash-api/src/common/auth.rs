@@ -0,0 +1,284 @@
+//! OAuth access tokens for the client credentials flow.
+//! See https://www.pathofexile.com/developer/docs/authorization
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::poe_api::OAuthResponse;
+
+/// How long before its expiry a token gets replaced by a fresh one.
+const DEFAULT_REFRESH_AHEAD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// The token endpoint could not be reached.
+    Transport(String),
+    /// The token endpoint is temporarily not handing out tokens, ie. `503` or `429`.
+    Unavailable { status: u16 },
+    /// The token endpoint refused to issue a token, ie. due to `invalid_client`.
+    Rejected { status: u16, error: Option<String> },
+    /// The token endpoint responded with something that is not a token.
+    MalformedResponse(String),
+}
+
+impl AuthError {
+    /// Whether requesting a token again might succeed. Everything else requires fixing the
+    /// credentials or configuration.
+    pub fn is_retriable(&self) -> bool {
+        matches!(self, Self::Transport(_) | Self::Unavailable { .. })
+    }
+
+    pub(crate) fn from_status(status: u16, body: &str) -> Self {
+        if status == 429 || status >= 500 {
+            return Self::Unavailable { status };
+        }
+
+        #[derive(serde::Deserialize)]
+        struct ErrorResponse {
+            error: String,
+        }
+
+        Self::Rejected {
+            status,
+            error: serde_json::from_str::<ErrorResponse>(body)
+                .ok()
+                .map(|e| e.error),
+        }
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "Failed requesting OAuth token: {e}"),
+            Self::Unavailable { status } => {
+                write!(f, "OAuth token endpoint unavailable with status {status}")
+            }
+            Self::Rejected {
+                status,
+                error: Some(error),
+            } => write!(f, "OAuth token request rejected with {status}: {error}"),
+            Self::Rejected {
+                status,
+                error: None,
+            } => {
+                write!(f, "OAuth token request rejected with {status}")
+            }
+            Self::MalformedResponse(e) => write!(f, "Malformed OAuth token response: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[derive(Debug, Clone)]
+struct Token {
+    access_token: String,
+    issued_at: Instant,
+    expires_in: Option<Duration>,
+}
+
+impl Token {
+    fn new(response: OAuthResponse, now: Instant) -> Self {
+        Self {
+            access_token: response.access_token,
+            issued_at: now,
+            expires_in: response.expires_in.map(Duration::from_secs),
+        }
+    }
+
+    fn is_fresh(&self, now: Instant, refresh_ahead: Duration) -> bool {
+        match self.expires_in {
+            Some(expires_in) => {
+                // Refreshing a token ahead of its whole lifetime would request one on every call
+                let refresh_ahead = refresh_ahead.min(expires_in / 2);
+                now + refresh_ahead < self.issued_at + expires_in
+            }
+            None => true,
+        }
+    }
+}
+
+/// Hands out access tokens, requesting a new one once the current one is about to expire or
+/// was rejected by the API.
+///
+/// Used by both the sync and async indexer. Tokens without `expires_in` are kept until they
+/// get invalidated. Concurrent requests waiting for a new token share a single token request.
+pub struct TokenProvider {
+    url: String,
+    client_id: String,
+    client_secret: String,
+    refresh_ahead: Duration,
+    token: Mutex<Option<Token>>,
+    /// Held while requesting a new token
+    #[cfg(feature = "async")]
+    refresh: tokio::sync::Mutex<()>,
+}
+
+impl TokenProvider {
+    pub fn new(url: impl Into<String>, client_id: String, client_secret: String) -> Self {
+        Self {
+            url: url.into(),
+            client_id,
+            client_secret,
+            refresh_ahead: DEFAULT_REFRESH_AHEAD,
+            token: Mutex::new(None),
+            #[cfg(feature = "async")]
+            refresh: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Refreshes tokens the given amount of time before they expire, but no earlier than halfway
+    /// through their lifetime. Defaults to 60s.
+    pub fn with_refresh_ahead(mut self, refresh_ahead: Duration) -> Self {
+        self.refresh_ahead = refresh_ahead;
+        self
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// Forgets `access_token`, ie. after the API responded with `401 Unauthorized`, so that the
+    /// next call requests a new one. A token that has been refreshed in the meantime is kept.
+    pub fn invalidate(&self, access_token: &str) {
+        let mut token = self.token.lock().unwrap();
+        if token
+            .as_ref()
+            .is_some_and(|t| t.access_token.eq(access_token))
+        {
+            tracing::info!("Invalidating OAuth token");
+            *token = None;
+        }
+    }
+
+    fn cached(&self, now: Instant) -> Option<String> {
+        self.token
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|t| t.is_fresh(now, self.refresh_ahead))
+            .map(|t| t.access_token.clone())
+    }
+
+    fn store(&self, response: OAuthResponse, now: Instant) -> String {
+        let token = Token::new(response, now);
+        let access_token = token.access_token.clone();
+        *self.token.lock().unwrap() = Some(token);
+        access_token
+    }
+
+    #[cfg(feature = "sync")]
+    /// Returns the current access token, requesting a new one if necessary.
+    pub fn access_token_sync(&self) -> Result<String, AuthError> {
+        if let Some(access_token) = self.cached(Instant::now()) {
+            return Ok(access_token);
+        }
+
+        tracing::info!("Requesting OAuth token");
+        let response =
+            super::poe_api::get_oauth_token_sync(&self.url, &self.client_id, &self.client_secret)?;
+        Ok(self.store(response, Instant::now()))
+    }
+
+    #[cfg(feature = "async")]
+    /// Returns the current access token, requesting a new one if necessary.
+    pub async fn access_token(&self) -> Result<String, AuthError> {
+        if let Some(access_token) = self.cached(Instant::now()) {
+            return Ok(access_token);
+        }
+
+        let _refresh = self.refresh.lock().await;
+        // Another request may have refreshed the token while we were waiting
+        if let Some(access_token) = self.cached(Instant::now()) {
+            return Ok(access_token);
+        }
+
+        tracing::info!("Requesting OAuth token");
+        let response =
+            super::poe_api::get_oauth_token(&self.url, &self.client_id, &self.client_secret)
+                .await?;
+        Ok(self.store(response, Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{AuthError, TokenProvider};
+    use crate::common::poe_api::OAuthResponse;
+
+    fn response(access_token: &str, expires_in: Option<u64>) -> OAuthResponse {
+        OAuthResponse {
+            access_token: access_token.into(),
+            expires_in,
+            token_type: "bearer".into(),
+            scope: "service:psapi".into(),
+        }
+    }
+
+    #[test]
+    fn test_refreshes_ahead_of_expiry() {
+        let provider = TokenProvider::new("", "id".into(), "secret".into())
+            .with_refresh_ahead(Duration::from_secs(10));
+        let now = Instant::now();
+
+        provider.store(response("a", Some(60)), now);
+        assert_eq!(provider.cached(now), Some("a".into()));
+        assert_eq!(
+            provider.cached(now + Duration::from_secs(49)),
+            Some("a".into())
+        );
+        assert_eq!(provider.cached(now + Duration::from_secs(50)), None);
+
+        provider.store(response("b", None), now);
+        assert_eq!(
+            provider.cached(now + Duration::from_secs(3600)),
+            Some("b".into())
+        );
+    }
+
+    #[test]
+    fn test_refreshes_short_lived_tokens_halfway() {
+        let provider = TokenProvider::new("", "id".into(), "secret".into())
+            .with_refresh_ahead(Duration::from_secs(60));
+        let now = Instant::now();
+
+        provider.store(response("a", Some(60)), now);
+        assert_eq!(provider.cached(now), Some("a".into()));
+        assert_eq!(
+            provider.cached(now + Duration::from_secs(29)),
+            Some("a".into())
+        );
+        assert_eq!(provider.cached(now + Duration::from_secs(30)), None);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let provider = TokenProvider::new("", "id".into(), "secret".into());
+        let now = Instant::now();
+        provider.store(response("b", None), now);
+
+        provider.invalidate("a");
+        assert_eq!(provider.cached(now), Some("b".into()));
+        provider.invalidate("b");
+        assert_eq!(provider.cached(now), None);
+    }
+
+    #[test]
+    fn test_error_from_status() {
+        assert_eq!(
+            AuthError::from_status(401, r#"{"error": "invalid_client"}"#),
+            AuthError::Rejected {
+                status: 401,
+                error: Some("invalid_client".into())
+            }
+        );
+        assert!(!AuthError::from_status(400, "").is_retriable());
+        assert!(AuthError::from_status(503, "").is_retriable());
+        assert!(AuthError::from_status(429, "").is_retriable());
+    }
+}
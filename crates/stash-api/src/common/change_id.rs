@@ -1,8 +1,86 @@
-use std::fmt::Display;
+use std::{cmp::Ordering, fmt::Display, hash::Hash};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// How many shards the river is split into, ie. how many offsets a change id consists of.
+pub const SHARD_COUNT: usize = 5;
+
+/// A position in the river, consisting of one offset per shard, ie.
+/// `2000000000-2000000001-2000000002-2000000003-2000000004`.
+///
+/// Offsets only ever grow, so change ids are ordered shard by shard: one change id is ahead of
+/// another if none of its offsets is behind. Change ids where some shards are ahead and others
+/// behind are not comparable.
+#[derive(Debug, Clone)]
 pub struct ChangeId {
     pub(crate) inner: String,
+    shards: [u64; SHARD_COUNT],
+}
+
+impl ChangeId {
+    pub fn from_shards(shards: [u64; SHARD_COUNT]) -> Self {
+        let inner = shards
+            .iter()
+            .map(|shard| shard.to_string())
+            .collect::<Vec<_>>()
+            .join("-");
+        Self { inner, shards }
+    }
+
+    pub fn shards(&self) -> &[u64; SHARD_COUNT] {
+        &self.shards
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+
+    /// How far this change id is ahead of `earlier`, shard by shard. Saturates at the bounds of
+    /// `i64` for offsets that far apart.
+    pub fn delta(&self, earlier: &ChangeId) -> ChangeIdDelta {
+        let mut shards = [0; SHARD_COUNT];
+        for (delta, (shard, earlier)) in shards
+            .iter_mut()
+            .zip(self.shards.iter().zip(earlier.shards.iter()))
+        {
+            let difference = i128::from(*shard) - i128::from(*earlier);
+            *delta = difference.clamp(i64::MIN.into(), i64::MAX.into()) as i64;
+        }
+        ChangeIdDelta { shards }
+    }
+
+    /// Whether any shard moved on since `earlier` without any other shard going back, ie. whether
+    /// a page pointing from `earlier` to this change id contains anything new.
+    pub fn has_progressed_since(&self, earlier: &ChangeId) -> bool {
+        self > earlier
+    }
+}
+
+impl PartialEq for ChangeId {
+    fn eq(&self, other: &Self) -> bool {
+        self.shards == other.shards
+    }
+}
+
+impl Eq for ChangeId {}
+
+impl Hash for ChangeId {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.shards.hash(state);
+    }
+}
+
+impl PartialOrd for ChangeId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let delta = self.delta(other);
+        let is_ahead = delta.shards.iter().any(|shard| *shard > 0);
+        let is_behind = delta.shards.iter().any(|shard| *shard < 0);
+
+        match (is_ahead, is_behind) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Greater),
+            (false, true) => Some(Ordering::Less),
+            (true, true) => None,
+        }
+    }
 }
 
 impl Display for ChangeId {
@@ -12,18 +90,27 @@ impl Display for ChangeId {
 }
 
 impl std::str::FromStr for ChangeId {
-    type Err = Box<dyn std::error::Error>;
+    type Err = crate::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let is_valid = s.split('-').map(|x| x.parse::<u32>()).all(|x| x.is_ok());
-
-        if is_valid {
-            Ok(Self {
-                inner: s.to_owned(),
-            })
-        } else {
-            Err(format!("Failed parsing ChangeId {s}").into())
+        let malformed = || crate::Error::MalformedChangeId(s.to_owned());
+
+        let mut shards = [0; SHARD_COUNT];
+        let mut parts = s.split('-');
+        for shard in shards.iter_mut() {
+            *shard = parts
+                .next()
+                .and_then(|part| part.parse::<u64>().ok())
+                .ok_or_else(malformed)?;
         }
+        if parts.next().is_some() {
+            return Err(malformed());
+        }
+
+        Ok(Self {
+            inner: s.to_owned(),
+            shards,
+        })
     }
 }
 
@@ -33,6 +120,25 @@ impl From<ChangeId> for String {
     }
 }
 
+/// The difference between two change ids, ie. how many updates one is behind the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChangeIdDelta {
+    pub shards: [i64; SHARD_COUNT],
+}
+
+impl ChangeIdDelta {
+    /// The sum over all shards, saturating at the bounds of `i64`.
+    pub fn total(&self) -> i64 {
+        self.shards
+            .iter()
+            .fold(0, |total: i64, shard| total.saturating_add(*shard))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.shards.iter().all(|shard| *shard == 0)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -43,10 +149,15 @@ mod test {
         let change_id = ChangeId::from_str("850662131-863318628-825558626-931433265-890834941");
 
         assert!(change_id.is_ok(),);
+        let change_id = change_id.unwrap();
         assert_eq!(
-            change_id.unwrap().inner,
+            change_id.inner,
             "850662131-863318628-825558626-931433265-890834941"
         );
+        assert_eq!(
+            change_id.shards(),
+            &[850662131, 863318628, 825558626, 931433265, 890834941]
+        );
     }
 
     #[test]
@@ -54,5 +165,51 @@ mod test {
         assert!(
             super::ChangeId::from_str("850662A31-863318628-825558626-931433265-890834941").is_err(),
         );
+        assert!(super::ChangeId::from_str("1-2-3-4").is_err());
+        assert!(super::ChangeId::from_str("1-2-3-4-5-6").is_err());
+    }
+
+    #[test]
+    fn test_ordering() {
+        let id = |s| ChangeId::from_str(s).unwrap();
+
+        assert!(id("1-2-3-4-5") < id("1-2-3-4-6"));
+        assert!(id("2-2-3-4-6") > id("1-2-3-4-5"));
+        assert_eq!(
+            id("1-2-3-4-5").partial_cmp(&id("1-2-3-4-5")),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(id("2-2-3-4-5").partial_cmp(&id("1-3-3-4-5")), None);
+        assert_eq!(id("1-2-3-4-5"), ChangeId::from_shards([1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn test_delta() {
+        let earlier = ChangeId::from_str("10-20-30-40-50").unwrap();
+        let later = ChangeId::from_str("15-20-33-40-49").unwrap();
+
+        let delta = later.delta(&earlier);
+        assert_eq!(delta.shards, [5, 0, 3, 0, -1]);
+        assert_eq!(delta.total(), 7);
+        assert!(earlier.delta(&earlier).is_zero());
+
+        assert!(!later.has_progressed_since(&earlier));
+        assert!(!earlier.has_progressed_since(&earlier));
+        assert!(ChangeId::from_str("15-20-33-40-50")
+            .unwrap()
+            .has_progressed_since(&earlier));
+    }
+
+    #[test]
+    fn test_delta_saturates() {
+        let zero = ChangeId::from_shards([0; SHARD_COUNT]);
+        let max = ChangeId::from_shards([u64::MAX; SHARD_COUNT]);
+
+        assert_eq!(max.delta(&zero).shards, [i64::MAX; SHARD_COUNT]);
+        assert_eq!(max.delta(&zero).total(), i64::MAX);
+        assert_eq!(zero.delta(&max).shards, [i64::MIN; SHARD_COUNT]);
+        assert_eq!(zero.delta(&max).total(), i64::MIN);
+        assert!(max > zero);
+        assert!(zero < max);
     }
 }
@@ -0,0 +1,230 @@
+//! Compression of river pages on the wire.
+//!
+//! Both indexers ask for compressed pages and decode them chunk by chunk themselves instead of
+//! leaving it to the HTTP client, so that the size of a page before and after decoding is known.
+
+use std::{borrow::Cow, io::Write, time::Duration, time::Instant};
+
+use brotli_decompressor::DecompressorWriter;
+use flate2::write::GzDecoder;
+
+use crate::Error;
+
+/// The buffer size of the brotli decoder.
+const BROTLI_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The compression of a response body, as announced by its `Content-Encoding` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentEncoding {
+    #[default]
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl ContentEncoding {
+    /// The `Accept-Encoding` header sent with every request for a river page.
+    pub const ACCEPT: &'static str = "br, gzip";
+
+    pub fn from_header(value: Option<&str>) -> Result<Self, Error> {
+        match value
+            .map(|value| value.trim().to_ascii_lowercase())
+            .as_deref()
+        {
+            None | Some("") | Some("identity") => Ok(Self::Identity),
+            Some("gzip") | Some("x-gzip") => Ok(Self::Gzip),
+            Some("br") => Ok(Self::Brotli),
+            Some(other) => Err(Error::Transport(format!(
+                "Unsupported content encoding {other}"
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for ContentEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Identity => write!(f, "identity"),
+            Self::Gzip => write!(f, "gzip"),
+            Self::Brotli => write!(f, "br"),
+        }
+    }
+}
+
+/// How a single river page was transferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TransferStats {
+    pub content_encoding: ContentEncoding,
+    /// The size of the response body as it was sent over the wire.
+    pub compressed_bytes: u64,
+    /// The size of the response body after decoding, ie. of the JSON.
+    pub decoded_bytes: u64,
+    /// From sending the request until the response body was read completely.
+    pub download_time: Duration,
+}
+
+impl TransferStats {
+    /// How many decoded bytes each byte on the wire amounts to, ie. `1.0` for uncompressed pages.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            return 1.0;
+        }
+        self.decoded_bytes as f64 / self.compressed_bytes as f64
+    }
+}
+
+enum Decoder {
+    Identity,
+    Gzip(Box<GzDecoder<Vec<u8>>>),
+    Brotli(Box<DecompressorWriter<Vec<u8>>>),
+}
+
+/// Decodes a response body chunk by chunk while keeping track of its [`TransferStats`].
+pub(crate) struct BodyDecoder {
+    decoder: Decoder,
+    stats: TransferStats,
+    requested_at: Instant,
+}
+
+impl BodyDecoder {
+    pub(crate) fn new(content_encoding: ContentEncoding, requested_at: Instant) -> Self {
+        let decoder = match content_encoding {
+            ContentEncoding::Identity => Decoder::Identity,
+            ContentEncoding::Gzip => Decoder::Gzip(Box::new(GzDecoder::new(vec![]))),
+            ContentEncoding::Brotli => Decoder::Brotli(Box::new(DecompressorWriter::new(
+                vec![],
+                BROTLI_BUFFER_SIZE,
+            ))),
+        };
+
+        Self {
+            decoder,
+            stats: TransferStats {
+                content_encoding,
+                ..TransferStats::default()
+            },
+            requested_at,
+        }
+    }
+
+    /// Decodes the next chunk of the body. Compressed chunks may not decode to anything yet.
+    pub(crate) fn decode<'a>(&mut self, chunk: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+        self.stats.compressed_bytes += chunk.len() as u64;
+
+        let decoded = match &mut self.decoder {
+            Decoder::Identity => Cow::Borrowed(chunk),
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(chunk).map_err(decode_error)?;
+                Cow::Owned(std::mem::take(decoder.get_mut()))
+            }
+            Decoder::Brotli(decoder) => {
+                decoder.write_all(chunk).map_err(decode_error)?;
+                Cow::Owned(std::mem::take(decoder.get_mut()))
+            }
+        };
+
+        self.stats.decoded_bytes += decoded.len() as u64;
+        Ok(decoded)
+    }
+
+    /// Decodes whatever the decoder still holds back once the body has been read completely,
+    /// failing if the body ended in the middle of the compressed stream.
+    pub(crate) fn finish(&mut self) -> Result<Vec<u8>, Error> {
+        let rest = match &mut self.decoder {
+            Decoder::Identity => vec![],
+            Decoder::Gzip(decoder) => {
+                decoder.try_finish().map_err(decode_error)?;
+                std::mem::take(decoder.get_mut())
+            }
+            Decoder::Brotli(decoder) => {
+                decoder.close().map_err(decode_error)?;
+                std::mem::take(decoder.get_mut())
+            }
+        };
+
+        self.stats.decoded_bytes += rest.len() as u64;
+        self.stats.download_time = self.requested_at.elapsed();
+        Ok(rest)
+    }
+
+    pub(crate) fn stats(&self) -> &TransferStats {
+        &self.stats
+    }
+}
+
+fn decode_error(e: std::io::Error) -> Error {
+    Error::Transport(format!("Failed decoding response body: {e}"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, time::Instant};
+
+    use super::{BodyDecoder, ContentEncoding};
+
+    const PAGE: &[u8] = br#"{"next_change_id": "2-2-2-2-2", "stashes": []}"#;
+
+    fn decode_in_chunks(content_encoding: ContentEncoding, body: &[u8]) -> Vec<u8> {
+        let mut decoder = BodyDecoder::new(content_encoding, Instant::now());
+        let mut decoded = vec![];
+        for chunk in body.chunks(7) {
+            decoded.extend_from_slice(&decoder.decode(chunk).unwrap());
+        }
+        decoded.extend(decoder.finish().unwrap());
+
+        let stats = decoder.stats();
+        assert_eq!(stats.content_encoding, content_encoding);
+        assert_eq!(stats.compressed_bytes, body.len() as u64);
+        assert_eq!(stats.decoded_bytes, decoded.len() as u64);
+        decoded
+    }
+
+    #[test]
+    fn test_from_header() {
+        assert_eq!(
+            ContentEncoding::from_header(None),
+            Ok(ContentEncoding::Identity)
+        );
+        assert_eq!(
+            ContentEncoding::from_header(Some("GZIP")),
+            Ok(ContentEncoding::Gzip)
+        );
+        assert_eq!(
+            ContentEncoding::from_header(Some("br")),
+            Ok(ContentEncoding::Brotli)
+        );
+        assert!(ContentEncoding::from_header(Some("zstd")).is_err());
+    }
+
+    #[test]
+    fn test_decode_gzip() {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(PAGE).unwrap();
+        let body = encoder.finish().unwrap();
+
+        assert_eq!(decode_in_chunks(ContentEncoding::Gzip, &body), PAGE);
+    }
+
+    #[test]
+    fn test_decode_brotli() {
+        let mut body = vec![];
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut body, 4096, 5, 22);
+            encoder.write_all(PAGE).unwrap();
+        }
+
+        assert_eq!(decode_in_chunks(ContentEncoding::Brotli, &body), PAGE);
+        assert_eq!(decode_in_chunks(ContentEncoding::Identity, PAGE), PAGE);
+    }
+
+    #[test]
+    fn test_truncated_body_fails() {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(PAGE).unwrap();
+        let body = encoder.finish().unwrap();
+
+        let mut decoder = BodyDecoder::new(ContentEncoding::Gzip, Instant::now());
+        decoder.decode(&body[..body.len() / 2]).unwrap();
+        assert!(decoder.finish().is_err());
+    }
+}
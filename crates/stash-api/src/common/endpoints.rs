@@ -0,0 +1,115 @@
+use super::ChangeId;
+
+const PUBLIC_STASH_TABS_URL: &str = "https://api.pathofexile.com/public-stash-tabs";
+const OAUTH_TOKEN_URL: &str = "https://www.pathofexile.com/oauth/token";
+const POE_NINJA_STATS_URL: &str = "https://poe.ninja/api/Data/GetStats";
+
+/// The set of remote endpoints the indexers talk to.
+///
+/// Defaults to the official Path of Exile API and poe.ninja, but every endpoint can be
+/// overridden, ie. to run against a local stand-in server that replays recorded river pages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoints {
+    pub(crate) public_stash_tabs: String,
+    pub(crate) oauth_token: String,
+    pub(crate) poe_ninja_stats: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            public_stash_tabs: PUBLIC_STASH_TABS_URL.into(),
+            oauth_token: OAUTH_TOKEN_URL.into(),
+            poe_ninja_stats: POE_NINJA_STATS_URL.into(),
+        }
+    }
+}
+
+impl Endpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Points all endpoints at a single server, using the same paths as the official APIs:
+    /// `{base_url}/public-stash-tabs`, `{base_url}/oauth/token` and `{base_url}/api/Data/GetStats`.
+    pub fn with_base_url(self, base_url: &str) -> Self {
+        let base_url = base_url.trim_end_matches('/');
+
+        Self {
+            public_stash_tabs: format!("{base_url}/public-stash-tabs"),
+            oauth_token: format!("{base_url}/oauth/token"),
+            poe_ninja_stats: format!("{base_url}/api/Data/GetStats"),
+        }
+    }
+
+    pub fn with_public_stash_tabs(mut self, url: impl Into<String>) -> Self {
+        self.public_stash_tabs = url.into();
+        self
+    }
+
+    pub fn with_oauth_token(mut self, url: impl Into<String>) -> Self {
+        self.oauth_token = url.into();
+        self
+    }
+
+    pub fn with_poe_ninja_stats(mut self, url: impl Into<String>) -> Self {
+        self.poe_ninja_stats = url.into();
+        self
+    }
+
+    /// The URL to request the river page for `change_id` from.
+    pub fn public_stash_tabs_url(&self, change_id: &ChangeId) -> String {
+        format!("{}?id={}", self.public_stash_tabs, change_id)
+    }
+
+    pub fn oauth_token(&self) -> &str {
+        &self.oauth_token
+    }
+
+    pub fn poe_ninja_stats(&self) -> &str {
+        &self.poe_ninja_stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::Endpoints;
+    use crate::common::ChangeId;
+
+    #[test]
+    fn test_default_endpoints() {
+        let endpoints = Endpoints::default();
+        let change_id =
+            ChangeId::from_str("850662131-863318628-825558626-931433265-890834941").unwrap();
+
+        assert_eq!(
+            endpoints.public_stash_tabs_url(&change_id),
+            "https://api.pathofexile.com/public-stash-tabs?id=850662131-863318628-825558626-931433265-890834941"
+        );
+        assert_eq!(
+            endpoints.oauth_token(),
+            "https://www.pathofexile.com/oauth/token"
+        );
+        assert_eq!(
+            endpoints.poe_ninja_stats(),
+            "https://poe.ninja/api/Data/GetStats"
+        );
+    }
+
+    #[test]
+    fn test_with_base_url() {
+        let endpoints = Endpoints::new().with_base_url("http://localhost:8080/");
+
+        assert_eq!(
+            endpoints.public_stash_tabs,
+            "http://localhost:8080/public-stash-tabs"
+        );
+        assert_eq!(endpoints.oauth_token(), "http://localhost:8080/oauth/token");
+        assert_eq!(
+            endpoints.poe_ninja_stats(),
+            "http://localhost:8080/api/Data/GetStats"
+        );
+    }
+}
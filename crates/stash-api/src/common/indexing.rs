@@ -0,0 +1,259 @@
+//! The transport-agnostic part of the indexer.
+//!
+//! The sync and async indexers are thin drivers around [`IndexerCore`]: they only send requests
+//! and read response bodies, while the core decides how requests look, how responses, rate limits
+//! and tokens are handled and how to recover from errors.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::{
+    auth::TokenProvider,
+    encoding::{ContentEncoding, TransferStats},
+    poe_api::user_agent,
+    rate_limit::{RateLimitHeaders, RateLimiter},
+    retry::{ErrorClass, RetryPolicy},
+    ChangeId, Endpoints, StashTabResponse,
+};
+use crate::Error;
+
+/// How long to wait before requesting a page again that did not contain any stashes yet, ie.
+/// because we reached the head of the river.
+pub(crate) const EMPTY_PAGE_DELAY: Duration = Duration::from_secs(4);
+
+/// What a running indexer hands to its consumer. Shared by the sync and async indexer.
+#[derive(Debug, Clone)]
+pub enum IndexerEvent {
+    Tick {
+        response: StashTabResponse,
+        change_id: ChangeId,
+        previous_change_id: ChangeId,
+        created_at: std::time::SystemTime,
+        /// How the page was transferred, ie. to tell the bandwidth spent on indexing.
+        transfer: TransferStats,
+    },
+    RateLimited(Duration),
+    /// Something went wrong. The indexer keeps going unless `Stop` follows.
+    Error(Error),
+    /// The page for `change_id` still failed after `attempts` attempts and the indexer gives up,
+    /// see [`RetryPolicy`]. `Stop` follows, and starting again at `change_id` continues without
+    /// skipping any pages.
+    RetriesExhausted {
+        change_id: ChangeId,
+        attempts: u32,
+    },
+    /// The indexer stopped, either as requested or due to an error it cannot recover from.
+    Stop,
+}
+
+/// Where the indexer continues after the page that is currently being read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Prefetch {
+    /// Not enough of the page has been read yet.
+    Pending,
+    /// The page contains stashes and is followed by the page for the given change id.
+    Next(ChangeId),
+    /// The page does not contain any stashes or does not point past itself, and has to be
+    /// requested again.
+    Empty,
+}
+
+/// What a driver does after a request for a page failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Recovery {
+    /// Requests the page again after the given delay.
+    Retry(Duration),
+    /// The retry budget for the page is used up after the given amount of attempts.
+    Exhausted(u32),
+    Stop,
+}
+
+/// Everything the drivers share about a running indexer.
+pub(crate) struct IndexerCore {
+    endpoints: Endpoints,
+    token_provider: TokenProvider,
+    rate_limiter: Mutex<RateLimiter>,
+    retry_policy: RetryPolicy,
+}
+
+impl IndexerCore {
+    pub(crate) fn new(endpoints: Endpoints, client_id: String, client_secret: String) -> Self {
+        Self {
+            token_provider: TokenProvider::new(endpoints.oauth_token(), client_id, client_secret),
+            endpoints,
+            rate_limiter: Mutex::new(RateLimiter::new()),
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    pub(crate) fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub(crate) fn token_provider(&self) -> &TokenProvider {
+        &self.token_provider
+    }
+
+    /// Reserves a request within the rate limits, or returns how long to wait before trying again.
+    pub(crate) fn try_acquire(&self) -> Result<(), Duration> {
+        self.rate_limiter
+            .lock()
+            .unwrap()
+            .try_acquire(Instant::now())
+    }
+
+    pub(crate) fn url(&self, change_id: &ChangeId) -> String {
+        self.endpoints.public_stash_tabs_url(change_id)
+    }
+
+    pub(crate) fn headers(&self, access_token: &str) -> [(&'static str, String); 4] {
+        [
+            ("Accept", "application/json".to_string()),
+            ("Accept-Encoding", ContentEncoding::ACCEPT.to_string()),
+            ("User-Agent", user_agent(self.token_provider.client_id())),
+            ("Authorization", format!("Bearer {access_token}")),
+        ]
+    }
+
+    /// Updates the rate limits from the response headers and fails unless the page can be read.
+    /// Returns how the body of the page is encoded.
+    pub(crate) fn on_response<'a>(
+        &self,
+        change_id: &ChangeId,
+        access_token: &str,
+        status: u16,
+        header: impl Fn(&str) -> Option<&'a str>,
+    ) -> Result<ContentEncoding, Error> {
+        let headers = RateLimitHeaders::parse(&header);
+        {
+            let mut rate_limiter = self.rate_limiter.lock().unwrap();
+            if status == 429 {
+                rate_limiter.on_rate_limited(&headers, Instant::now());
+                return Err(Error::RateLimited(rate_limiter.wait_time(Instant::now())));
+            }
+            rate_limiter.update(&headers, Instant::now());
+        }
+
+        if status == 401 {
+            tracing::info!("Requesting a new OAuth token due to 401 Unauthorized");
+            self.token_provider.invalidate(access_token);
+        }
+
+        if status != 200 {
+            return Err(Error::HttpStatus {
+                status,
+                change_id: Some(change_id.clone()),
+            });
+        }
+
+        ContentEncoding::from_header(header("content-encoding"))
+    }
+
+    /// Decides how to go on after an attempt at a page failed. `failed_attempts` counts the
+    /// failed attempts at the page so far and is increased unless `error` does not count against
+    /// the retry budget.
+    pub(crate) fn recovery(&self, error: &Error, failed_attempts: &mut u32) -> Recovery {
+        if !error.is_retriable() {
+            return Recovery::Stop;
+        }
+
+        // The rate limiter holds back the retry until the restriction is lifted
+        let class = match ErrorClass::of(error) {
+            Some(class) => class,
+            None => return Recovery::Retry(Duration::ZERO),
+        };
+
+        *failed_attempts += 1;
+        match self.retry_policy.retry_delay(class, *failed_attempts) {
+            Some(delay) => Recovery::Retry(delay),
+            None => Recovery::Exhausted(*failed_attempts),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{str::FromStr, time::Duration};
+
+    use super::{IndexerCore, Recovery};
+    use crate::{
+        common::{
+            encoding::ContentEncoding,
+            retry::{Backoff, RetryPolicy},
+            ChangeId, Endpoints,
+        },
+        Error,
+    };
+
+    fn core() -> IndexerCore {
+        IndexerCore::new(
+            Endpoints::new().with_base_url("http://localhost"),
+            "client".into(),
+            "secret".into(),
+        )
+    }
+
+    #[test]
+    fn test_on_response() {
+        let core = core();
+        let change_id = ChangeId::from_str("1-1-1-1-1").unwrap();
+        let headers = |name: &str| match name {
+            "x-rate-limit-rules" => Some("ip"),
+            "x-rate-limit-ip" => Some("1:60:60"),
+            "x-rate-limit-ip-state" => Some("1:60:0"),
+            _ => None,
+        };
+
+        assert_eq!(
+            core.on_response(&change_id, "token", 200, headers),
+            Ok(ContentEncoding::Identity)
+        );
+        assert!(core.try_acquire().is_err());
+        assert!(matches!(
+            core.on_response(&change_id, "token", 429, |_| None),
+            Err(Error::RateLimited(_))
+        ));
+        assert_eq!(
+            core.on_response(&change_id, "token", 503, |_| None),
+            Err(Error::HttpStatus {
+                status: 503,
+                change_id: Some(change_id)
+            })
+        );
+    }
+
+    #[test]
+    fn test_recovery() {
+        let core = core().with_retry_policy(RetryPolicy::uniform(Backoff::new(
+            2,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        )));
+        let status = |status| Error::HttpStatus {
+            status,
+            change_id: None,
+        };
+        let mut failed_attempts = 0;
+
+        assert_eq!(
+            core.recovery(&status(403), &mut failed_attempts),
+            Recovery::Stop
+        );
+        assert_eq!(
+            core.recovery(&Error::RateLimited(Duration::ZERO), &mut failed_attempts),
+            Recovery::Retry(Duration::ZERO)
+        );
+        assert_eq!(failed_attempts, 0);
+        assert_eq!(
+            core.recovery(&status(503), &mut failed_attempts),
+            Recovery::Retry(Duration::from_secs(1))
+        );
+        assert_eq!(
+            core.recovery(&Error::Transport("reset".into()), &mut failed_attempts),
+            Recovery::Exhausted(2)
+        );
+    }
+}
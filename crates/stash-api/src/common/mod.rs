@@ -1,8 +1,19 @@
+pub mod auth;
 mod change_id;
+pub mod encoding;
+mod endpoints;
+pub mod indexing;
 pub mod parse;
 pub mod poe_api;
 pub mod poe_ninja_client;
+pub mod rate_limit;
+mod record;
+pub mod reorder;
+pub mod retry;
 mod stash;
+pub mod stream;
 
-pub use change_id::ChangeId;
-pub use stash::{Item, ItemExtendedProp, Stash, StashTabResponse};
+pub use change_id::{ChangeId, ChangeIdDelta, SHARD_COUNT};
+pub use endpoints::Endpoints;
+pub use record::{StashRecord, STASH_RECORD_VERSION};
+pub use stash::{Item, ItemExtendedProp, ItemProperty, ItemSocket, Stash, StashTabResponse};
@@ -1,17 +1,24 @@
-use std::string::FromUtf8Error;
-
 use super::ChangeId;
+use crate::Error;
 use std::str::FromStr;
 
-pub fn parse_change_id_from_bytes(bytes: &[u8]) -> Result<ChangeId, FromUtf8Error> {
-    String::from_utf8(bytes.split(|b| b.eq(&b'"')).nth(3).unwrap().to_vec())
-        .map(|s| ChangeId::from_str(&s).expect("Valid ChangeId"))
+/// Extracts the `next_change_id` from the first bytes of a river page.
+pub fn parse_change_id_from_bytes(bytes: &[u8]) -> Result<ChangeId, Error> {
+    let malformed = || Error::MalformedChangeId(String::from_utf8_lossy(bytes).into_owned());
+
+    let next_change_id = bytes.split(|b| b.eq(&b'"')).nth(3).ok_or_else(malformed)?;
+    let next_change_id = std::str::from_utf8(next_change_id).map_err(|_| malformed())?;
+
+    ChangeId::from_str(next_change_id)
 }
 
 #[cfg(test)]
 mod test {
 
-    use crate::common::{parse::parse_change_id_from_bytes, ChangeId};
+    use crate::{
+        common::{parse::parse_change_id_from_bytes, ChangeId},
+        Error,
+    };
 
     #[test]
     fn test_parse_change_id_from_bytes() {
@@ -19,9 +26,21 @@ mod test {
         let result = parse_change_id_from_bytes(input);
         assert_eq!(
             result,
-            Ok(ChangeId {
-                inner: "1882903321-1878868410-1818903289-2014357625-1957236232".into()
-            })
+            Ok(ChangeId::from_shards([
+                1882903321, 1878868410, 1818903289, 2014357625, 1957236232
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_change_id_from_bytes() {
+        assert_eq!(
+            parse_change_id_from_bytes(b"{\"next_change_id\": "),
+            Err(Error::MalformedChangeId("{\"next_change_id\": ".into()))
+        );
+        assert_eq!(
+            parse_change_id_from_bytes(b"{\"next_change_id\": \"abc-def\", \"stashes\": []}"),
+            Err(Error::MalformedChangeId("abc-def".into()))
         );
     }
 }
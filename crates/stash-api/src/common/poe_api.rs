@@ -1,7 +1,7 @@
-use std::time::Duration;
-
 use serde::{Deserialize, Serialize};
 
+use super::auth::AuthError;
+
 pub fn user_agent(client_id: &str) -> String {
     format!("OAuth {client_id}/0.1 (contact: mxmlnstock@gmail.com)")
 }
@@ -9,6 +9,13 @@ pub fn user_agent(client_id: &str) -> String {
 #[derive(Debug, Deserialize)]
 pub struct OAuthResponse {
     pub access_token: String,
+    /// Seconds until the token expires. Not set for tokens that do not expire.
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    #[serde(default)]
+    pub token_type: String,
+    #[serde(default)]
+    pub scope: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -33,10 +40,10 @@ impl OAuthRequestPayload {
 #[cfg(feature = "sync")]
 /// According to https://www.pathofexile.com/developer/docs/authorization
 pub fn get_oauth_token_sync(
+    url: &str,
     client_id: &str,
     client_secret: &str,
-) -> Result<OAuthResponse, Box<dyn std::error::Error>> {
-    let url = "https://www.pathofexile.com/oauth/token";
+) -> Result<OAuthResponse, AuthError> {
     let payload = serde_urlencoded::to_string(OAuthRequestPayload::new(
         client_id.into(),
         client_secret.into(),
@@ -45,20 +52,29 @@ pub fn get_oauth_token_sync(
     let response = ureq::post(url)
         .set("Content-Type", "application/x-www-form-urlencoded")
         .set("User-Agent", user_agent(client_id).as_str())
-        .send(payload.as_bytes())?;
+        .send(payload.as_bytes())
+        .map_err(|e| match e {
+            ureq::Error::Status(status, response) => {
+                AuthError::from_status(status, &response.into_string().unwrap_or_default())
+            }
+            ureq::Error::Transport(e) => AuthError::Transport(e.to_string()),
+        })?;
 
-    serde_json::from_str(&response.into_string()?).map_err(|e| e.into())
+    let body = response
+        .into_string()
+        .map_err(|e| AuthError::Transport(e.to_string()))?;
+    serde_json::from_str(&body).map_err(|e| AuthError::MalformedResponse(e.to_string()))
 }
 
 #[cfg(feature = "async")]
 /// According to https://www.pathofexile.com/developer/docs/authorization
 pub async fn get_oauth_token(
+    url: &str,
     client_id: &str,
     client_secret: &str,
-) -> Result<OAuthResponse, Box<dyn std::error::Error>> {
+) -> Result<OAuthResponse, AuthError> {
     use trade_common::telemetry::generate_http_client;
 
-    let url = "https://www.pathofexile.com/oauth/token";
     let payload = serde_urlencoded::to_string(OAuthRequestPayload::new(
         client_id.into(),
         client_secret.into(),
@@ -71,23 +87,17 @@ pub async fn get_oauth_token(
         .header("User-Agent", user_agent(client_id).as_str())
         .body(payload)
         .send()
-        .await?;
+        .await
+        .map_err(|e| AuthError::Transport(e.to_string()))?;
 
-    serde_json::from_slice(&response.bytes().await?).map_err(|e| e.into())
-}
-
-const DEFAULT_RATE_LIMIT_TIMER: u64 = 60;
-
-pub fn parse_rate_limit_timer(input: Option<&str>) -> Duration {
-    let seconds = input
-        .and_then(|v| v.split(':').last())
-        .map(|s| {
-            if s.ne("60") {
-                tracing::warn!("Expected x-rate-limit-ip to be 60 seconds");
-            }
-            s.parse().unwrap_or(DEFAULT_RATE_LIMIT_TIMER)
-        })
-        .unwrap_or(DEFAULT_RATE_LIMIT_TIMER);
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| AuthError::Transport(e.to_string()))?;
+    if !status.is_success() {
+        return Err(AuthError::from_status(status.as_u16(), &body));
+    }
 
-    Duration::from_secs(seconds)
+    serde_json::from_str(&body).map_err(|e| AuthError::MalformedResponse(e.to_string()))
 }
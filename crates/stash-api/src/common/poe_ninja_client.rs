@@ -1,4 +1,7 @@
-use crate::common::ChangeId;
+use crate::{
+    common::{ChangeId, Endpoints},
+    Error,
+};
 use serde::Deserialize;
 use std::str::FromStr;
 
@@ -7,27 +10,61 @@ struct PoeNinjaGetStats {
     next_change_id: String,
 }
 
-#[derive(Debug)]
-pub struct PoeNinjaClient;
+#[derive(Debug, Clone)]
+pub struct PoeNinjaClient {
+    url: String,
+}
+
+impl Default for PoeNinjaClient {
+    fn default() -> Self {
+        Self::new(Endpoints::default().poe_ninja_stats())
+    }
+}
 
 impl PoeNinjaClient {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self { url: url.into() }
+    }
+
     #[cfg(feature = "sync")]
-    pub fn fetch_latest_change_id() -> Result<ChangeId, Box<dyn std::error::Error>> {
-        ureq::get("https://poe.ninja/api/Data/GetStats")
+    pub fn fetch_latest_change_id(&self) -> Result<ChangeId, Error> {
+        ureq::get(&self.url)
             .call()
-            .map_err(|e| e.into())
-            .and_then(|res| res.into_string().map_err(|e| e.into()))
+            .map_err(|e| match e {
+                ureq::Error::Status(status, _) => Error::HttpStatus {
+                    status,
+                    change_id: None,
+                },
+                ureq::Error::Transport(e) => Error::Transport(e.to_string()),
+            })
+            .and_then(|res| {
+                res.into_string()
+                    .map_err(|e| Error::Transport(e.to_string()))
+            })
             .and_then(|s| {
-                serde_json::from_str::<PoeNinjaGetStats>(s.as_str()).map_err(|e| e.into())
+                serde_json::from_str::<PoeNinjaGetStats>(s.as_str())
+                    .map_err(|e| Error::Transport(e.to_string()))
             })
             .and_then(|x| ChangeId::from_str(&x.next_change_id))
     }
 
     #[cfg(feature = "async")]
     #[allow(dead_code)]
-    pub async fn fetch_latest_change_id_async() -> Result<ChangeId, Box<dyn std::error::Error>> {
-        let response = reqwest::get("https://poe.ninja/api/Data/GetStats").await?;
-        let str = response.json::<PoeNinjaGetStats>().await?;
+    pub async fn fetch_latest_change_id_async(&self) -> Result<ChangeId, Error> {
+        let response = reqwest::get(&self.url)
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| match e.status() {
+                Some(status) => Error::HttpStatus {
+                    status: status.as_u16(),
+                    change_id: None,
+                },
+                None => Error::Transport(e.to_string()),
+            })?;
+        let str = response
+            .json::<PoeNinjaGetStats>()
+            .await
+            .map_err(|e| Error::Transport(e.to_string()))?;
         ChangeId::from_str(&str.next_change_id)
     }
 }
@@ -37,7 +74,7 @@ mod test {
     #[test]
     #[cfg(feature = "sync")]
     fn test_fetch_latest_change_id_sync() {
-        let latest_change_id = super::PoeNinjaClient::fetch_latest_change_id();
+        let latest_change_id = super::PoeNinjaClient::default().fetch_latest_change_id();
         assert!(latest_change_id.is_ok());
         assert!(latest_change_id.unwrap().inner.len() > 50);
     }
@@ -45,7 +82,9 @@ mod test {
     #[tokio::test]
     #[cfg(feature = "async")]
     async fn test_fetch_latest_change_id_async() {
-        let latest_change_id = super::PoeNinjaClient::fetch_latest_change_id_async().await;
+        let latest_change_id = super::PoeNinjaClient::default()
+            .fetch_latest_change_id_async()
+            .await;
         assert!(latest_change_id.is_ok());
         assert!(latest_change_id.unwrap().inner.len() > 50);
     }
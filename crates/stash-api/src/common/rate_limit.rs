@@ -0,0 +1,447 @@
+//! Parsing and tracking of GGG's rate limit headers.
+//!
+//! Every response carries an `x-rate-limit-policy` and a list of `x-rate-limit-rules`, ie. `ip,client`.
+//! For each rule there is an `x-rate-limit-{rule}` header describing its windows as
+//! `max_hits:period:penalty` and an `x-rate-limit-{rule}-state` header with the current state of
+//! each window as `hits:period:restricted_for`, all in seconds.
+//! See https://www.pathofexile.com/developer/docs#ratelimits
+
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// The restriction we assume if the server rate limits us without telling us for how long.
+const DEFAULT_RESTRICTION: Duration = Duration::from_secs(60);
+
+/// The rate limit headers of a single response.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimitHeaders {
+    pub policy: Option<String>,
+    pub rules: Vec<RateLimitRule>,
+    pub retry_after: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitRule {
+    pub name: String,
+    pub windows: Vec<RateLimitWindow>,
+    pub states: Vec<RateLimitWindowState>,
+}
+
+/// A window allowing `max_hits` requests per `period`, restricting further requests for `penalty`
+/// once it is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitWindow {
+    pub max_hits: u32,
+    pub period: Duration,
+    pub penalty: Duration,
+}
+
+/// The server's view on a `RateLimitWindow` with the same `period`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitWindowState {
+    pub hits: u32,
+    pub period: Duration,
+    pub restricted_for: Duration,
+}
+
+fn parse_triple(s: &str) -> Result<(u64, u64, u64), String> {
+    let parts = s
+        .trim()
+        .split(':')
+        .map(|p| p.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed parsing rate limit window {s}: {e}"))?;
+
+    match parts.as_slice() {
+        [a, b, c] => Ok((*a, *b, *c)),
+        _ => Err(format!("Expected three fields in rate limit window {s}")),
+    }
+}
+
+impl FromStr for RateLimitWindow {
+    type Err = String;
+
+    /// Parses `max_hits:period:penalty`, ie. `45:60:60`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (max_hits, period, penalty) = parse_triple(s)?;
+
+        Ok(Self {
+            max_hits: max_hits as u32,
+            period: Duration::from_secs(period),
+            penalty: Duration::from_secs(penalty),
+        })
+    }
+}
+
+impl FromStr for RateLimitWindowState {
+    type Err = String;
+
+    /// Parses `hits:period:restricted_for`, ie. `1:60:0`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hits, period, restricted_for) = parse_triple(s)?;
+
+        Ok(Self {
+            hits: hits as u32,
+            period: Duration::from_secs(period),
+            restricted_for: Duration::from_secs(restricted_for),
+        })
+    }
+}
+
+fn parse_list<T: FromStr<Err = String>>(value: Option<&str>) -> Vec<T> {
+    value
+        .map(|v| {
+            v.split(',')
+                .filter_map(|w| match w.parse() {
+                    Ok(window) => Some(window),
+                    Err(e) => {
+                        tracing::warn!("{}", e);
+                        None
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl RateLimitHeaders {
+    /// Parses all rate limit related headers, looked up by their lowercase name via `header`.
+    pub fn parse<'a>(header: impl Fn(&str) -> Option<&'a str>) -> Self {
+        let rules = header("x-rate-limit-rules")
+            .map(|rules| {
+                rules
+                    .split(',')
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| !name.is_empty())
+                    .map(|name| RateLimitRule {
+                        windows: parse_list(header(&format!("x-rate-limit-{name}"))),
+                        states: parse_list(header(&format!("x-rate-limit-{name}-state"))),
+                        name,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            policy: header("x-rate-limit-policy").map(str::to_owned),
+            rules,
+            retry_after: header("retry-after")
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .map(Duration::from_secs),
+        }
+    }
+
+    /// How long the server restricts us from sending further requests, if at all.
+    pub fn restriction(&self) -> Option<Duration> {
+        self.rules
+            .iter()
+            .flat_map(|r| r.states.iter())
+            .map(|s| s.restricted_for)
+            .chain(self.retry_after)
+            .filter(|d| !d.is_zero())
+            .max()
+    }
+}
+
+#[derive(Debug)]
+struct TrackedWindow {
+    rule: String,
+    window: RateLimitWindow,
+    hits: VecDeque<Instant>,
+}
+
+impl TrackedWindow {
+    fn prune(&mut self, now: Instant) {
+        while self
+            .hits
+            .front()
+            .is_some_and(|hit| now.duration_since(*hit) >= self.window.period)
+        {
+            self.hits.pop_front();
+        }
+    }
+
+    /// How long until another request fits into this window.
+    fn wait_time(&self, now: Instant) -> Duration {
+        if self.window.max_hits == 0 {
+            return self.window.period;
+        }
+
+        let max_hits = self.window.max_hits as usize;
+        if self.hits.len() < max_hits {
+            return Duration::ZERO;
+        }
+
+        // The request that has to leave the window before we fit in again
+        let blocking = self.hits[self.hits.len() - max_hits];
+        (blocking + self.window.period).saturating_duration_since(now)
+    }
+
+    /// The minimum distance between two requests to spread them evenly across the window.
+    fn pacing_interval(&self) -> Duration {
+        self.window.period / self.window.max_hits.max(1)
+    }
+}
+
+/// Tracks every window of every rule the server told us about and paces requests so that none
+/// of them is exceeded.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    windows: Vec<TrackedWindow>,
+    last_request: Option<Instant>,
+    restricted_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a request at `now` if it does not violate any known window, otherwise returns
+    /// how long to wait before trying again.
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        let wait_time = self.wait_time(now);
+
+        if wait_time.is_zero() {
+            self.last_request = Some(now);
+            for w in self.windows.iter_mut() {
+                w.hits.push_back(now);
+            }
+            Ok(())
+        } else {
+            Err(wait_time)
+        }
+    }
+
+    /// How long to wait at `now` before the next request can be sent.
+    pub fn wait_time(&mut self, now: Instant) -> Duration {
+        let restriction = self
+            .restricted_until
+            .map(|until| until.saturating_duration_since(now))
+            .unwrap_or_default();
+
+        let pacing = match self.last_request {
+            Some(last) => self
+                .windows
+                .iter()
+                .map(|w| (last + w.pacing_interval()).saturating_duration_since(now))
+                .max()
+                .unwrap_or_default(),
+            None => Duration::ZERO,
+        };
+
+        self.windows
+            .iter_mut()
+            .map(|w| {
+                w.prune(now);
+                w.wait_time(now)
+            })
+            .chain([restriction, pacing])
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Applies the rate limit headers of a response received at `now`.
+    ///
+    /// The windows of every rule the server announces are replaced by the announced ones and our
+    /// local hit count is raised to the server's count, ie. to account for requests made by other
+    /// clients sharing our limits. Windows of rules missing from the headers are kept, as
+    /// responses of proxies or failing servers do not carry any.
+    pub fn update(&mut self, headers: &RateLimitHeaders, now: Instant) {
+        let (mut previous, kept) = std::mem::take(&mut self.windows)
+            .into_iter()
+            .partition(|w| headers.rules.iter().any(|rule| rule.name == w.rule));
+        self.windows = kept;
+
+        for rule in &headers.rules {
+            for window in &rule.windows {
+                let mut tracked = previous
+                    .iter()
+                    .position(|w| w.rule.eq(&rule.name) && w.window.period == window.period)
+                    .map(|idx| previous.swap_remove(idx))
+                    .unwrap_or_else(|| TrackedWindow {
+                        rule: rule.name.clone(),
+                        window: *window,
+                        hits: VecDeque::new(),
+                    });
+                tracked.window = *window;
+                tracked.prune(now);
+
+                if let Some(state) = rule.states.iter().find(|s| s.period == window.period) {
+                    let hits = tracked.hits.len().max(state.hits as usize);
+                    tracked.hits.resize(hits, now);
+                }
+
+                self.windows.push(tracked);
+            }
+        }
+
+        if let Some(restriction) = headers.restriction() {
+            tracing::warn!(
+                "Rate limit restriction of {}s for policy {:?}",
+                restriction.as_secs(),
+                headers.policy
+            );
+            self.restrict(now + restriction);
+        }
+    }
+
+    /// Marks us as rate limited by the server, ie. after receiving a `429 Too Many Requests`.
+    pub fn on_rate_limited(&mut self, headers: &RateLimitHeaders, now: Instant) {
+        self.update(headers, now);
+
+        if headers.restriction().is_none() {
+            self.restrict(now + DEFAULT_RESTRICTION);
+        }
+    }
+
+    fn restrict(&mut self, until: Instant) {
+        self.restricted_until = self.restricted_until.max(Some(until));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        RateLimitHeaders, RateLimitRule, RateLimitWindow, RateLimitWindowState, RateLimiter,
+    };
+
+    fn headers<'a>(pairs: &'a [(&'a str, &'a str)]) -> RateLimitHeaders {
+        RateLimitHeaders::parse(|name| pairs.iter().find(|(k, _)| k.eq(&name)).map(|(_, v)| *v))
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        let parsed = headers(&[
+            ("x-rate-limit-policy", "public-stash-tabs-request-limit"),
+            ("x-rate-limit-rules", "Ip,Client"),
+            ("x-rate-limit-ip", "45:60:60,240:240:900"),
+            ("x-rate-limit-ip-state", "1:60:0,1:240:0"),
+            ("x-rate-limit-client", "7:10:60"),
+            ("x-rate-limit-client-state", "8:10:60"),
+            ("retry-after", "60"),
+        ]);
+
+        assert_eq!(
+            parsed.policy.as_deref(),
+            Some("public-stash-tabs-request-limit")
+        );
+        assert_eq!(parsed.rules.len(), 2);
+        assert_eq!(
+            parsed.rules[0],
+            RateLimitRule {
+                name: "ip".into(),
+                windows: vec![
+                    RateLimitWindow {
+                        max_hits: 45,
+                        period: Duration::from_secs(60),
+                        penalty: Duration::from_secs(60),
+                    },
+                    RateLimitWindow {
+                        max_hits: 240,
+                        period: Duration::from_secs(240),
+                        penalty: Duration::from_secs(900),
+                    },
+                ],
+                states: vec![
+                    RateLimitWindowState {
+                        hits: 1,
+                        period: Duration::from_secs(60),
+                        restricted_for: Duration::ZERO,
+                    },
+                    RateLimitWindowState {
+                        hits: 1,
+                        period: Duration::from_secs(240),
+                        restricted_for: Duration::ZERO,
+                    },
+                ],
+            }
+        );
+        assert_eq!(parsed.retry_after, Some(Duration::from_secs(60)));
+        assert_eq!(parsed.restriction(), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_parse_malformed_headers() {
+        assert_eq!(headers(&[]), RateLimitHeaders::default());
+
+        let parsed = headers(&[
+            ("x-rate-limit-rules", "ip"),
+            ("x-rate-limit-ip", "something,_:_:abc,45:60:60"),
+        ]);
+        assert_eq!(parsed.rules[0].windows.len(), 1);
+        assert_eq!(parsed.restriction(), None);
+
+        assert!("45:60".parse::<RateLimitWindow>().is_err());
+        assert!("a:b:c".parse::<RateLimitWindow>().is_err());
+        assert!("1:60:0:0".parse::<RateLimitWindowState>().is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_respects_windows() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new();
+        limiter.update(
+            &headers(&[
+                ("x-rate-limit-rules", "ip"),
+                ("x-rate-limit-ip", "2:10:60"),
+                ("x-rate-limit-ip-state", "1:10:0"),
+            ]),
+            now,
+        );
+
+        // One hit is already accounted for by the server and requests are paced 5s apart
+        assert_eq!(limiter.try_acquire(now), Ok(()));
+        assert_eq!(
+            limiter.try_acquire(now + Duration::from_secs(5)),
+            Err(Duration::from_secs(5))
+        );
+        assert_eq!(limiter.try_acquire(now + Duration::from_secs(10)), Ok(()));
+    }
+
+    #[test]
+    fn test_rate_limiter_respects_restrictions() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new();
+        limiter.on_rate_limited(&headers(&[("retry-after", "120")]), now);
+        assert_eq!(limiter.wait_time(now), Duration::from_secs(120));
+
+        let mut limiter = RateLimiter::new();
+        limiter.on_rate_limited(&headers(&[]), now);
+        assert_eq!(limiter.wait_time(now), Duration::from_secs(60));
+        assert_eq!(limiter.try_acquire(now + Duration::from_secs(60)), Ok(()));
+    }
+
+    #[test]
+    fn test_rate_limiter_keeps_windows_missing_from_headers() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new();
+        limiter.update(
+            &headers(&[
+                ("x-rate-limit-rules", "ip"),
+                ("x-rate-limit-ip", "1:10:60"),
+                ("x-rate-limit-ip-state", "1:10:0"),
+            ]),
+            now,
+        );
+
+        // ie. a 502 of a proxy in front of the API
+        limiter.update(&headers(&[]), now);
+        assert_eq!(limiter.wait_time(now), Duration::from_secs(10));
+
+        limiter.update(
+            &headers(&[
+                ("x-rate-limit-rules", "ip"),
+                ("x-rate-limit-ip", "5:10:60"),
+                ("x-rate-limit-ip-state", "1:10:0"),
+            ]),
+            now,
+        );
+        assert_eq!(limiter.wait_time(now), Duration::ZERO);
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::SystemTime;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{ChangeId, Item, StashTabResponse};
+
+/// The version of the [`StashRecord`] wire format written by this crate.
+///
+/// Bump it whenever a change to `StashRecord` or `Item` would break consumers that were built
+/// against an older version. Records written before versioning was introduced are version 1.
+pub const STASH_RECORD_VERSION: u32 = 1;
+
+fn legacy_version() -> u32 {
+    1
+}
+
+/// A single public stash tab update, as passed from the indexer to all downstream services.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StashRecord {
+    #[serde(default = "legacy_version")]
+    pub version: u32,
+    pub created_at: NaiveDateTime,
+    pub change_id: String,
+    pub next_change_id: String,
+    pub stash_id: String,
+    pub stash_type: String,
+    pub items: Vec<Item>,
+    pub public: bool,
+    pub account_name: Option<String>,
+    pub last_character_name: Option<String>,
+    pub stash_name: Option<String>,
+    pub league: Option<String>,
+    #[serde(default)]
+    pub chunk_id: i64,
+}
+
+impl StashRecord {
+    /// Maps the public stashes of a river page to records. Stash tabs flagged as private are
+    /// skipped, as their updates are always empty.
+    pub fn from_response(
+        change_id: ChangeId,
+        created_at: SystemTime,
+        response: StashTabResponse,
+        chunk_id: i64,
+    ) -> impl Iterator<Item = StashRecord> {
+        let next_change_id = response.next_change_id;
+        let created_at = DateTime::<Utc>::from(created_at).naive_utc();
+
+        response
+            .stashes
+            .into_iter()
+            .filter(|stash| stash.public)
+            .map(move |stash| StashRecord {
+                version: STASH_RECORD_VERSION,
+                account_name: stash.account_name,
+                last_character_name: stash.last_character_name,
+                stash_id: stash.id,
+                stash_name: stash.stash,
+                stash_type: stash.stash_type,
+                items: stash.items,
+                public: stash.public,
+                league: stash.league,
+                change_id: change_id.to_string(),
+                created_at,
+                next_change_id: next_change_id.clone(),
+                chunk_id,
+            })
+    }
+
+    /// Whether this record was written in a format this crate can read.
+    pub fn is_supported(&self) -> bool {
+        self.version <= STASH_RECORD_VERSION
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{str::FromStr, time::SystemTime};
+
+    use super::{StashRecord, STASH_RECORD_VERSION};
+    use crate::common::{ChangeId, StashTabResponse};
+
+    fn records() -> Vec<StashRecord> {
+        let response = serde_json::from_str::<StashTabResponse>(include_str!(
+            "../../tests/fixtures/river/2000000000-2000000001-2000000002-2000000003-2000000004.json"
+        ))
+        .unwrap();
+
+        StashRecord::from_response(
+            ChangeId::from_str("2000000000-2000000001-2000000002-2000000003-2000000004").unwrap(),
+            SystemTime::now(),
+            response,
+            7,
+        )
+        .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let records = records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].version, STASH_RECORD_VERSION);
+
+        let serialized = serde_json::to_string(&records).unwrap();
+        let deserialized = serde_json::from_str::<Vec<StashRecord>>(&serialized).unwrap();
+
+        assert_eq!(deserialized, records);
+        assert_eq!(deserialized[0].items[0].type_line, "Chaos Orb");
+        assert_eq!(deserialized[0].items[0].max_stack_size, Some(20));
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let value = serde_json::to_value(&records()[0]).unwrap();
+
+        // Consumers rely on these field names
+        for field in ["created_at", "stash_id", "account_name", "league", "items"] {
+            assert!(value.get(field).is_some(), "missing {field}");
+        }
+        let item = &value["items"][0];
+        assert_eq!(item["type_line"], "Chaos Orb");
+        assert_eq!(item["stack_size"], 12);
+        assert_eq!(item["extended"]["category"], "currency");
+    }
+
+    #[test]
+    fn test_unversioned_records_are_supported() {
+        let mut value = serde_json::to_value(&records()[0]).unwrap();
+        value.as_object_mut().unwrap().remove("version");
+
+        let record = serde_json::from_value::<StashRecord>(value).unwrap();
+        assert_eq!(record.version, 1);
+        assert!(record.is_supported());
+    }
+}
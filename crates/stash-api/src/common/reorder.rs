@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use super::ChangeId;
+
+/// Restores the river order of pages that finished downloading out of order.
+///
+/// Every page is keyed on the change id it was requested with and the change id of the page
+/// before it. A page is only handed out once its predecessor has been handed out.
+#[derive(Debug)]
+pub struct ReorderBuffer<T> {
+    last_change_id: ChangeId,
+    pending: HashMap<ChangeId, (ChangeId, T)>,
+    reordered: u64,
+}
+
+impl<T> ReorderBuffer<T> {
+    /// Expects the first page to follow `last_change_id`. The very first page of the river
+    /// follows itself.
+    pub fn new(last_change_id: ChangeId) -> Self {
+        Self {
+            last_change_id,
+            pending: HashMap::new(),
+            reordered: 0,
+        }
+    }
+
+    /// Adds the page for `change_id` and returns all pages that are now in order, together with
+    /// their change ids.
+    pub fn push(
+        &mut self,
+        previous_change_id: ChangeId,
+        change_id: ChangeId,
+        page: T,
+    ) -> Vec<(ChangeId, T)> {
+        if previous_change_id.ne(&self.last_change_id) {
+            self.reordered += 1;
+        }
+        self.pending.insert(previous_change_id, (change_id, page));
+
+        let mut ready = vec![];
+        while let Some((change_id, page)) = self.pending.remove(&self.last_change_id) {
+            self.last_change_id = change_id.clone();
+            ready.push((change_id, page));
+        }
+        ready
+    }
+
+    /// The number of pages waiting for their predecessor.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// How many pages arrived before their predecessor.
+    pub fn reordered(&self) -> u64 {
+        self.reordered
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::ReorderBuffer;
+    use crate::common::ChangeId;
+
+    fn id(s: &str) -> ChangeId {
+        ChangeId::from_str(&format!("{s}-0-0-0-0")).unwrap()
+    }
+
+    #[test]
+    fn test_in_order() {
+        let mut buffer = ReorderBuffer::new(id("1"));
+
+        assert_eq!(buffer.push(id("1"), id("1"), 'a'), vec![(id("1"), 'a')]);
+        assert_eq!(buffer.push(id("1"), id("2"), 'b'), vec![(id("2"), 'b')]);
+        assert_eq!(buffer.reordered(), 0);
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let mut buffer = ReorderBuffer::new(id("1"));
+
+        assert_eq!(buffer.push(id("2"), id("3"), 'c'), vec![]);
+        assert_eq!(buffer.push(id("3"), id("4"), 'd'), vec![]);
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(
+            buffer.push(id("1"), id("2"), 'b'),
+            vec![(id("2"), 'b'), (id("3"), 'c'), (id("4"), 'd')]
+        );
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.reordered(), 2);
+    }
+}
@@ -0,0 +1,257 @@
+//! How often and how long to wait before a failed request for a river page is sent again.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use crate::Error;
+
+/// The kinds of failures a [`RetryPolicy`] distinguishes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The request could not be sent or the response body could not be read.
+    Transport,
+    /// The API responded with a `5xx` status code.
+    ServerError,
+    /// The response could not be parsed as a river page.
+    Parse,
+    /// Everything else that may resolve itself, ie. a rejected OAuth token.
+    Other,
+}
+
+impl ErrorClass {
+    /// Returns `None` for errors that do not count against the retry budget. Being rate limited
+    /// is handled by the rate limiter, which holds back the next request as long as necessary.
+    pub fn of(error: &Error) -> Option<Self> {
+        match error {
+            Error::RateLimited(_) => None,
+            Error::Transport(_) => Some(Self::Transport),
+            Error::HttpStatus { status, .. } if *status >= 500 => Some(Self::ServerError),
+            Error::MalformedChangeId(_) | Error::Deserialize { .. } => Some(Self::Parse),
+            Error::HttpStatus { .. } | Error::Auth(_) => Some(Self::Other),
+        }
+    }
+}
+
+/// Exponential backoff for a single [`ErrorClass`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// How many attempts are made at a page before giving up, including the first one.
+    pub max_attempts: u32,
+    /// The delay after the first failed attempt, which doubles after every further one.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// The share of every delay, between `0.0` and `1.0`, that is randomly cut off so that
+    /// retries do not happen in lockstep.
+    pub jitter: f64,
+}
+
+impl Backoff {
+    pub fn new(max_attempts: u32, initial_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            initial_delay,
+            max_delay,
+            jitter: 0.0,
+        }
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The delay before the next attempt after `failed_attempts` attempts failed, without jitter.
+    pub fn delay(&self, failed_attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failed_attempts.saturating_sub(1));
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+
+    fn jittered_delay(&self, failed_attempts: u32) -> Duration {
+        self.delay(failed_attempts)
+            .mul_f64(1.0 - self.jitter * random_fraction())
+    }
+}
+
+/// Decides whether and when a failed request for a river page is sent again.
+///
+/// Failed attempts are counted per page. The indexer gives up on a page once as many attempts
+/// failed as the [`Backoff`] for the class of the latest error allows, and emits
+/// `IndexerEvent::RetriesExhausted` followed by `IndexerEvent::Stop`. Errors that can never
+/// resolve themselves, see [`Error::is_retriable`], stop the indexer right away.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    transport: Backoff,
+    server_error: Backoff,
+    parse: Backoff,
+    other: Backoff,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            transport: Backoff::new(5, Duration::from_secs(1), Duration::from_secs(30))
+                .with_jitter(0.2),
+            server_error: Backoff::new(5, Duration::from_secs(5), Duration::from_secs(120))
+                .with_jitter(0.2),
+            parse: Backoff::new(3, Duration::from_secs(5), Duration::from_secs(30))
+                .with_jitter(0.2),
+            other: Backoff::new(3, Duration::from_secs(1), Duration::from_secs(30))
+                .with_jitter(0.2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses the same backoff for every class of errors.
+    pub fn uniform(backoff: Backoff) -> Self {
+        Self {
+            transport: backoff,
+            server_error: backoff,
+            parse: backoff,
+            other: backoff,
+        }
+    }
+
+    /// Overrides the amount of attempts made at a page for every class of errors.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        for backoff in [
+            &mut self.transport,
+            &mut self.server_error,
+            &mut self.parse,
+            &mut self.other,
+        ] {
+            backoff.max_attempts = max_attempts.max(1);
+        }
+        self
+    }
+
+    pub fn with_transport(mut self, backoff: Backoff) -> Self {
+        self.transport = backoff;
+        self
+    }
+
+    pub fn with_server_error(mut self, backoff: Backoff) -> Self {
+        self.server_error = backoff;
+        self
+    }
+
+    pub fn with_parse(mut self, backoff: Backoff) -> Self {
+        self.parse = backoff;
+        self
+    }
+
+    pub fn with_other(mut self, backoff: Backoff) -> Self {
+        self.other = backoff;
+        self
+    }
+
+    pub fn backoff(&self, class: ErrorClass) -> &Backoff {
+        match class {
+            ErrorClass::Transport => &self.transport,
+            ErrorClass::ServerError => &self.server_error,
+            ErrorClass::Parse => &self.parse,
+            ErrorClass::Other => &self.other,
+        }
+    }
+
+    /// How long to wait before sending the request again after `failed_attempts` attempts
+    /// failed, the latest one due to `class`. Returns `None` once the budget is exhausted.
+    pub fn retry_delay(&self, class: ErrorClass, failed_attempts: u32) -> Option<Duration> {
+        let backoff = self.backoff(class);
+        if failed_attempts >= backoff.max_attempts {
+            return None;
+        }
+        Some(backoff.jittered_delay(failed_attempts))
+    }
+}
+
+/// A random number in `[0, 1)`. Every `RandomState` is seeded differently, which is plenty to
+/// spread out retries without pulling in a random number generator.
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Backoff, ErrorClass, RetryPolicy};
+    use crate::Error;
+
+    #[test]
+    fn test_error_class() {
+        let status = |status| Error::HttpStatus {
+            status,
+            change_id: None,
+        };
+
+        assert_eq!(ErrorClass::of(&status(503)), Some(ErrorClass::ServerError));
+        assert_eq!(ErrorClass::of(&status(401)), Some(ErrorClass::Other));
+        assert_eq!(
+            ErrorClass::of(&Error::Transport("reset".into())),
+            Some(ErrorClass::Transport)
+        );
+        assert_eq!(
+            ErrorClass::of(&Error::MalformedChangeId("abc".into())),
+            Some(ErrorClass::Parse)
+        );
+        assert_eq!(ErrorClass::of(&Error::RateLimited(Duration::ZERO)), None);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max_delay() {
+        let backoff = Backoff::new(10, Duration::from_secs(1), Duration::from_secs(10));
+
+        let delays = (1..=5)
+            .map(|n| backoff.delay(n).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10]);
+        assert_eq!(backoff.delay(100), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_retry_delay_is_jittered_within_bounds() {
+        let backoff =
+            Backoff::new(10, Duration::from_secs(8), Duration::from_secs(60)).with_jitter(0.5);
+        let policy = RetryPolicy::uniform(backoff);
+
+        for _ in 0..100 {
+            let delay = policy.retry_delay(ErrorClass::Transport, 1).unwrap();
+            assert!(delay >= Duration::from_secs(4) && delay <= Duration::from_secs(8));
+        }
+    }
+
+    #[test]
+    fn test_retry_budget_per_class() {
+        let policy = RetryPolicy::uniform(Backoff::new(
+            5,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        ))
+        .with_parse(Backoff::new(1, Duration::ZERO, Duration::ZERO));
+
+        assert_eq!(
+            policy.retry_delay(ErrorClass::ServerError, 4),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(policy.retry_delay(ErrorClass::ServerError, 5), None);
+        assert_eq!(policy.retry_delay(ErrorClass::Parse, 1), None);
+        assert_eq!(
+            policy
+                .with_max_attempts(2)
+                .retry_delay(ErrorClass::Transport, 2),
+            None
+        );
+    }
+}
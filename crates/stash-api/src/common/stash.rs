@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
@@ -21,21 +23,195 @@ pub struct Stash {
     pub league: Option<String>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+/// An item as sent by the public stash tab API.
+///
+/// Fields the API only sends for some items are optional. Everything that is not modelled here
+/// ends up in `extra` instead of being dropped. Items are serialized with snake_case field names
+/// and accept both these and the API's camelCase ones.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Item {
-    pub name: String,
+    #[serde(default)]
+    pub verified: bool,
+    #[serde(default)]
+    pub w: u32,
+    #[serde(default)]
+    pub h: u32,
+    #[serde(default)]
+    pub icon: String,
+    pub support: Option<bool>,
+    #[serde(alias = "stackSize")]
+    pub stack_size: Option<u32>,
+    #[serde(alias = "maxStackSize")]
+    pub max_stack_size: Option<u32>,
+    #[serde(alias = "stackSizeText")]
+    pub stack_size_text: Option<String>,
+    pub league: Option<String>,
     pub id: String,
-    pub note: Option<String>,
-    #[serde(rename(deserialize = "typeLine"))]
+    pub influences: Option<BTreeMap<String, bool>>,
+    pub elder: Option<bool>,
+    pub shaper: Option<bool>,
+    pub searing: Option<bool>,
+    pub tangled: Option<bool>,
+    #[serde(alias = "abyssJewel")]
+    pub abyss_jewel: Option<bool>,
+    pub delve: Option<bool>,
+    pub fractured: Option<bool>,
+    pub synthesised: Option<bool>,
+    pub sockets: Option<Vec<ItemSocket>>,
+    #[serde(alias = "socketedItems")]
+    pub socketed_items: Option<Vec<Item>>,
+    pub name: String,
+    #[serde(alias = "typeLine")]
     pub type_line: String,
-    #[serde(rename(deserialize = "stackSize"))]
-    pub stack_size: Option<u32>,
+    #[serde(default, alias = "baseType")]
+    pub base_type: String,
+    pub rarity: Option<String>,
+    #[serde(default)]
+    pub identified: bool,
+    #[serde(alias = "itemLevel")]
+    pub item_level: Option<u32>,
+    #[serde(default)]
+    pub ilvl: u32,
+    pub note: Option<String>,
+    pub forum_note: Option<String>,
+    #[serde(alias = "lockedToCharacter")]
+    pub locked_to_character: Option<bool>,
+    #[serde(alias = "lockedToAccount")]
+    pub locked_to_account: Option<bool>,
+    pub duplicated: Option<bool>,
+    pub split: Option<bool>,
+    pub corrupted: Option<bool>,
+    pub unmodifiable: Option<bool>,
+    pub properties: Option<Vec<ItemProperty>>,
+    #[serde(alias = "notableProperties")]
+    pub notable_properties: Option<Vec<ItemProperty>>,
+    pub requirements: Option<Vec<ItemProperty>>,
+    #[serde(alias = "additionalProperties")]
+    pub additional_properties: Option<Vec<ItemProperty>>,
+    #[serde(alias = "nextLevelRequirements")]
+    pub next_level_requirements: Option<Vec<ItemProperty>>,
+    #[serde(alias = "talismanTier")]
+    pub talisman_tier: Option<u32>,
+    #[serde(alias = "secDescrText")]
+    pub sec_descr_text: Option<String>,
+    #[serde(alias = "utilityMods")]
+    pub utility_mods: Option<Vec<String>>,
+    #[serde(alias = "enchantMods")]
+    pub enchant_mods: Option<Vec<String>>,
+    #[serde(alias = "scourgeMods")]
+    pub scourge_mods: Option<Vec<String>>,
+    #[serde(alias = "implicitMods")]
+    pub implicit_mods: Option<Vec<String>>,
+    #[serde(alias = "explicitMods")]
+    pub explicit_mods: Option<Vec<String>>,
+    #[serde(alias = "craftedMods")]
+    pub crafted_mods: Option<Vec<String>>,
+    #[serde(alias = "fracturedMods")]
+    pub fractured_mods: Option<Vec<String>>,
+    #[serde(alias = "crucibleMods")]
+    pub crucible_mods: Option<Vec<String>>,
+    #[serde(alias = "cosmeticMods")]
+    pub cosmetic_mods: Option<Vec<String>>,
+    #[serde(alias = "veiledMods")]
+    pub veiled_mods: Option<Vec<String>>,
+    pub veiled: Option<bool>,
+    #[serde(alias = "descrText")]
+    pub descr_text: Option<String>,
+    #[serde(alias = "flavourText")]
+    pub flavour_text: Option<Vec<String>>,
+    #[serde(alias = "isRelic")]
+    pub is_relic: Option<bool>,
+    pub replica: Option<bool>,
+    pub scourged: Option<bool>,
+    pub ruthless: Option<bool>,
+    #[serde(default, alias = "frameType")]
+    pub frame_type: u32,
+    #[serde(alias = "artFilename")]
+    pub art_filename: Option<String>,
+    pub x: Option<u32>,
+    pub y: Option<u32>,
+    #[serde(alias = "inventoryId")]
+    pub inventory_id: Option<String>,
+    /// The socket this item is placed in, for socketed items.
+    pub socket: Option<u32>,
+    pub colour: Option<String>,
     pub extended: ItemExtendedProp,
+    /// Fields that are not modelled above, ie. ones added by later versions of the API.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ItemSocket {
+    pub group: u32,
+    pub attr: Option<String>,
+    #[serde(alias = "sColour")]
+    pub s_colour: Option<String>,
 }
 
+/// A property or requirement line, ie. `Quality: +20%`.
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ItemProperty {
+    pub name: String,
+    /// Every value is accompanied by the id of its display colour.
+    pub values: Vec<(String, u32)>,
+    #[serde(alias = "displayMode")]
+    pub display_mode: Option<u32>,
+    #[serde(alias = "type")]
+    pub kind: Option<u32>,
+    pub suffix: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct ItemExtendedProp {
     pub category: String,
-    #[serde(rename(deserialize = "baseType"))]
+    pub subcategories: Option<Vec<String>>,
+    pub prefixes: Option<u32>,
+    pub suffixes: Option<u32>,
+    #[serde(alias = "baseType")]
     pub base_type: Option<String>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::Item;
+
+    #[test]
+    fn test_deserialize_rare_item() {
+        let item = serde_json::from_str::<Item>(
+            r#"{
+                "verified": false, "w": 2, "h": 3, "icon": "https://web.poecdn.com/icon.png",
+                "league": "Crucible", "id": "abc", "influences": {"shaper": true},
+                "sockets": [{"group": 0, "attr": "S", "sColour": "R"}],
+                "name": "Doom Shell", "typeLine": "Astral Plate", "baseType": "Astral Plate",
+                "rarity": "Rare", "identified": true, "ilvl": 84, "corrupted": true,
+                "properties": [{"name": "Armour", "values": [["711", 1]], "displayMode": 0, "type": 16}],
+                "requirements": [{"name": "Level", "values": [["62", 0]], "displayMode": 0}],
+                "implicitMods": ["+12% to all Elemental Resistances"],
+                "explicitMods": ["+95 to maximum Life"],
+                "craftedMods": ["+30% to Fire Resistance"],
+                "frameType": 2, "x": 4, "y": 0, "inventoryId": "Stash1",
+                "extended": {"category": "armour", "subcategories": ["chest"], "prefixes": 1, "suffixes": 2},
+                "hybrid": {"isVaalGem": true}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(item.ilvl, 84);
+        assert_eq!(item.frame_type, 2);
+        assert_eq!(item.corrupted, Some(true));
+        assert!(item.influences.unwrap()["shaper"]);
+        assert_eq!(item.sockets.unwrap()[0].s_colour.as_deref(), Some("R"));
+        assert_eq!(item.properties.unwrap()[0].values[0], ("711".into(), 1));
+        assert_eq!(item.explicit_mods.unwrap(), vec!["+95 to maximum Life"]);
+        assert_eq!(item.extended.suffixes, Some(2));
+        // Unknown fields are kept as is
+        assert_eq!(
+            item.extra.get("hybrid"),
+            Some(&serde_json::json!({"isVaalGem": true}))
+        );
+        assert!(!item.extra.contains_key("typeLine"));
+    }
+}
@@ -0,0 +1,557 @@
+//! Incremental deserialization of river pages.
+//!
+//! A river page is a single JSON object of the form `{"next_change_id": "...", "stashes": [...]}`
+//! that easily weighs 5-10 MB. Instead of buffering the whole body, [`StashParser`] is fed the
+//! body chunk by chunk and yields every `Stash` as soon as it is complete, only ever holding on to
+//! the bytes of the stash it is currently reading.
+
+use std::str::FromStr;
+#[cfg(feature = "sync")]
+use std::{io::Read, time::Instant};
+
+#[cfg(feature = "sync")]
+use super::encoding::{BodyDecoder, ContentEncoding, TransferStats};
+use super::{indexing::Prefetch, ChangeId, Stash};
+use crate::Error;
+
+#[cfg(feature = "sync")]
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    NextChangeId,
+    Stashes,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    BeforeKey,
+    AfterKey(Key),
+    BeforeValue(Key),
+    Stashes,
+    Done,
+    Failed,
+}
+
+/// Finds the end of a JSON value, picking up where it left off once more bytes are available.
+#[derive(Debug, Default)]
+struct ValueScanner {
+    scanned: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl ValueScanner {
+    /// Returns the length of the value at the start of `bytes` once it is complete.
+    fn scan(&mut self, bytes: &[u8]) -> Option<usize> {
+        while self.scanned < bytes.len() {
+            let byte = bytes[self.scanned];
+            self.scanned += 1;
+
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                    if self.depth == 0 {
+                        return Some(self.complete());
+                    }
+                }
+                continue;
+            }
+
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' if self.depth > 0 => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        return Some(self.complete());
+                    }
+                }
+                // Numbers, booleans and null end at the next delimiter
+                b'}' | b']' | b',' | b' ' | b'\t' | b'\r' | b'\n' if self.depth == 0 => {
+                    self.scanned -= 1;
+                    return Some(self.complete());
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    fn complete(&mut self) -> usize {
+        std::mem::take(self).scanned
+    }
+}
+
+/// Reads `Stash`es from a river page that arrives in chunks.
+///
+/// Feed it bytes via [`StashParser::feed`] and take out complete stashes via
+/// [`StashParser::next_stash`] until it returns `None`, then feed it the next chunk.
+#[derive(Debug)]
+pub struct StashParser {
+    change_id: ChangeId,
+    buffer: Vec<u8>,
+    position: usize,
+    state: State,
+    scanner: ValueScanner,
+    next_change_id: Option<ChangeId>,
+    stashes: usize,
+    has_stashes: Option<bool>,
+}
+
+impl StashParser {
+    /// Parses the page requested for `change_id`, which is used as context for errors.
+    pub fn new(change_id: ChangeId) -> Self {
+        Self {
+            change_id,
+            buffer: Vec::new(),
+            position: 0,
+            state: State::Start,
+            scanner: ValueScanner::default(),
+            next_change_id: None,
+            stashes: 0,
+            has_stashes: None,
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        // Drop everything that has already been parsed before growing the buffer
+        if self.position > 0 {
+            self.buffer.drain(..self.position);
+            self.position = 0;
+        }
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The change id of the following page, as soon as it has been read.
+    pub fn next_change_id(&self) -> Option<&ChangeId> {
+        self.next_change_id.as_ref()
+    }
+
+    /// Whether the page contains any stashes, as soon as this is known.
+    pub fn has_stashes(&self) -> Option<bool> {
+        self.has_stashes
+    }
+
+    /// Whether the whole page has been read.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Returns the next complete stash, or `None` if more bytes are needed or the page is done.
+    pub fn next_stash(&mut self) -> Result<Option<Stash>, Error> {
+        let result = self.advance(true);
+        if result.is_err() {
+            self.state = State::Failed;
+        }
+        result
+    }
+
+    /// Reads as far as needed to tell where the indexer continues, without reading any stashes.
+    #[cfg(feature = "sync")]
+    pub(crate) fn prefetch(&mut self) -> Result<Prefetch, Error> {
+        if let Err(e) = self.advance(false) {
+            self.state = State::Failed;
+            return Err(e);
+        }
+        self.prefetched()
+    }
+
+    /// Where the indexer continues, as far as the page has been read.
+    pub(crate) fn prefetched(&self) -> Result<Prefetch, Error> {
+        // At the head of the river, the API points back to the requested page until it has
+        // something new
+        if let Some(next_change_id) = &self.next_change_id {
+            if !next_change_id.has_progressed_since(&self.change_id) {
+                return Ok(Prefetch::Empty);
+            }
+        }
+
+        match (self.has_stashes, &self.next_change_id) {
+            (Some(false), _) => Ok(Prefetch::Empty),
+            (Some(true), Some(next_change_id)) => Ok(Prefetch::Next(next_change_id.clone())),
+            (Some(true), None) => Err(self.error("Expected next_change_id before stashes")),
+            (None, _) if self.is_done() => Ok(Prefetch::Empty),
+            (None, _) => Ok(Prefetch::Pending),
+        }
+    }
+
+    /// Checks that the whole page has been read and returns the change id of the following page.
+    pub fn finish(self) -> Result<ChangeId, Error> {
+        if !self.is_done() {
+            return Err(self.error("Unexpected end of page"));
+        }
+
+        match self.next_change_id {
+            Some(next_change_id) => Ok(next_change_id),
+            None => Err(self.error("Missing next_change_id")),
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> Error {
+        Error::Deserialize {
+            change_id: self.change_id.clone(),
+            message: message.into(),
+        }
+    }
+
+    /// Skips whitespace and returns the next byte without consuming it.
+    fn peek(&mut self) -> Option<u8> {
+        while let Some(byte) = self.buffer.get(self.position) {
+            if byte.is_ascii_whitespace() {
+                self.position += 1;
+            } else {
+                return Some(*byte);
+            }
+        }
+        None
+    }
+
+    /// Returns the length of the next value once it is complete, without consuming it.
+    fn scan_value(&mut self) -> Option<usize> {
+        self.scanner.scan(&self.buffer[self.position..])
+    }
+
+    fn value(&self, length: usize) -> &[u8] {
+        &self.buffer[self.position..self.position + length]
+    }
+
+    /// Runs the state machine until the next stash is complete, or until it starts if
+    /// `read_stashes` is false.
+    fn advance(&mut self, read_stashes: bool) -> Result<Option<Stash>, Error> {
+        loop {
+            match self.state {
+                State::Done | State::Failed => return Ok(None),
+                State::Start => match self.peek() {
+                    None => return Ok(None),
+                    Some(b'{') => {
+                        self.position += 1;
+                        self.state = State::BeforeKey;
+                    }
+                    Some(byte) => {
+                        return Err(self.error(format!(
+                            "Expected page to start with {{, got {}",
+                            byte as char
+                        )))
+                    }
+                },
+                State::BeforeKey => match self.peek() {
+                    None => return Ok(None),
+                    Some(b',') => self.position += 1,
+                    Some(b'}') => {
+                        self.position += 1;
+                        self.state = State::Done;
+                    }
+                    Some(b'"') => {
+                        let length = match self.scan_value() {
+                            Some(length) => length,
+                            None => return Ok(None),
+                        };
+                        let key = match self.value(length) {
+                            b"\"next_change_id\"" => Key::NextChangeId,
+                            b"\"stashes\"" => Key::Stashes,
+                            _ => Key::Other,
+                        };
+                        self.position += length;
+                        self.state = State::AfterKey(key);
+                    }
+                    Some(byte) => {
+                        return Err(self.error(format!("Expected key, got {}", byte as char)))
+                    }
+                },
+                State::AfterKey(key) => match self.peek() {
+                    None => return Ok(None),
+                    Some(b':') => {
+                        self.position += 1;
+                        self.state = State::BeforeValue(key);
+                    }
+                    Some(byte) => {
+                        return Err(self.error(format!("Expected :, got {}", byte as char)))
+                    }
+                },
+                State::BeforeValue(Key::Stashes) => match self.peek() {
+                    None => return Ok(None),
+                    Some(b'[') => {
+                        self.position += 1;
+                        self.state = State::Stashes;
+                    }
+                    Some(byte) => {
+                        return Err(self.error(format!(
+                            "Expected stashes to be an array, got {}",
+                            byte as char
+                        )))
+                    }
+                },
+                State::BeforeValue(key) => {
+                    if self.peek().is_none() {
+                        return Ok(None);
+                    }
+                    let length = match self.scan_value() {
+                        Some(length) => length,
+                        None => return Ok(None),
+                    };
+
+                    if key == Key::NextChangeId {
+                        let next_change_id = serde_json::from_slice::<String>(self.value(length))
+                            .map_err(|e| self.error(e.to_string()))?;
+                        self.next_change_id = Some(ChangeId::from_str(&next_change_id)?);
+                    }
+
+                    self.position += length;
+                    self.state = State::BeforeKey;
+                }
+                State::Stashes => match self.peek() {
+                    None => return Ok(None),
+                    Some(b',') => self.position += 1,
+                    Some(b']') => {
+                        self.position += 1;
+                        self.has_stashes.get_or_insert(false);
+                        self.state = State::BeforeKey;
+                    }
+                    Some(_) => {
+                        self.has_stashes = Some(true);
+                        if !read_stashes {
+                            return Ok(None);
+                        }
+                        let length = match self.scan_value() {
+                            Some(length) => length,
+                            None => return Ok(None),
+                        };
+                        let stash = serde_json::from_slice::<Stash>(self.value(length))
+                            .map_err(|e| self.error(e.to_string()))?;
+
+                        self.position += length;
+                        self.stashes += 1;
+                        return Ok(Some(stash));
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Reads `Stash`es from a blocking reader as they arrive.
+///
+/// The change id of the following page is available via [`StashReader::next_change_id`] as soon
+/// as it has been read, and via [`StashReader::finish`] once all stashes have been read.
+#[cfg(feature = "sync")]
+pub struct StashReader<R> {
+    reader: R,
+    decoder: BodyDecoder,
+    parser: StashParser,
+    chunk: Vec<u8>,
+    is_exhausted: bool,
+}
+
+#[cfg(feature = "sync")]
+impl<R: Read> StashReader<R> {
+    pub fn new(change_id: ChangeId, reader: R) -> Self {
+        Self {
+            reader,
+            decoder: BodyDecoder::new(ContentEncoding::Identity, Instant::now()),
+            parser: StashParser::new(change_id),
+            chunk: vec![0; READ_BUFFER_SIZE],
+            is_exhausted: false,
+        }
+    }
+
+    /// Decodes the body according to its `Content-Encoding`. `requested_at` is when the request
+    /// for the page was sent, which the download time in [`StashReader::transfer`] starts from.
+    pub fn with_content_encoding(
+        mut self,
+        content_encoding: ContentEncoding,
+        requested_at: Instant,
+    ) -> Self {
+        self.decoder = BodyDecoder::new(content_encoding, requested_at);
+        self
+    }
+
+    pub fn next_change_id(&self) -> Option<&ChangeId> {
+        self.parser.next_change_id()
+    }
+
+    /// How the body has been transferred so far. Complete once all stashes have been read.
+    pub fn transfer(&self) -> &TransferStats {
+        self.decoder.stats()
+    }
+
+    pub fn finish(self) -> Result<ChangeId, Error> {
+        self.parser.finish()
+    }
+
+    /// Reads as far as needed to tell where the indexer continues, without reading any stashes.
+    pub(crate) fn prefetch(&mut self) -> Result<Prefetch, Error> {
+        loop {
+            match self.parser.prefetch()? {
+                Prefetch::Pending if self.is_exhausted => {
+                    return Err(self.parser.error("Unexpected end of page"))
+                }
+                Prefetch::Pending => {}
+                prefetch => return Ok(prefetch),
+            }
+
+            self.read_chunk()?;
+        }
+    }
+
+    fn read_chunk(&mut self) -> Result<(), Error> {
+        let result = match self.reader.read(&mut self.chunk) {
+            Ok(0) => {
+                self.is_exhausted = true;
+                self.decoder.finish().map(|rest| self.parser.feed(&rest))
+            }
+            Ok(n) => self
+                .decoder
+                .decode(&self.chunk[..n])
+                .map(|decoded| self.parser.feed(&decoded)),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => Ok(()),
+            Err(e) => Err(Error::Transport(e.to_string())),
+        };
+
+        if result.is_err() {
+            self.is_exhausted = true;
+        }
+        result
+    }
+}
+
+#[cfg(feature = "sync")]
+impl<R: Read> Iterator for StashReader<R> {
+    type Item = Result<Stash, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.parser.next_stash() {
+                Ok(Some(stash)) => return Some(Ok(stash)),
+                Err(e) => return Some(Err(e)),
+                Ok(None) if self.is_exhausted => return None,
+                // Keeps reading after the end of the page to decode the rest of the body
+                Ok(None) => {}
+            }
+
+            if let Err(e) = self.read_chunk() {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::StashParser;
+    #[cfg(feature = "sync")]
+    use super::StashReader;
+    use crate::{
+        common::{indexing::Prefetch, ChangeId},
+        Error,
+    };
+
+    const PAGE: &str = r#"{"next_change_id": "2-2-2-2-2", "stashes": [
+        {"accountName": "a", "id": "1", "stash": "}\"[{", "stashType": "PremiumStash", "items": [], "public": true, "league": null, "extra": [1, {"x": true}]},
+        {"accountName": null, "id": "2", "stashType": "PremiumStash", "items": [], "public": false, "league": "Standard"}
+    ], "unknown": 12.5}"#;
+
+    fn change_id() -> ChangeId {
+        ChangeId::from_str("1-1-1-1-1").unwrap()
+    }
+
+    #[test]
+    fn test_parse_page_in_chunks() {
+        for chunk_size in [1, 7, 64, PAGE.len()] {
+            let mut parser = StashParser::new(change_id());
+            let mut stashes = vec![];
+
+            for chunk in PAGE.as_bytes().chunks(chunk_size) {
+                parser.feed(chunk);
+                while let Some(stash) = parser.next_stash().unwrap() {
+                    stashes.push(stash);
+                }
+            }
+
+            assert_eq!(parser.has_stashes(), Some(true));
+            assert_eq!(stashes.len(), 2);
+            assert_eq!(stashes[0].stash.as_deref(), Some("}\"[{"));
+            assert_eq!(stashes[1].league.as_deref(), Some("Standard"));
+            assert_eq!(
+                parser.finish(),
+                Ok(ChangeId::from_str("2-2-2-2-2").unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn test_next_change_id_is_available_early() {
+        let mut parser = StashParser::new(change_id());
+        parser.feed(&PAGE.as_bytes()[..50]);
+
+        assert_eq!(parser.next_stash(), Ok(None));
+        assert_eq!(
+            parser.next_change_id(),
+            Some(&ChangeId::from_str("2-2-2-2-2").unwrap())
+        );
+        assert_eq!(parser.has_stashes(), None);
+    }
+
+    #[test]
+    fn test_empty_page() {
+        let mut parser = StashParser::new(change_id());
+        parser.feed(br#"{"next_change_id":"1-1-1-1-1","stashes":[]}"#);
+
+        assert_eq!(parser.next_stash(), Ok(None));
+        assert_eq!(parser.has_stashes(), Some(false));
+        assert_eq!(parser.finish(), Ok(change_id()));
+    }
+
+    #[test]
+    fn test_prefetched() {
+        let mut parser = StashParser::new(change_id());
+        assert_eq!(parser.prefetched(), Ok(Prefetch::Pending));
+        parser.feed(br#"{"next_change_id":"2-2-2-2-2","stashes":[{"#);
+        assert_eq!(parser.next_stash(), Ok(None));
+        assert_eq!(
+            parser.prefetched(),
+            Ok(Prefetch::Next(ChangeId::from_str("2-2-2-2-2").unwrap()))
+        );
+
+        // A page that points back to itself makes no progress, even before its stashes are read
+        let mut parser = StashParser::new(change_id());
+        parser.feed(br#"{"next_change_id":"1-1-1-1-1","#);
+        assert_eq!(parser.next_stash(), Ok(None));
+        assert_eq!(parser.prefetched(), Ok(Prefetch::Empty));
+    }
+
+    #[test]
+    fn test_malformed_page() {
+        let mut parser = StashParser::new(change_id());
+        parser.feed(br#"{"next_change_id":"1-1-1-1-1","stashes":[{"id": 1}]}"#);
+        assert!(matches!(
+            parser.next_stash(),
+            Err(Error::Deserialize { .. })
+        ));
+
+        let parser = StashParser::new(change_id());
+        assert!(parser.finish().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn test_stash_reader() {
+        let mut reader = StashReader::new(change_id(), PAGE.as_bytes());
+
+        assert_eq!(reader.by_ref().filter(|stash| stash.is_ok()).count(), 2);
+        assert_eq!(
+            reader.finish(),
+            Ok(ChangeId::from_str("2-2-2-2-2").unwrap())
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use crate::common::{auth::AuthError, ChangeId};
+
+/// Everything that can go wrong while indexing the river.
+///
+/// Errors encountered by a running indexer are handed to the consumer as an `Error` message or
+/// event, which decides whether to keep going or to shut down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The request could not be sent or its response body could not be read.
+    Transport(String),
+    /// The API responded with an unexpected status code.
+    HttpStatus {
+        status: u16,
+        change_id: Option<ChangeId>,
+    },
+    /// The API rate limited us and we have to wait before sending the next request.
+    RateLimited(Duration),
+    /// No OAuth token could be obtained.
+    Auth(AuthError),
+    /// A change id did not consist of one numeric offset per shard, ie. `\d+(-\d+){4}`.
+    MalformedChangeId(String),
+    /// The river page for `change_id` could not be deserialized.
+    Deserialize {
+        change_id: ChangeId,
+        message: String,
+    },
+}
+
+impl Error {
+    /// Whether the indexer is able to continue after this error, ie. by retrying the request.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            Self::Transport(_) | Self::RateLimited(_) => true,
+            // A rejected token is replaced by a new one, but missing permissions are permanent
+            Self::HttpStatus { status, .. } => *status != 403,
+            Self::Auth(e) => e.is_retriable(),
+            Self::MalformedChangeId(_) | Self::Deserialize { .. } => true,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "Transport error: {e}"),
+            Self::HttpStatus {
+                status,
+                change_id: Some(change_id),
+            } => write!(f, "HTTP status {status} for change id {change_id}"),
+            Self::HttpStatus {
+                status,
+                change_id: None,
+            } => write!(f, "HTTP status {status}"),
+            Self::RateLimited(wait_time) => {
+                write!(f, "Rate limited for {}ms", wait_time.as_millis())
+            }
+            Self::Auth(e) => e.fmt(f),
+            Self::MalformedChangeId(change_id) => write!(f, "Malformed change id {change_id}"),
+            Self::Deserialize { change_id, message } => {
+                write!(f, "Failed deserializing change id {change_id}: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Auth(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<AuthError> for Error {
+    fn from(e: AuthError) -> Self {
+        Self::Auth(e)
+    }
+}
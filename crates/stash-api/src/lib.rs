@@ -1,7 +1,13 @@
 pub mod common;
+mod error;
+
+pub use error::Error;
 
 #[cfg(feature = "sync")]
 pub mod sync;
 
 #[cfg(feature = "async")]
 pub mod r#async;
+
+#[cfg(feature = "mock-server")]
+pub mod mock;
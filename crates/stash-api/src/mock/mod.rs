@@ -0,0 +1,531 @@
+//! A stand-in server for the Public Stash Tab API that replays recorded river pages.
+//!
+//! Besides serving pages keyed by change id, it implements the OAuth client credentials flow,
+//! poe.ninja's `GetStats` endpoint, emits rate limit headers, gzips pages for clients accepting it
+//! and injects `429` and `503` responses on a schedule. Point the indexers at it via [`crate::common::Endpoints::with_base_url`].
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use serde::Deserialize;
+
+use crate::common::rate_limit::RateLimitWindow;
+
+const RATE_LIMIT_POLICY: &str = "public-stash-tabs-request-limit";
+
+#[derive(Debug, Clone)]
+pub struct MockRiverConfig {
+    pages_dir: PathBuf,
+    credentials: Option<(String, String)>,
+    rate_limit: RateLimitWindow,
+    rate_limited_every: Option<u64>,
+    unavailable_every: Option<u64>,
+    truncated_every: Option<u64>,
+    token_expires_in: Option<u64>,
+    max_offset: Option<u64>,
+}
+
+/// The rate limit GGG applies to the public stash tab API per IP.
+const DEFAULT_RATE_LIMIT: RateLimitWindow = RateLimitWindow {
+    max_hits: 45,
+    period: Duration::from_secs(60),
+    penalty: Duration::from_secs(60),
+};
+
+impl MockRiverConfig {
+    /// Serves all `<change_id>.json` files in `pages_dir` as river pages.
+    pub fn new(pages_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            pages_dir: pages_dir.into(),
+            credentials: None,
+            rate_limit: DEFAULT_RATE_LIMIT,
+            rate_limited_every: None,
+            unavailable_every: None,
+            truncated_every: None,
+            token_expires_in: None,
+            max_offset: None,
+        }
+    }
+
+    /// Only issues tokens for the given client credentials. Any credentials are accepted otherwise.
+    pub fn with_credentials(mut self, client_id: String, client_secret: String) -> Self {
+        self.credentials = Some((client_id, client_secret));
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: RateLimitWindow) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Responds with `429 Too Many Requests` to every n-th river request.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is 0.
+    pub fn with_rate_limited_every(mut self, n: u64) -> Self {
+        assert!(n > 0, "Every 0th request cannot be rate limited");
+        self.rate_limited_every = Some(n);
+        self
+    }
+
+    /// Responds with `503 Service Unavailable` to every n-th river request.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is 0.
+    pub fn with_unavailable_every(mut self, n: u64) -> Self {
+        assert!(n > 0, "Every 0th request cannot be unavailable");
+        self.unavailable_every = Some(n);
+        self
+    }
+
+    /// Cuts the body of every n-th river request for a recorded page short halfway through its
+    /// stashes, after its next change id.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is 0.
+    pub fn with_truncated_every(mut self, n: u64) -> Self {
+        assert!(n > 0, "Every 0th request cannot be truncated");
+        self.truncated_every = Some(n);
+        self
+    }
+
+    /// Lets issued tokens expire after the given amount of seconds, after which requests using
+    /// them are rejected with `401 Unauthorized`.
+    pub fn with_token_expires_in(mut self, seconds: u64) -> Self {
+        self.token_expires_in = Some(seconds);
+        self
+    }
+
+    /// Clamps the offsets of requested change ids to `max`, like the API may do with offsets far
+    /// ahead of the river.
+    pub fn with_max_offset(mut self, max: u64) -> Self {
+        self.max_offset = Some(max);
+        self
+    }
+
+    /// Reads the configuration from `MOCK_RIVER_*` environment variables.
+    pub fn from_env() -> Result<Self, String> {
+        let pages_dir = std::env::var("MOCK_RIVER_PAGES_DIR")
+            .map_err(|_| "Missing environment variable MOCK_RIVER_PAGES_DIR".to_string())?;
+        let mut config = Self::new(pages_dir);
+
+        if let (Ok(client_id), Ok(client_secret)) = (
+            std::env::var("MOCK_RIVER_CLIENT_ID"),
+            std::env::var("MOCK_RIVER_CLIENT_SECRET"),
+        ) {
+            config = config.with_credentials(client_id, client_secret);
+        }
+        if let Ok(rule) = std::env::var("MOCK_RIVER_RATE_LIMIT") {
+            config = config.with_rate_limit(rule.parse()?);
+        }
+        if let Some(n) = read_every_from_env("MOCK_RIVER_RATE_LIMITED_EVERY")? {
+            config = config.with_rate_limited_every(n);
+        }
+        if let Some(n) = read_every_from_env("MOCK_RIVER_UNAVAILABLE_EVERY")? {
+            config = config.with_unavailable_every(n);
+        }
+        if let Some(n) = read_every_from_env("MOCK_RIVER_TRUNCATED_EVERY")? {
+            config = config.with_truncated_every(n);
+        }
+        if let Some(seconds) = read_u64_from_env("MOCK_RIVER_TOKEN_EXPIRES_IN")? {
+            config = config.with_token_expires_in(seconds);
+        }
+        if let Some(max) = read_u64_from_env("MOCK_RIVER_MAX_OFFSET")? {
+            config = config.with_max_offset(max);
+        }
+
+        Ok(config)
+    }
+}
+
+fn read_u64_from_env(name: &str) -> Result<Option<u64>, String> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Invalid value for {name}: {e}")),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads the `n` of an every n-th request setting, which has to be at least 1.
+fn read_every_from_env(name: &str) -> Result<Option<u64>, String> {
+    match read_u64_from_env(name)? {
+        Some(0) => Err(format!("Invalid value for {name}: has to be at least 1")),
+        n => Ok(n),
+    }
+}
+
+#[derive(Debug)]
+pub struct MockRiver {
+    config: MockRiverConfig,
+    pages: HashMap<String, Vec<u8>>,
+    first_change_id: Option<String>,
+    state: Mutex<MockRiverState>,
+}
+
+#[derive(Debug, Default)]
+struct MockRiverState {
+    requests: u64,
+    hits: VecDeque<Instant>,
+    restricted_until: Option<Instant>,
+    /// Issued access tokens and when they were issued
+    tokens: HashMap<String, Instant>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PagePrefix {
+    next_change_id: String,
+}
+
+impl MockRiver {
+    /// Loads all recorded pages from the configured directory.
+    pub fn new(config: MockRiverConfig) -> std::io::Result<Self> {
+        let pages = load_pages(&config.pages_dir)?;
+        let first_change_id = find_first_change_id(&pages);
+
+        Ok(Self {
+            config,
+            pages,
+            first_change_id,
+            state: Mutex::new(MockRiverState::default()),
+        })
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/public-stash-tabs", get(public_stash_tabs))
+            .route("/oauth/token", post(oauth_token))
+            .route("/api/Data/GetStats", get(get_stats))
+            .with_state(Arc::new(self))
+    }
+
+    /// Serves the mock river on `addr` until the returned future is dropped.
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), axum::Error> {
+        axum::Server::bind(&addr)
+            .serve(self.router().into_make_service())
+            .await
+            .map_err(axum::Error::new)
+    }
+
+    /// Serves the mock river on a random local port in the background and returns its address.
+    pub fn spawn(self) -> SocketAddr {
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(self.router().into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+}
+
+fn load_pages(pages_dir: &Path) -> std::io::Result<HashMap<String, Vec<u8>>> {
+    let mut pages = HashMap::new();
+
+    for entry in std::fs::read_dir(pages_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        if let Some(change_id) = path.file_stem().and_then(|s| s.to_str()) {
+            pages.insert(change_id.to_owned(), std::fs::read(&path)?);
+        }
+    }
+
+    tracing::info!("mock-river: Loaded {} pages", pages.len());
+    Ok(pages)
+}
+
+/// The first page of the recording is the one no other page points to.
+fn find_first_change_id(pages: &HashMap<String, Vec<u8>>) -> Option<String> {
+    let referenced = pages
+        .values()
+        .filter_map(|bytes| serde_json::from_slice::<PagePrefix>(bytes).ok())
+        .map(|p| p.next_change_id)
+        .collect::<HashSet<_>>();
+
+    let mut roots = pages
+        .keys()
+        .filter(|change_id| !referenced.contains(*change_id))
+        .collect::<Vec<_>>();
+    roots.sort();
+    roots.first().map(|s| s.to_string())
+}
+
+#[derive(Debug, Deserialize)]
+struct StashTabsQuery {
+    id: Option<String>,
+}
+
+async fn public_stash_tabs(
+    State(river): State<Arc<MockRiver>>,
+    Query(query): Query<StashTabsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let now = Instant::now();
+    let mut state = river.state.lock().unwrap();
+
+    let is_authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .and_then(|token| state.tokens.get(token))
+        .is_some_and(|issued_at| match river.config.token_expires_in {
+            Some(seconds) => now.duration_since(*issued_at) < Duration::from_secs(seconds),
+            None => true,
+        });
+    if !is_authorized {
+        return (StatusCode::UNAUTHORIZED, "Invalid access token").into_response();
+    }
+    let accepts_gzip = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|encoding| encoding.trim() == "gzip"));
+
+    state.requests += 1;
+    let requests = state.requests;
+    let rule = river.config.rate_limit;
+
+    while state
+        .hits
+        .front()
+        .is_some_and(|hit| now.duration_since(*hit) >= rule.period)
+    {
+        state.hits.pop_front();
+    }
+    state.hits.push_back(now);
+
+    let is_scheduled_rate_limit = river
+        .config
+        .rate_limited_every
+        .is_some_and(|n| requests % n == 0);
+    if state.hits.len() > rule.max_hits as usize || is_scheduled_rate_limit {
+        state.restricted_until = Some(now + rule.penalty);
+    }
+
+    let restricted_for = state
+        .restricted_until
+        .map(|until| until.saturating_duration_since(now))
+        .unwrap_or_default();
+    let rate_limit_headers = rate_limit_headers(&rule, state.hits.len(), restricted_for);
+    drop(state);
+
+    if !restricted_for.is_zero() {
+        tracing::info!("mock-river: Rate limiting request #{}", requests);
+        let mut headers = rate_limit_headers;
+        headers.insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(restricted_for.as_secs().max(1)),
+        );
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            headers,
+            "Rate limit exceeded",
+        )
+            .into_response();
+    }
+
+    if river
+        .config
+        .unavailable_every
+        .is_some_and(|n| requests % n == 0)
+    {
+        tracing::info!(
+            "mock-river: Responding unavailable to request #{}",
+            requests
+        );
+        return (StatusCode::SERVICE_UNAVAILABLE, rate_limit_headers).into_response();
+    }
+
+    let change_id = match query.id.or_else(|| river.first_change_id.clone()) {
+        Some(change_id) => change_id,
+        None => return (StatusCode::NOT_FOUND, "No pages recorded").into_response(),
+    };
+    let change_id = match river.config.max_offset {
+        Some(max) => clamp_offsets(&change_id, max),
+        None => change_id,
+    };
+
+    // Past the end of the recording we are at the head of the river, which the API
+    // signals by returning an empty page pointing to the requested change id again.
+    let body = match river.pages.get(&change_id) {
+        Some(page)
+            if river
+                .config
+                .truncated_every
+                .is_some_and(|n| requests % n == 0) =>
+        {
+            tracing::info!("mock-river: Truncating response to request #{}", requests);
+            page[..page.len() / 2].to_vec()
+        }
+        Some(page) => page.clone(),
+        None => format!("{{\"next_change_id\":\"{change_id}\",\"stashes\":[]}}").into_bytes(),
+    };
+
+    let mut headers = rate_limit_headers;
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+
+    let body = if accepts_gzip {
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&body).unwrap();
+        encoder.finish().unwrap()
+    } else {
+        body
+    };
+    (StatusCode::OK, headers, body).into_response()
+}
+
+fn clamp_offsets(change_id: &str, max: u64) -> String {
+    change_id
+        .split('-')
+        .map(|offset| match offset.parse::<u64>() {
+            Ok(offset) if offset > max => max.to_string(),
+            _ => offset.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn rate_limit_headers(rule: &RateLimitWindow, hits: usize, restricted_for: Duration) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let values = [
+        ("x-rate-limit-policy", RATE_LIMIT_POLICY.to_string()),
+        ("x-rate-limit-rules", "ip".to_string()),
+        (
+            "x-rate-limit-ip",
+            format!(
+                "{}:{}:{}",
+                rule.max_hits,
+                rule.period.as_secs(),
+                rule.penalty.as_secs()
+            ),
+        ),
+        (
+            "x-rate-limit-ip-state",
+            format!(
+                "{}:{}:{}",
+                hits,
+                rule.period.as_secs(),
+                restricted_for.as_secs()
+            ),
+        ),
+    ];
+
+    for (name, value) in values {
+        headers.insert(name, HeaderValue::from_str(&value).unwrap());
+    }
+
+    headers
+}
+
+#[derive(Debug, Deserialize)]
+struct OAuthTokenRequest {
+    client_id: String,
+    client_secret: String,
+    grant_type: String,
+    scope: Option<String>,
+}
+
+async fn oauth_token(
+    State(river): State<Arc<MockRiver>>,
+    Form(request): Form<OAuthTokenRequest>,
+) -> Response {
+    if request.grant_type != "client_credentials" {
+        return oauth_error(StatusCode::BAD_REQUEST, "unsupported_grant_type");
+    }
+
+    if let Some((client_id, client_secret)) = &river.config.credentials {
+        if request.client_id.ne(client_id) || request.client_secret.ne(client_secret) {
+            return oauth_error(StatusCode::UNAUTHORIZED, "invalid_client");
+        }
+    }
+
+    let mut state = river.state.lock().unwrap();
+    let access_token = format!("mock-token-{}", state.tokens.len() + 1);
+    state.tokens.insert(access_token.clone(), Instant::now());
+
+    axum::Json(serde_json::json!({
+        "access_token": access_token,
+        "expires_in": river.config.token_expires_in,
+        "token_type": "bearer",
+        "scope": request.scope.unwrap_or_else(|| "service:psapi".into()),
+        "username": request.client_id,
+        "sub": request.client_id,
+    }))
+    .into_response()
+}
+
+fn oauth_error(status: StatusCode, error: &str) -> Response {
+    (status, axum::Json(serde_json::json!({ "error": error }))).into_response()
+}
+
+async fn get_stats(State(river): State<Arc<MockRiver>>) -> Response {
+    match &river.first_change_id {
+        Some(change_id) => {
+            axum::Json(serde_json::json!({ "next_change_id": change_id })).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "No pages recorded").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{clamp_offsets, find_first_change_id, read_every_from_env, MockRiverConfig};
+
+    #[test]
+    fn test_find_first_change_id() {
+        let pages = HashMap::from([
+            (
+                "2-2-2-2-2".to_string(),
+                br#"{"next_change_id": "3-3-3-3-3", "stashes": []}"#.to_vec(),
+            ),
+            (
+                "1-1-1-1-1".to_string(),
+                br#"{"next_change_id": "2-2-2-2-2", "stashes": []}"#.to_vec(),
+            ),
+        ]);
+
+        assert_eq!(find_first_change_id(&pages), Some("1-1-1-1-1".into()));
+    }
+
+    #[test]
+    fn test_clamp_offsets() {
+        assert_eq!(clamp_offsets("1-20-3-40-5", 10), "1-10-3-10-5");
+        assert_eq!(clamp_offsets("1-2-3-4-5", 10), "1-2-3-4-5");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_every_zeroth_request_is_rejected() {
+        MockRiverConfig::new("pages").with_unavailable_every(0);
+    }
+
+    #[test]
+    fn test_every_zeroth_request_is_rejected_from_env() {
+        std::env::set_var("MOCK_RIVER_TEST_EVERY", "0");
+        assert!(read_every_from_env("MOCK_RIVER_TEST_EVERY").is_err());
+        std::env::set_var("MOCK_RIVER_TEST_EVERY", "3");
+        assert_eq!(read_every_from_env("MOCK_RIVER_TEST_EVERY"), Ok(Some(3)));
+    }
+}
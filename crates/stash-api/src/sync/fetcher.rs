@@ -1,145 +1,118 @@
 use std::{
-    error::Error,
-    io::{BufReader, Read},
-    sync::mpsc::{Receiver, Sender},
-    time::Duration,
+    io::Read,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc,
+    },
 };
 
-use crate::common::{parse::parse_change_id_from_bytes, poe_api::parse_rate_limit_timer};
-use ureq::Response;
-
 use crate::{
     common::{
-        poe_api::{get_oauth_token_sync, user_agent, OAuthResponse},
+        indexing::{IndexerCore, IndexerEvent, Prefetch, Recovery, EMPTY_PAGE_DELAY},
+        stream::StashReader,
         ChangeId,
     },
-    sync::worker::WorkerTask,
+    sync::worker::{PageReader, WorkerTask},
+    Error,
 };
 
 use super::scheduler::SchedulerMessage;
 
 pub(crate) enum FetcherMessage {
     Task(FetchTask),
+    /// The worker failed to read the page of the task.
+    Failed(FetchTask, Error),
     Stop,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct FetchTask {
     change_id: ChangeId,
+    previous_change_id: ChangeId,
+    /// How many attempts at the page failed so far.
+    failed_attempts: u32,
+    /// Whether the following page has been scheduled already, ie. because the worker failed to
+    /// read the page after its next change id.
+    is_refetch: bool,
 }
 
 impl FetchTask {
+    /// The very first page of the river follows itself.
     pub(crate) fn new(change_id: ChangeId) -> Self {
-        Self { change_id }
-    }
-
-    pub(crate) fn retry(self) -> Option<Self> {
-        Some(FetchTask { ..self })
+        Self {
+            previous_change_id: change_id.clone(),
+            change_id,
+            failed_attempts: 0,
+            is_refetch: false,
+        }
     }
-}
 
-#[derive(Debug)]
-enum FetcherError {
-    #[allow(dead_code)]
-    HttpError {
-        status: u16,
-    },
-    Transport,
-    ServiceUnavailable,
-    RateLimited(Duration),
-    ParseError,
-}
-
-impl std::fmt::Display for FetcherError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Debug::fmt(&self, f)
+    /// Requests a page again that the worker failed to read.
+    pub(crate) fn refetch(
+        change_id: ChangeId,
+        previous_change_id: ChangeId,
+        failed_attempts: u32,
+    ) -> Self {
+        Self {
+            change_id,
+            previous_change_id,
+            failed_attempts,
+            is_refetch: true,
+        }
     }
 }
 
-impl Error for FetcherError {}
-
 pub(crate) fn start_fetcher(
     fetcher_rx: Receiver<FetcherMessage>,
     scheduler_tx: Sender<SchedulerMessage>,
+    core: Arc<IndexerCore>,
 ) -> std::thread::JoinHandle<()> {
     std::thread::spawn(move || {
-        let client_id = std::env::var("CLIENT_ID").unwrap();
-        let client_secret = std::env::var("CLIENT_SECRET").unwrap();
-
-        let oauth_response = match get_oauth_token_sync(&client_id, &client_secret) {
-            Ok(oauth) => oauth,
-            Err(e) => {
-                tracing::error!(
-                    "fetcher: encountered error during oauth token retrieval {}",
-                    e.to_string(),
-                );
-                scheduler_tx.send(SchedulerMessage::Stop).unwrap();
-                return;
-            }
-        };
-
-        while let Ok(FetcherMessage::Task(task)) = fetcher_rx.recv() {
-            std::thread::sleep(Duration::from_millis(500));
-
-            let start = std::time::Instant::now();
-            tracing::debug!("Requesting {}", task.change_id);
-
-            match fetch_chunk(&task, &client_id, &oauth_response).and_then(parse_chunk) {
-                Ok((decoder, change_id_buffer, next_change_id)) => {
-                    tracing::debug!(
-                        "fetcher: Took {}ms to read next id: {}",
-                        start.elapsed().as_millis(),
-                        next_change_id
+        while let Ok(message) = fetcher_rx.recv() {
+            let (mut task, e) = match message {
+                FetcherMessage::Task(task) => match fetch(&core, task, &scheduler_tx) {
+                    Some(failure) => failure,
+                    None => continue,
+                },
+                // Failures of the worker count against the retry budget of the page as well
+                FetcherMessage::Failed(task, e) => (task, e),
+                FetcherMessage::Stop => break,
+            };
+
+            let recovery = core.recovery(&e, &mut task.failed_attempts);
+            scheduler_tx
+                .send(SchedulerMessage::Done(IndexerEvent::Error(e.clone())))
+                .unwrap();
+
+            match recovery {
+                Recovery::Retry(delay) => {
+                    tracing::info!(
+                        "fetcher: Rescheduling {} in {}ms due to {}",
+                        task.change_id,
+                        delay.as_millis(),
+                        e
+                    );
+                    std::thread::sleep(delay);
+                    scheduler_tx.send(SchedulerMessage::Fetch(task)).unwrap();
+                }
+                Recovery::Exhausted(attempts) => {
+                    tracing::error!(
+                        "fetcher: Giving up after {} attempts due to {}",
+                        attempts,
+                        e
                     );
-
-                    scheduler_tx
-                        .send(SchedulerMessage::Fetch(FetchTask {
-                            change_id: next_change_id.clone(),
-                        }))
-                        .unwrap();
-
                     scheduler_tx
-                        .send(SchedulerMessage::Work(WorkerTask {
-                            reader: Box::new(decoder),
-                            fetch_partial: change_id_buffer,
-                            change_id: task.change_id.clone(),
+                        .send(SchedulerMessage::Done(IndexerEvent::RetriesExhausted {
+                            change_id: task.change_id,
+                            attempts,
                         }))
                         .unwrap();
-
-                    if next_change_id.eq(&task.change_id) {
-                        std::thread::sleep(std::time::Duration::from_secs(4));
-                    }
-                }
-                Err(FetcherError::HttpError { status: 403 }) => {
-                    tracing::error!("fetcher: Received 403 Forbidden - cannot access API. Please check your API credentials");
                     scheduler_tx.send(SchedulerMessage::Stop).unwrap();
                 }
-                Err(FetcherError::HttpError { status: 401 }) => {
-                    tracing::error!("fetcher: Received 401 Unauthorized");
+                Recovery::Stop => {
+                    tracing::error!("fetcher: Stopping due to {}", e);
                     scheduler_tx.send(SchedulerMessage::Stop).unwrap();
                 }
-                Err(
-                    e @ (FetcherError::HttpError { .. }
-                    | FetcherError::ParseError
-                    | FetcherError::Transport),
-                ) => {
-                    tracing::error!("fetcher: Encountered error {}", e);
-                    reschedule_task(&scheduler_tx, task);
-                }
-                Err(FetcherError::ServiceUnavailable) => {
-                    tracing::error!("fetcher: Service Unavailable - Retrying in 60s");
-                    scheduler_tx
-                        .send(SchedulerMessage::RateLimited(Duration::from_secs(60)))
-                        .unwrap();
-                    reschedule_task(&scheduler_tx, task);
-                }
-                Err(FetcherError::RateLimited(timer)) => {
-                    tracing::info!("fetcher: Rate limit reached");
-                    scheduler_tx
-                        .send(SchedulerMessage::RateLimited(timer))
-                        .unwrap();
-                    reschedule_task(&scheduler_tx, task);
-                }
             }
         }
 
@@ -147,100 +120,92 @@ pub(crate) fn start_fetcher(
     })
 }
 
-fn reschedule_task(scheduler_tx: &Sender<SchedulerMessage>, task: FetchTask) {
-    match task.retry() {
-        Some(t) => {
-            tracing::info!("fetcher: Rescheduling {} in 1s", t.change_id);
-            std::thread::sleep(Duration::from_secs(1));
-            scheduler_tx.send(SchedulerMessage::Fetch(t)).unwrap();
+/// Fetches the page of `task` and hands it to the worker, scheduling the following page unless
+/// it has been already. Returns the task along with the error if it failed.
+fn fetch(
+    core: &IndexerCore,
+    task: FetchTask,
+    scheduler_tx: &Sender<SchedulerMessage>,
+) -> Option<(FetchTask, Error)> {
+    let start = std::time::Instant::now();
+    tracing::debug!("Requesting {}", task.change_id);
+
+    match fetch_page(core, &task.change_id, scheduler_tx) {
+        Ok(Some((next_change_id, reader))) => {
+            tracing::debug!(
+                "fetcher: Took {}ms to read next id: {}",
+                start.elapsed().as_millis(),
+                next_change_id
+            );
+
+            if !task.is_refetch {
+                scheduler_tx
+                    .send(SchedulerMessage::Fetch(FetchTask {
+                        change_id: next_change_id,
+                        previous_change_id: task.change_id.clone(),
+                        failed_attempts: 0,
+                        is_refetch: false,
+                    }))
+                    .unwrap();
+            }
+
+            scheduler_tx
+                .send(SchedulerMessage::Work(Box::new(WorkerTask {
+                    change_id: task.change_id,
+                    previous_change_id: task.previous_change_id,
+                    failed_attempts: task.failed_attempts,
+                    reader,
+                })))
+                .unwrap();
+            None
         }
-        None => {
-            scheduler_tx.send(SchedulerMessage::Stop).unwrap();
+        Ok(None) => {
+            tracing::info!("fetcher: Rescheduling in 4s due to empty response");
+            std::thread::sleep(EMPTY_PAGE_DELAY);
+            scheduler_tx.send(SchedulerMessage::Fetch(task)).unwrap();
+            None
         }
+        Err(e) => Some((task, e)),
     }
 }
 
-fn parse_chunk(
-    response: Response,
-) -> Result<(BufReader<impl Read + Send>, [u8; 80], ChangeId), FetcherError> {
-    let reader = response.into_reader();
-    let mut decoder = BufReader::new(reader);
-    let mut next_id_buffer = [0; 80];
-
-    match decoder.read_exact(&mut next_id_buffer) {
-        Ok(_) => {
-            let next_id = parse_change_id_from_bytes(&next_id_buffer)
-                .map_err(|_| FetcherError::ParseError)?;
-            Ok((decoder, next_id_buffer, next_id))
-        }
-        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
-            tracing::error!("fetcher: UnexpectedEof: {:?}", next_id_buffer);
-            Err(FetcherError::ParseError)
-        }
-        Err(err) => {
-            tracing::error!("fetcher: gzip decoding failed: {}", err);
-            Err(FetcherError::ParseError)
-        }
+/// Requests a single page and reads it up to the next change id. Returns `None` if the page is
+/// empty, ie. because we reached the head of the river, and has to be requested again.
+fn fetch_page(
+    core: &IndexerCore,
+    change_id: &ChangeId,
+    scheduler_tx: &Sender<SchedulerMessage>,
+) -> Result<Option<(ChangeId, PageReader)>, Error> {
+    let access_token = core.token_provider().access_token_sync()?;
+
+    // Pace requests so that we stay within every rate limit window the API told us about
+    while let Err(wait_time) = core.try_acquire() {
+        tracing::debug!("fetcher: Throttling for {}ms", wait_time.as_millis());
+        let _ = scheduler_tx.send(SchedulerMessage::RateLimited(wait_time));
+        std::thread::sleep(wait_time);
     }
-}
 
-fn fetch_chunk(
-    task: &FetchTask,
-    client_id: &str,
-    oauth_response: &OAuthResponse,
-) -> Result<Response, FetcherError> {
-    let url = format!(
-        "https://api.pathofexile.com/public-stash-tabs?id={}",
-        task.change_id
-    );
-
-    let request = ureq::request("GET", &url)
-        .set("Accept", "application/json")
-        .set("User-Agent", user_agent(client_id).as_str())
-        .set(
-            "Authorization",
-            format!("Bearer {}", &oauth_response.access_token).as_str(),
-        );
-    let response = request.call();
-
-    response.map_err(|e| match e {
-        ureq::Error::Status(status, ref response) => {
-            tracing::error!("fetcher: HTTP error {}", status);
-            tracing::error!("fetcher: HTTP response: {:?}", response);
-
-            match status {
-                429 => {
-                    let wait_time = parse_rate_limit_timer(response.header("x-rate-limit-ip"));
-                    FetcherError::RateLimited(wait_time)
-                }
-                503 => FetcherError::ServiceUnavailable,
-                _ => FetcherError::HttpError { status },
-            }
-        }
-        ureq::Error::Transport(_) => FetcherError::Transport,
-    })
-}
+    let mut request = ureq::request("GET", &core.url(change_id));
+    for (name, value) in core.headers(&access_token) {
+        request = request.set(name, &value);
+    }
 
-#[cfg(test)]
-mod test {
-    use std::time::Duration;
-
-    use super::parse_rate_limit_timer;
-
-    #[test]
-    fn test_parse_rate_limit_timer() {
-        assert_eq!(parse_rate_limit_timer(None), Duration::from_secs(60));
-        assert_eq!(
-            parse_rate_limit_timer(Some("something")),
-            Duration::from_secs(60)
-        );
-        assert_eq!(
-            parse_rate_limit_timer(Some("_:_:abc")),
-            Duration::from_secs(60)
-        );
-        assert_eq!(
-            parse_rate_limit_timer(Some("_:_:120")),
-            Duration::from_secs(120)
-        );
+    let requested_at = std::time::Instant::now();
+    let response = match request.call() {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(ureq::Error::Transport(e)) => return Err(Error::Transport(e.to_string())),
+    };
+
+    let content_encoding =
+        core.on_response(change_id, &access_token, response.status(), |name| {
+            response.header(name)
+        })?;
+
+    let body: Box<dyn Read + Send> = Box::new(response.into_reader());
+    let mut reader = StashReader::new(change_id.clone(), body)
+        .with_content_encoding(content_encoding, requested_at);
+    match reader.prefetch()? {
+        Prefetch::Next(next_change_id) => Ok(Some((next_change_id, reader))),
+        Prefetch::Empty | Prefetch::Pending => Ok(None),
     }
 }
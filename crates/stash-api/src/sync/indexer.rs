@@ -1,18 +1,25 @@
-use std::time::Duration;
-use std::{sync::mpsc::Receiver, sync::mpsc::Sender};
+use std::sync::{mpsc::Receiver, mpsc::Sender, Arc};
 
 use crate::sync::scheduler::SchedulerMessage;
 use crate::{
-    common::{poe_ninja_client::PoeNinjaClient, ChangeId, StashTabResponse},
+    common::{
+        indexing::IndexerCore, poe_ninja_client::PoeNinjaClient, retry::RetryPolicy, ChangeId,
+        Endpoints,
+    },
     sync::fetcher::FetchTask,
+    Error,
 };
 
 use super::scheduler::start_scheduler;
 
+pub use crate::common::indexing::IndexerEvent;
+
 #[derive(Default)]
 pub struct Indexer {
     pub(crate) scheduler_tx: Option<Sender<SchedulerMessage>>,
     pub(crate) is_stopping: bool,
+    pub(crate) endpoints: Endpoints,
+    pub(crate) retry_policy: RetryPolicy,
 }
 
 impl Indexer {
@@ -20,14 +27,25 @@ impl Indexer {
         Self::default()
     }
 
+    /// Overrides the endpoints to fetch river pages, OAuth tokens and the latest change id from.
+    pub fn with_endpoints(mut self, endpoints: Endpoints) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// Overrides how often and how long to wait before failed requests are sent again.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
     pub fn stop(&mut self) {
         self.is_stopping = true;
         tracing::info!("Stopping indexer");
-        self.scheduler_tx
-            .as_ref()
-            .expect("indexer: Missing ref to scheduler_rx")
-            .send(SchedulerMessage::Stop)
-            .expect("indexer: Failed to send SchedulerMessage::Stop");
+        // The scheduler may have stopped on its own already, ie. after running out of retries
+        if let Some(scheduler_tx) = &self.scheduler_tx {
+            let _ = scheduler_tx.send(SchedulerMessage::Stop);
+        }
     }
 
     pub fn is_stopping(&self) -> bool {
@@ -35,17 +53,24 @@ impl Indexer {
     }
 
     /// Start the indexer with a given change_id
-    pub fn start_with_id(&mut self, change_id: ChangeId) -> IndexerResult {
-        tracing::info!("Resuming at change id: {}", change_id);
-        self.start(change_id)
+    ///
+    /// Fails if no OAuth token can be obtained for the given credentials.
+    pub fn start_at_change_id(
+        &mut self,
+        client_id: String,
+        client_secret: String,
+        change_id: ChangeId,
+    ) -> IndexerResult {
+        tracing::info!("Starting at change id: {}", change_id);
+        self.start(client_id, client_secret, change_id)
     }
 
     /// Start the indexer with the latest change_id from poe.ninja
-    pub fn start_with_latest(&mut self) -> IndexerResult {
-        let latest_change_id = PoeNinjaClient::fetch_latest_change_id()
-            .expect("Fetching lastest change_id from poe.ninja failed");
+    pub fn start_with_latest(&mut self, client_id: String, client_secret: String) -> IndexerResult {
+        let latest_change_id =
+            PoeNinjaClient::new(self.endpoints.poe_ninja_stats()).fetch_latest_change_id()?;
         tracing::info!("Fetched latest change id: {}", latest_change_id);
-        self.start(latest_change_id)
+        self.start(client_id, client_secret, latest_change_id)
     }
 
     /// Starts the indexer instance.
@@ -59,8 +84,17 @@ impl Indexer {
     /// b) another thread with a work queue to deserialize the full response data
     ///    as StashTabResponse structs and sending it to the user of the indexer
     ///    instance.
-    fn start(&mut self, change_id: ChangeId) -> IndexerResult {
-        let (indexer_rx, scheduler_tx) = start_scheduler();
+    fn start(
+        &mut self,
+        client_id: String,
+        client_secret: String,
+        change_id: ChangeId,
+    ) -> IndexerResult {
+        let core = IndexerCore::new(self.endpoints.clone(), client_id, client_secret)
+            .with_retry_policy(self.retry_policy.clone());
+        core.token_provider().access_token_sync()?;
+
+        let (indexer_rx, scheduler_tx) = start_scheduler(Arc::new(core));
 
         scheduler_tx
             .send(SchedulerMessage::Fetch(FetchTask::new(change_id)))
@@ -68,19 +102,8 @@ impl Indexer {
 
         self.scheduler_tx = Some(scheduler_tx);
 
-        indexer_rx
+        Ok(indexer_rx)
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum IndexerMessage {
-    Tick {
-        payload: StashTabResponse,
-        change_id: ChangeId,
-        created_at: std::time::SystemTime,
-    },
-    RateLimited(Duration),
-    Stop,
-}
-
-type IndexerResult = Receiver<IndexerMessage>;
+type IndexerResult = Result<Receiver<IndexerEvent>, Error>;
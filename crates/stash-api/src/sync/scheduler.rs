@@ -1,9 +1,15 @@
 use std::{
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
     time::Duration,
 };
 
-use crate::sync::indexer::IndexerMessage;
+use crate::{
+    common::indexing::{IndexerCore, IndexerEvent},
+    Error,
+};
 
 use super::{
     fetcher::{start_fetcher, FetchTask, FetcherMessage},
@@ -12,64 +18,61 @@ use super::{
 
 pub(crate) enum SchedulerMessage {
     Fetch(FetchTask),
-    Work(WorkerTask),
-    Done(IndexerMessage),
+    Work(Box<WorkerTask>),
+    /// The worker failed to read a page, which the fetcher handles like its own failures.
+    Failed(FetchTask, Error),
+    Done(IndexerEvent),
     RateLimited(Duration),
     Stop,
 }
 
-pub(crate) fn start_scheduler() -> (Receiver<IndexerMessage>, Sender<SchedulerMessage>) {
+pub(crate) fn start_scheduler(
+    core: Arc<IndexerCore>,
+) -> (Receiver<IndexerEvent>, Sender<SchedulerMessage>) {
     // Channel scheduler -> fetcher/worker
     let (scheduler_fetcher_tx, fetcher_rx) = channel::<FetcherMessage>();
     let (scheduler_worker_tx, worker_rx) = channel::<WorkerMessage>();
     // Channel fetcher/worker -> scheduler
     let (scheduler_tx, scheduler_rx) = channel::<SchedulerMessage>();
     // Channel scheduler -> caller
-    let (indexer_tx, indexer_rx) = channel::<IndexerMessage>();
+    let (indexer_tx, indexer_rx) = channel::<IndexerEvent>();
 
     let ret = (indexer_rx, scheduler_tx.clone());
 
     std::thread::spawn(move || {
-        let fetcher_handle = start_fetcher(fetcher_rx, scheduler_tx.clone());
+        let fetcher_handle = start_fetcher(fetcher_rx, scheduler_tx.clone(), core);
         let worker_handle = start_worker(worker_rx, scheduler_tx.clone());
 
+        // Stops once asked to or once the fetcher, worker or caller went away
         while let Ok(msg) = scheduler_rx.recv() {
-            match msg {
-                SchedulerMessage::Stop => {
-                    let _ = scheduler_fetcher_tx.send(FetcherMessage::Stop);
-                    let _ = scheduler_worker_tx.send(WorkerMessage::Stop);
-                    break;
-                }
-                SchedulerMessage::Fetch(task) => {
-                    scheduler_fetcher_tx
-                        .send(FetcherMessage::Task(task))
-                        .expect("scheduler: Failed to send FetcherMessage::Task");
-                }
+            let sent = match msg {
+                SchedulerMessage::Stop => break,
+                SchedulerMessage::Fetch(task) => scheduler_fetcher_tx
+                    .send(FetcherMessage::Task(task))
+                    .is_ok(),
+                SchedulerMessage::Failed(task, e) => scheduler_fetcher_tx
+                    .send(FetcherMessage::Failed(task, e))
+                    .is_ok(),
                 SchedulerMessage::Work(task) => {
-                    scheduler_worker_tx
-                        .send(WorkerMessage::Task(task))
-                        .expect("scheduler: Failed to send WorkerMessage::Task");
+                    scheduler_worker_tx.send(WorkerMessage::Task(task)).is_ok()
                 }
                 SchedulerMessage::RateLimited(timer) => {
-                    indexer_tx
-                        .send(IndexerMessage::RateLimited(timer))
-                        .expect("scheduler: Failed to send IndexerMessage::RateLimited");
-                    std::thread::sleep(timer);
-                }
-                SchedulerMessage::Done(msg) => {
-                    indexer_tx
-                        .send(msg)
-                        .expect("scheduler: Failed to send IndexerMessage");
+                    indexer_tx.send(IndexerEvent::RateLimited(timer)).is_ok()
                 }
+                SchedulerMessage::Done(msg) => indexer_tx.send(msg).is_ok(),
+            };
+            if !sent {
+                tracing::debug!("scheduler: Receiver went away, stopping");
+                break;
             }
         }
 
+        let _ = scheduler_fetcher_tx.send(FetcherMessage::Stop);
+        let _ = scheduler_worker_tx.send(WorkerMessage::Stop);
         fetcher_handle.join().unwrap();
         worker_handle.join().unwrap();
 
-        indexer_tx
-            .send(IndexerMessage::Stop)
-            .expect("scheduler: Failed to send IndexerMessage::Stop");
+        let _ = indexer_tx.send(IndexerEvent::Stop);
 
         tracing::debug!("Shut down scheduler");
     });
@@ -1,24 +1,32 @@
 use std::{
-    io::{Read, Write},
+    io::Read,
     sync::mpsc::{Receiver, Sender},
 };
 
 use crate::{
-    common::{ChangeId, StashTabResponse},
-    sync::indexer::IndexerMessage,
+    common::{
+        encoding::TransferStats, indexing::IndexerEvent, stream::StashReader, ChangeId,
+        StashTabResponse,
+    },
+    Error,
 };
 
-use super::scheduler::SchedulerMessage;
+use super::{fetcher::FetchTask, scheduler::SchedulerMessage};
+
+/// A page of which the fetcher already read up to the next change id.
+pub(crate) type PageReader = StashReader<Box<dyn Read + Send>>;
 
 pub(crate) enum WorkerMessage {
-    Task(WorkerTask),
+    Task(Box<WorkerTask>),
     Stop,
 }
 
 pub(crate) struct WorkerTask {
-    pub(crate) fetch_partial: [u8; 80],
     pub(crate) change_id: ChangeId,
-    pub(crate) reader: Box<dyn Read + Send>,
+    pub(crate) previous_change_id: ChangeId,
+    /// How many attempts at the page failed before this one.
+    pub(crate) failed_attempts: u32,
+    pub(crate) reader: PageReader,
 }
 
 pub(crate) fn start_worker(
@@ -26,35 +34,29 @@ pub(crate) fn start_worker(
     scheduler_tx: Sender<SchedulerMessage>,
 ) -> std::thread::JoinHandle<()> {
     std::thread::spawn(move || {
-        let mut buffer = Vec::new();
         while let Ok(message) = worker_rx.recv() {
-            buffer.clear();
-
             match message {
-                WorkerMessage::Task(mut task) => {
-                    let start = std::time::Instant::now();
-                    buffer.write_all(&task.fetch_partial).unwrap();
-                    task.reader.read_to_end(&mut buffer).unwrap();
-                    tracing::debug!(
-                        "worker: Took {}ms to read body",
-                        start.elapsed().as_millis()
-                    );
-
-                    let start = std::time::Instant::now();
-                    let deserialized = serde_json::from_slice::<StashTabResponse>(&buffer)
-                        .expect("Deserialization of body failed");
-                    tracing::debug!(
-                        "worker: Took {}ms to deserialize body",
-                        start.elapsed().as_millis()
-                    );
-
-                    scheduler_tx
-                        .send(SchedulerMessage::Done(IndexerMessage::Tick {
-                            payload: deserialized,
-                            change_id: task.change_id,
+                WorkerMessage::Task(task) => {
+                    let change_id = task.change_id;
+                    let previous_change_id = task.previous_change_id;
+                    let message = match process(task.reader) {
+                        Ok((response, transfer)) => SchedulerMessage::Done(IndexerEvent::Tick {
+                            response,
+                            change_id,
+                            previous_change_id,
                             created_at: std::time::SystemTime::now(),
-                        }))
-                        .expect("worker: Sending IndexerMessage::Tick failed");
+                            transfer,
+                        }),
+                        // The fetcher requests the page again, as it does when fetching it fails
+                        Err(e) => SchedulerMessage::Failed(
+                            FetchTask::refetch(change_id, previous_change_id, task.failed_attempts),
+                            e,
+                        ),
+                    };
+
+                    if scheduler_tx.send(message).is_err() {
+                        break;
+                    }
                 }
                 WorkerMessage::Stop => break,
             }
@@ -63,3 +65,25 @@ pub(crate) fn start_worker(
         tracing::debug!("worker: Shutting down");
     })
 }
+
+fn process(mut reader: PageReader) -> Result<(StashTabResponse, TransferStats), Error> {
+    let start = std::time::Instant::now();
+    let stashes = reader.by_ref().collect::<Result<Vec<_>, _>>()?;
+    let transfer = *reader.transfer();
+    let next_change_id = reader.finish()?;
+    tracing::debug!(
+        "worker: Took {}ms to read and deserialize body, downloaded {} bytes ({} decoded, {})",
+        start.elapsed().as_millis(),
+        transfer.compressed_bytes,
+        transfer.decoded_bytes,
+        transfer.content_encoding
+    );
+
+    Ok((
+        StashTabResponse {
+            next_change_id: next_change_id.to_string(),
+            stashes,
+        },
+        transfer,
+    ))
+}
@@ -0,0 +1,673 @@
+#![cfg(all(feature = "mock-server", feature = "async", feature = "sync"))]
+
+use std::{collections::HashSet, str::FromStr, time::Duration};
+
+use stash_api::{
+    common::{encoding::ContentEncoding, ChangeId, Endpoints},
+    mock::{MockRiver, MockRiverConfig},
+};
+
+const FIRST_CHANGE_ID: &str = "2000000000-2000000001-2000000002-2000000003-2000000004";
+const RECORDED_CHANGE_IDS: [&str; 3] = [
+    FIRST_CHANGE_ID,
+    "2000000010-2000000011-2000000012-2000000013-2000000014",
+    "2000000020-2000000021-2000000022-2000000023-2000000024",
+];
+
+fn start_mock_river() -> Endpoints {
+    start_mock_river_with(|config| config)
+}
+
+fn start_mock_river_with(configure: impl FnOnce(MockRiverConfig) -> MockRiverConfig) -> Endpoints {
+    let config = configure(
+        MockRiverConfig::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/river"))
+            .with_credentials("client".into(), "secret".into()),
+    );
+    let addr = MockRiver::new(config).expect("loading river pages").spawn();
+    Endpoints::new().with_base_url(&format!("http://{addr}"))
+}
+
+#[tokio::test]
+async fn test_async_indexer_replays_recorded_river() {
+    use futures::StreamExt;
+    use stash_api::r#async::indexer::{Indexer, IndexerEvent};
+
+    let indexer = Indexer::new().with_endpoints(start_mock_river());
+    let mut stream = indexer
+        .start_at_change_id(
+            "client".into(),
+            "secret".into(),
+            ChangeId::from_str(FIRST_CHANGE_ID).unwrap(),
+        )
+        .await
+        .expect("starting indexer");
+
+    let mut change_ids = vec![];
+    let mut previous_change_ids = vec![];
+    while change_ids.len() < RECORDED_CHANGE_IDS.len() {
+        let event = tokio::time::timeout(Duration::from_secs(10), stream.next())
+            .await
+            .expect("receiving IndexerEvent in time");
+
+        if let Some(IndexerEvent::Tick {
+            change_id,
+            previous_change_id,
+            transfer,
+            ..
+        }) = event
+        {
+            // The mock river gzips pages for clients accepting it
+            assert_eq!(transfer.content_encoding, ContentEncoding::Gzip);
+            assert!(transfer.compressed_bytes < transfer.decoded_bytes);
+            change_ids.push(change_id.to_string());
+            previous_change_ids.push(previous_change_id.to_string());
+        }
+    }
+
+    assert_eq!(change_ids, RECORDED_CHANGE_IDS);
+    assert_eq!(
+        previous_change_ids,
+        [
+            FIRST_CHANGE_ID,
+            RECORDED_CHANGE_IDS[0],
+            RECORDED_CHANGE_IDS[1]
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_async_indexer_catches_up_shard_by_shard() {
+    use futures::StreamExt;
+    use stash_api::r#async::indexer::{Indexer, IndexerEvent};
+
+    // Only shard 1 is behind the head of the mock river, which is the first recorded page. The
+    // recording contains the page of that shard with all other shards pinned.
+    let start = "2000000000-1999999991-2000000002-2000000003-2000000004";
+    let indexer = Indexer::new()
+        .with_endpoints(start_mock_river())
+        .with_parallel_catch_up(true);
+    let mut stream = indexer
+        .start_at_change_id(
+            "client".into(),
+            "secret".into(),
+            ChangeId::from_str(start).unwrap(),
+        )
+        .await
+        .expect("starting indexer");
+
+    let mut ticks = vec![];
+    while ticks.len() < 1 + RECORDED_CHANGE_IDS.len() {
+        let event = tokio::time::timeout(Duration::from_secs(10), stream.next())
+            .await
+            .expect("receiving IndexerEvent in time");
+
+        if let Some(IndexerEvent::Tick {
+            change_id,
+            previous_change_id,
+            response,
+            ..
+        }) = event
+        {
+            ticks.push((
+                previous_change_id.to_string(),
+                change_id.to_string(),
+                response.next_change_id,
+                response.stashes.len(),
+            ));
+        }
+    }
+
+    assert_eq!(
+        ticks[0],
+        (
+            start.to_string(),
+            start.to_string(),
+            FIRST_CHANGE_ID.into(),
+            1
+        )
+    );
+    let change_ids = ticks[1..]
+        .iter()
+        .map(|(_, change_id, _, _)| change_id.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(change_ids, RECORDED_CHANGE_IDS);
+    assert_eq!(ticks[1].0, start);
+}
+
+#[tokio::test]
+async fn test_async_indexer_catches_up_serially_if_offsets_are_clamped() {
+    use futures::StreamExt;
+    use stash_api::r#async::indexer::{Indexer, IndexerEvent};
+
+    // Only shard 1 is behind the head of the mock river. Its pinned request is clamped to an
+    // unrecorded change id, whose empty page does not move the shard on.
+    let start = "2000000005-1999999991-2000000002-2000000003-2000000004";
+    let indexer = Indexer::new()
+        .with_endpoints(start_mock_river_with(|config| {
+            config.with_max_offset(3000000000)
+        }))
+        .with_parallel_catch_up(true);
+    let mut stream = indexer
+        .start_at_change_id(
+            "client".into(),
+            "secret".into(),
+            ChangeId::from_str(start).unwrap(),
+        )
+        .await
+        .expect("starting indexer");
+
+    let tick = loop {
+        let event = tokio::time::timeout(Duration::from_secs(10), stream.next())
+            .await
+            .expect("receiving IndexerEvent in time");
+
+        if let Some(IndexerEvent::Tick {
+            change_id,
+            previous_change_id,
+            response,
+            ..
+        }) = event
+        {
+            break (
+                previous_change_id.to_string(),
+                change_id.to_string(),
+                response.next_change_id,
+                response.stashes.len(),
+            );
+        }
+    };
+
+    // The page of the start is read in river order
+    assert_eq!(
+        tick,
+        (
+            start.to_string(),
+            start.to_string(),
+            "2000000005-2000000009-2000000002-2000000003-2000000004".into(),
+            1
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_async_indexer_reports_rate_limits() {
+    use futures::StreamExt;
+    use stash_api::{
+        common::rate_limit::RateLimitWindow,
+        r#async::indexer::{Indexer, IndexerEvent},
+        Error,
+    };
+
+    let endpoints = start_mock_river_with(|config| {
+        config
+            .with_rate_limit(RateLimitWindow {
+                max_hits: 45,
+                period: Duration::from_secs(60),
+                penalty: Duration::from_secs(1),
+            })
+            .with_rate_limited_every(2)
+    });
+    let mut stream = Indexer::new()
+        .with_endpoints(endpoints)
+        .start_at_change_id(
+            "client".into(),
+            "secret".into(),
+            ChangeId::from_str(FIRST_CHANGE_ID).unwrap(),
+        )
+        .await
+        .expect("starting indexer");
+
+    let mut change_ids = HashSet::new();
+    let mut rate_limited = 0;
+    while change_ids.len() < RECORDED_CHANGE_IDS.len() {
+        let event = tokio::time::timeout(Duration::from_secs(10), stream.next())
+            .await
+            .expect("receiving IndexerEvent in time");
+
+        match event {
+            Some(IndexerEvent::Tick { change_id, .. }) => {
+                change_ids.insert(change_id.to_string());
+            }
+            Some(IndexerEvent::Error(Error::RateLimited(_))) => rate_limited += 1,
+            Some(IndexerEvent::Error(e)) => panic!("Unexpected error {e}"),
+            _ => {}
+        }
+    }
+
+    assert!(rate_limited > 0);
+}
+
+#[tokio::test]
+async fn test_async_indexer_can_be_paused_and_stopped() {
+    use futures::StreamExt;
+    use stash_api::r#async::indexer::{Indexer, IndexerEvent, IndexerStream};
+
+    let mut stream = Indexer::new()
+        .with_endpoints(start_mock_river())
+        .with_max_in_flight(1)
+        .start_at_change_id(
+            "client".into(),
+            "secret".into(),
+            ChangeId::from_str(FIRST_CHANGE_ID).unwrap(),
+        )
+        .await
+        .expect("starting indexer");
+    let handle = stream.handle();
+    assert_eq!(handle.current_change_id().to_string(), FIRST_CHANGE_ID);
+
+    async fn next_tick(stream: &mut IndexerStream) -> String {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), stream.next())
+                .await
+                .expect("receiving IndexerEvent in time");
+            if let Some(IndexerEvent::Tick { change_id, .. }) = event {
+                return change_id.to_string();
+            }
+        }
+    }
+
+    assert_eq!(next_tick(&mut stream).await, RECORDED_CHANGE_IDS[0]);
+    handle.pause();
+    assert!(handle.is_paused());
+    handle.resume();
+    assert!(!handle.is_paused());
+    assert_eq!(next_tick(&mut stream).await, RECORDED_CHANGE_IDS[1]);
+    assert_eq!(
+        handle.current_change_id().to_string(),
+        RECORDED_CHANGE_IDS[2]
+    );
+
+    handle.stop();
+    let remaining = tokio::time::timeout(Duration::from_secs(10), stream.collect::<Vec<_>>())
+        .await
+        .expect("stream ends after stopping");
+    assert!(matches!(remaining.last(), Some(IndexerEvent::Stop)));
+    assert!(handle.is_stopped());
+}
+
+#[test]
+fn test_sync_indexer_rejects_invalid_credentials() {
+    use stash_api::{common::auth::AuthError, sync::indexer::Indexer, Error};
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let endpoints = {
+        let _guard = runtime.enter();
+        start_mock_river()
+    };
+
+    let result = Indexer::new().with_endpoints(endpoints).start_at_change_id(
+        "client".into(),
+        "wrong-secret".into(),
+        ChangeId::from_str(FIRST_CHANGE_ID).unwrap(),
+    );
+
+    assert!(matches!(
+        result,
+        Err(Error::Auth(AuthError::Rejected { status: 401, .. }))
+    ));
+}
+
+#[tokio::test]
+async fn test_async_indexer_rejects_invalid_credentials() {
+    use stash_api::{common::auth::AuthError, r#async::indexer::Indexer, Error};
+
+    let indexer = Indexer::new().with_endpoints(start_mock_river());
+    let result = indexer
+        .start_at_change_id(
+            "client".into(),
+            "wrong-secret".into(),
+            ChangeId::from_str(FIRST_CHANGE_ID).unwrap(),
+        )
+        .await;
+
+    assert_eq!(
+        result.err(),
+        Some(Error::Auth(AuthError::Rejected {
+            status: 401,
+            error: Some("invalid_client".into())
+        }))
+    );
+}
+
+#[tokio::test]
+async fn test_token_provider_refreshes_expiring_tokens() {
+    use stash_api::common::auth::TokenProvider;
+
+    // Refreshing 60s ahead of a 2s lifetime refreshes halfway through it instead
+    let endpoints = start_mock_river_with(|config| config.with_token_expires_in(2));
+    let provider = TokenProvider::new(endpoints.oauth_token(), "client".into(), "secret".into())
+        .with_refresh_ahead(Duration::from_secs(60));
+
+    let first = provider.access_token().await.unwrap();
+    assert_eq!(provider.access_token().await.unwrap(), first);
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_ne!(provider.access_token().await.unwrap(), first);
+}
+
+#[tokio::test]
+async fn test_mock_river_rejects_expired_tokens() {
+    use stash_api::common::auth::TokenProvider;
+
+    let endpoints = start_mock_river_with(|config| config.with_token_expires_in(1));
+    let provider = TokenProvider::new(endpoints.oauth_token(), "client".into(), "secret".into());
+    let token = provider.access_token().await.unwrap();
+
+    let url = endpoints.public_stash_tabs_url(&ChangeId::from_str(FIRST_CHANGE_ID).unwrap());
+    let fetch = || reqwest::Client::new().get(&url).bearer_auth(&token).send();
+    assert_eq!(fetch().await.unwrap().status(), 200);
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(fetch().await.unwrap().status(), 401);
+}
+
+#[tokio::test]
+async fn test_stash_stream_streams_recorded_page() {
+    use futures::StreamExt;
+    use stash_api::{common::auth::TokenProvider, r#async::stream::StashStream};
+
+    let endpoints = start_mock_river();
+    let provider = TokenProvider::new(endpoints.oauth_token(), "client".into(), "secret".into());
+    let change_id = ChangeId::from_str(FIRST_CHANGE_ID).unwrap();
+    let response = reqwest::Client::new()
+        .get(endpoints.public_stash_tabs_url(&change_id))
+        .bearer_auth(provider.access_token().await.unwrap())
+        .header("accept-encoding", ContentEncoding::ACCEPT)
+        .send()
+        .await
+        .unwrap();
+    let content_encoding = ContentEncoding::from_header(
+        response
+            .headers()
+            .get("content-encoding")
+            .and_then(|v| v.to_str().ok()),
+    )
+    .unwrap();
+
+    let mut stream = StashStream::from_response(change_id, response)
+        .with_content_encoding(content_encoding, std::time::Instant::now());
+    let first = stream.next().await.unwrap().unwrap();
+    assert!(!first.id.is_empty());
+    // The next change id precedes the stashes of a page
+    assert_eq!(
+        stream.next_change_id().map(ToString::to_string).as_deref(),
+        Some(RECORDED_CHANGE_IDS[1])
+    );
+
+    let mut stashes = 1;
+    while let Some(stash) = stream.next().await {
+        stash.unwrap();
+        stashes += 1;
+    }
+    assert!(stashes > 1);
+    assert_eq!(stream.transfer().content_encoding, ContentEncoding::Gzip);
+    assert_eq!(stream.finish().unwrap().to_string(), RECORDED_CHANGE_IDS[1]);
+}
+
+#[test]
+fn test_stash_reader_reads_recorded_page() {
+    use stash_api::common::{auth::TokenProvider, stream::StashReader};
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let endpoints = {
+        let _guard = runtime.enter();
+        start_mock_river()
+    };
+    let provider = TokenProvider::new(endpoints.oauth_token(), "client".into(), "secret".into());
+    let change_id = ChangeId::from_str(FIRST_CHANGE_ID).unwrap();
+    let response = ureq::get(&endpoints.public_stash_tabs_url(&change_id))
+        .set(
+            "Authorization",
+            &format!("Bearer {}", provider.access_token_sync().unwrap()),
+        )
+        .call()
+        .unwrap();
+
+    let mut reader = StashReader::new(change_id, response.into_reader());
+    assert!(reader.by_ref().map(Result::unwrap).count() > 1);
+    assert_eq!(reader.finish().unwrap().to_string(), RECORDED_CHANGE_IDS[1]);
+}
+
+#[tokio::test]
+async fn test_token_provider_requests_one_token_at_a_time() {
+    use stash_api::common::auth::TokenProvider;
+
+    let endpoints = start_mock_river();
+    let provider = TokenProvider::new(endpoints.oauth_token(), "client".into(), "secret".into());
+
+    // Every token request is answered with a new token
+    let tokens = futures::future::join_all((0..5).map(|_| provider.access_token())).await;
+    let first = tokens[0].as_ref().unwrap();
+    assert!(tokens.iter().all(|token| token.as_ref() == Ok(first)));
+}
+
+#[test]
+fn test_sync_indexer_replays_recorded_river() {
+    use stash_api::sync::indexer::{Indexer, IndexerEvent};
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let endpoints = {
+        let _guard = runtime.enter();
+        start_mock_river()
+    };
+
+    let mut indexer = Indexer::new().with_endpoints(endpoints);
+    let rx = indexer
+        .start_with_latest("client".into(), "secret".into())
+        .expect("starting indexer");
+
+    let mut change_ids = vec![];
+    let mut previous_change_ids = vec![];
+    while change_ids.len() < RECORDED_CHANGE_IDS.len() {
+        match rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(IndexerEvent::Tick {
+                change_id,
+                previous_change_id,
+                transfer,
+                ..
+            }) => {
+                assert_eq!(transfer.content_encoding, ContentEncoding::Gzip);
+                assert!(transfer.compressed_bytes < transfer.decoded_bytes);
+                change_ids.push(change_id.to_string());
+                previous_change_ids.push(previous_change_id.to_string());
+            }
+            Ok(_) => {}
+            Err(e) => panic!("Receiving IndexerEvent failed: {e}"),
+        }
+    }
+    indexer.stop();
+
+    assert_eq!(change_ids, RECORDED_CHANGE_IDS);
+    assert_eq!(
+        previous_change_ids,
+        [
+            FIRST_CHANGE_ID,
+            RECORDED_CHANGE_IDS[0],
+            RECORDED_CHANGE_IDS[1]
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_async_indexer_gives_up_after_retry_budget() {
+    use futures::StreamExt;
+    use stash_api::{
+        common::retry::{Backoff, RetryPolicy},
+        r#async::indexer::{Indexer, IndexerEvent},
+        Error,
+    };
+
+    let endpoints = start_mock_river_with(|config| config.with_unavailable_every(1));
+    let retry_policy = RetryPolicy::uniform(Backoff::new(
+        3,
+        Duration::from_millis(10),
+        Duration::from_millis(20),
+    ));
+    let stream = Indexer::new()
+        .with_endpoints(endpoints)
+        .with_retry_policy(retry_policy)
+        .start_at_change_id(
+            "client".into(),
+            "secret".into(),
+            ChangeId::from_str(FIRST_CHANGE_ID).unwrap(),
+        )
+        .await
+        .expect("starting indexer");
+
+    let events = tokio::time::timeout(Duration::from_secs(10), stream.collect::<Vec<_>>())
+        .await
+        .expect("stream ends after giving up");
+
+    let errors = events
+        .iter()
+        .filter(|event| {
+            matches!(
+                event,
+                IndexerEvent::Error(Error::HttpStatus { status: 503, .. })
+            )
+        })
+        .count();
+    assert_eq!(errors, 3);
+    assert!(matches!(
+        &events[events.len() - 2..],
+        [
+            IndexerEvent::RetriesExhausted { change_id, attempts: 3 },
+            IndexerEvent::Stop
+        ] if change_id.to_string() == FIRST_CHANGE_ID
+    ));
+}
+
+#[test]
+fn test_sync_indexer_gives_up_after_retry_budget() {
+    use stash_api::{
+        common::retry::{Backoff, RetryPolicy},
+        sync::indexer::{Indexer, IndexerEvent},
+    };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let endpoints = {
+        let _guard = runtime.enter();
+        start_mock_river_with(|config| config.with_unavailable_every(1))
+    };
+
+    let mut indexer =
+        Indexer::new()
+            .with_endpoints(endpoints)
+            .with_retry_policy(RetryPolicy::uniform(Backoff::new(
+                2,
+                Duration::from_millis(10),
+                Duration::from_millis(10),
+            )));
+    let rx = indexer
+        .start_at_change_id(
+            "client".into(),
+            "secret".into(),
+            ChangeId::from_str(FIRST_CHANGE_ID).unwrap(),
+        )
+        .expect("starting indexer");
+
+    let mut events = vec![];
+    loop {
+        match rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(IndexerEvent::Stop) => break,
+            Ok(event) => events.push(event),
+            Err(e) => panic!("Receiving IndexerEvent failed: {e}"),
+        }
+    }
+
+    assert!(matches!(
+        events.last(),
+        Some(IndexerEvent::RetriesExhausted { attempts: 2, .. })
+    ));
+}
+
+#[test]
+fn test_sync_indexer_fetches_truncated_pages_again() {
+    use stash_api::{
+        sync::indexer::{Indexer, IndexerEvent},
+        Error,
+    };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let endpoints = {
+        let _guard = runtime.enter();
+        start_mock_river_with(|config| config.with_truncated_every(2))
+    };
+
+    let mut indexer = Indexer::new().with_endpoints(endpoints);
+    let rx = indexer
+        .start_at_change_id(
+            "client".into(),
+            "secret".into(),
+            ChangeId::from_str(FIRST_CHANGE_ID).unwrap(),
+        )
+        .expect("starting indexer");
+
+    let mut change_ids = HashSet::new();
+    let mut errors = 0;
+    while change_ids.len() < RECORDED_CHANGE_IDS.len() {
+        match rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(IndexerEvent::Tick { change_id, .. }) => {
+                change_ids.insert(change_id.to_string());
+            }
+            Ok(IndexerEvent::Error(Error::Deserialize { .. })) => errors += 1,
+            Ok(_) => {}
+            Err(e) => panic!("Receiving IndexerEvent failed: {e}"),
+        }
+    }
+    indexer.stop();
+
+    // Pages are only complete if they were read entirely
+    assert_eq!(
+        change_ids,
+        HashSet::from(RECORDED_CHANGE_IDS.map(String::from))
+    );
+    assert!(errors > 0);
+}
+
+#[test]
+fn test_sync_indexer_gives_up_on_truncated_pages_after_retry_budget() {
+    use stash_api::{
+        common::retry::{Backoff, RetryPolicy},
+        sync::indexer::{Indexer, IndexerEvent},
+    };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let endpoints = {
+        let _guard = runtime.enter();
+        start_mock_river_with(|config| config.with_truncated_every(1))
+    };
+
+    let mut indexer =
+        Indexer::new()
+            .with_endpoints(endpoints)
+            .with_retry_policy(RetryPolicy::uniform(Backoff::new(
+                2,
+                Duration::from_millis(10),
+                Duration::from_millis(10),
+            )));
+    let rx = indexer
+        .start_at_change_id(
+            "client".into(),
+            "secret".into(),
+            ChangeId::from_str(FIRST_CHANGE_ID).unwrap(),
+        )
+        .expect("starting indexer");
+
+    let mut events = vec![];
+    loop {
+        match rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(IndexerEvent::Stop) => break,
+            Ok(event) => events.push(event),
+            Err(e) => panic!("Receiving IndexerEvent failed: {e}"),
+        }
+    }
+
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, IndexerEvent::Tick { .. })));
+    assert!(matches!(
+        events.last(),
+        Some(IndexerEvent::RetriesExhausted { attempts: 2, .. })
+    ));
+}
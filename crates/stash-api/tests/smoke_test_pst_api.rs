@@ -1,7 +1,8 @@
+use futures::StreamExt;
 use stash_api::{
     common::{
         poe_api::{get_oauth_token, get_oauth_token_sync},
-        poe_ninja_client,
+        poe_ninja_client, Endpoints,
     },
     r#async::indexer::Indexer,
 };
@@ -16,15 +17,24 @@ fn extract_credentials_from_env() -> (String, String) {
 #[tokio::test]
 async fn test_pst_api_oauth_async() {
     let (client_id, client_secret) = extract_credentials_from_env();
-    get_oauth_token(&client_id, &client_secret)
-        .await
-        .expect("fetching OAuth token");
+    get_oauth_token(
+        Endpoints::default().oauth_token(),
+        &client_id,
+        &client_secret,
+    )
+    .await
+    .expect("fetching OAuth token");
 }
 
 #[test]
 fn test_pst_api_oauth() {
     let (client_id, client_secret) = extract_credentials_from_env();
-    get_oauth_token_sync(&client_id, &client_secret).expect("fetching OAuth token");
+    get_oauth_token_sync(
+        Endpoints::default().oauth_token(),
+        &client_id,
+        &client_secret,
+    )
+    .expect("fetching OAuth token");
 }
 
 #[tokio::test]
@@ -32,15 +42,17 @@ async fn test_stream_pst_api() {
     let (client_id, client_secret) = extract_credentials_from_env();
 
     let indexer = Indexer::new();
-    let change_id = poe_ninja_client::PoeNinjaClient::fetch_latest_change_id_async()
+    let change_id = poe_ninja_client::PoeNinjaClient::default()
+        .fetch_latest_change_id_async()
         .await
         .expect("fetch latest change id");
-    let mut rx = indexer
+    let mut stream = indexer
         .start_at_change_id(client_id, client_secret, change_id)
-        .await;
+        .await
+        .expect("start indexer");
 
     let mut counter = 0;
-    while (rx.recv().await).is_some() {
+    while stream.next().await.is_some() {
         counter += 1;
         if counter == 2 {
             break;
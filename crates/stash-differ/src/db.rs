@@ -2,7 +2,7 @@ use std::collections::{HashSet, VecDeque};
 
 use sqlx::{Pool, Postgres};
 
-use crate::stash::StashRecord;
+use crate::stash::{stash_record_from_row, StashRecord};
 
 pub struct StashRecordIterator<'a> {
     pool: &'a Pool<Postgres>,
@@ -105,14 +105,16 @@ async fn fetch_stash_records_paginated(
     end: i64,
     league: &str,
 ) -> Result<Vec<StashRecord>, sqlx::Error> {
-    sqlx::query_as::<_, StashRecord>(
-        "SELECT change_id, next_change_id, stash_id, account_name, league, public, items, created_at
+    sqlx::query(
+        "SELECT created_at, change_id, next_change_id, stash_id, stash_type, items, public,
+                account_name, last_character_name, stash_name, league, chunk_id
              FROM stash_records
              WHERE league = $1 and int8range($2, $3, '[]') @> int8range(id, id, '[]')",
     )
     .bind(league)
     .bind(start)
     .bind(end)
+    .try_map(|row| stash_record_from_row(&row))
     .fetch_all(pool)
     .await
 }
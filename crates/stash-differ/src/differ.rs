@@ -20,7 +20,12 @@ impl StashDiffer {
 
     pub fn diff_stash(before: &Stash, after: &Stash, buffer: &mut Vec<DiffEvent>) {
         for (item_id, before_item) in before.content.iter() {
-            let item_age = Some(before_item.birthday.map_or(0, |b| before.update_count - b));
+            let item_age = Some(
+                before
+                    .birthdays
+                    .get(item_id)
+                    .map_or(0, |b| before.update_count - b),
+            );
 
             if let Some(after_item) = after.content.get(item_id) {
                 // Check for changed notes
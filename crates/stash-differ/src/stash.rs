@@ -1,7 +1,9 @@
-use serde::Deserialize;
 use sqlx::{postgres::PgRow, Row};
 use std::collections::HashMap;
 
+use stash_api::common::STASH_RECORD_VERSION;
+pub use stash_api::common::{Item, StashRecord};
+
 pub type AccountName = String;
 pub type ItemId = String;
 pub type StashId = String;
@@ -32,6 +34,8 @@ impl AccountStash {
 pub struct Stash {
     pub stash_id: StashId,
     pub content: HashMap<ItemId, Item>,
+    /// The `update_count` at which items were first seen.
+    pub birthdays: HashMap<ItemId, u64>,
     pub update_count: u64,
 }
 
@@ -74,58 +78,33 @@ impl From<StashRecord> for Stash {
                 .into_iter()
                 .map(|i| (i.id.clone(), i))
                 .collect(),
+            birthdays: HashMap::new(),
             update_count: 0,
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct StashRecord {
-    pub change_id: String,
-    pub next_change_id: String,
-    pub stash_id: String,
-    pub items: Vec<Item>,
-    pub account_name: Option<String>,
-    pub league: Option<String>,
-    pub public: bool,
-    pub created_at: sqlx::types::chrono::NaiveDateTime,
-}
-
-#[derive(Debug, Clone, Deserialize, Default)]
-pub struct Item {
-    pub id: String,
-    pub type_line: String,
-    pub note: Option<String>,
-    pub stack_size: Option<u32>,
-    pub birthday: Option<u64>,
-}
-
-impl Item {
-    #[allow(dead_code)]
-    pub fn new(id: impl Into<String>, type_line: impl Into<String>) -> Self {
-        Self {
-            id: id.into(),
-            type_line: type_line.into(),
-            ..Self::default()
-        }
-    }
-}
-
-impl<'r> sqlx::FromRow<'r, PgRow> for StashRecord {
-    fn from_row(row: &'r PgRow) -> Result<Self, sqlx::Error> {
-        Ok(StashRecord {
-            change_id: row.try_get("change_id")?,
-            next_change_id: row.try_get("next_change_id")?,
-            stash_id: row.try_get("stash_id")?,
-            account_name: row.try_get::<Option<String>, &str>("account_name")?,
-            league: row.try_get::<Option<String>, &str>("league")?,
-            items: row
-                .try_get::<sqlx::types::Json<Vec<Item>>, &str>("items")?
-                .0,
-            public: row.try_get("public")?,
-            created_at: row.try_get("created_at")?,
-        })
-    }
+/// Reads a `StashRecord` from a row of the indexer's `stash_records` table.
+pub fn stash_record_from_row(row: &PgRow) -> Result<StashRecord, sqlx::Error> {
+    Ok(StashRecord {
+        version: STASH_RECORD_VERSION,
+        created_at: row.try_get("created_at")?,
+        change_id: row.try_get("change_id")?,
+        next_change_id: row.try_get("next_change_id")?,
+        stash_id: row.try_get("stash_id")?,
+        stash_type: row.try_get("stash_type")?,
+        items: row
+            .try_get::<sqlx::types::Json<Vec<Item>>, &str>("items")?
+            .0,
+        public: row.try_get("public")?,
+        account_name: row.try_get::<Option<String>, &str>("account_name")?,
+        last_character_name: row.try_get::<Option<String>, &str>("last_character_name")?,
+        stash_name: row.try_get::<Option<String>, &str>("stash_name")?,
+        league: row.try_get::<Option<String>, &str>("league")?,
+        chunk_id: row
+            .try_get::<Option<i64>, &str>("chunk_id")?
+            .unwrap_or_default(),
+    })
 }
 
 pub fn group_stash_records_by_account_name(
@@ -152,11 +131,19 @@ mod tests {
 
     use super::{AccountStash, Item};
 
+    fn item(id: &str, type_line: &str) -> Item {
+        Item {
+            id: id.into(),
+            type_line: type_line.into(),
+            ..Item::default()
+        }
+    }
+
     #[test]
     fn test_diffing_happens_on_stash_level() {
         let account_before = AccountStash::new().with_stash(
             "Stash1",
-            Stash::new().with_item("Item A", Item::new("Item A", "Unique")),
+            Stash::new().with_item("Item A", item("Item A", "Unique")),
         );
         let account_after = AccountStash::new().with_stash("Stash1", Stash::new());
 
@@ -168,7 +155,7 @@ mod tests {
     fn test_diffing_does_not_happen_on_account_level() {
         let account_before = AccountStash::new().with_stash(
             "Stash1",
-            Stash::new().with_item("Item A", Item::new("Item A", "Unique")),
+            Stash::new().with_item("Item A", item("Item A", "Unique")),
         );
         let account_after = AccountStash::new().with_stash("Stash2", Stash::new());
 
@@ -5,14 +5,12 @@ use futures::StreamExt;
 use lapin::options::BasicAckOptions;
 
 use sqlx::{Execute, Pool, Postgres, QueryBuilder};
+use stash_api::common::StashRecord;
 use tracing::{info, trace};
 use trade_common::{assets::AssetIndex, league::League};
 
 use crate::{
-    config::Config,
-    metrics::store::StoreMetrics,
-    source::{retry_setup_consumer, StashRecord},
-    store::Offer,
+    config::Config, metrics::store::StoreMetrics, source::retry_setup_consumer, store::Offer,
 };
 
 pub async fn setup_rabbitmq_consumer(
@@ -56,7 +54,7 @@ async fn consume(
 
     let ingestable_stashes = stash_records
         .into_iter()
-        .filter(|s| s.league.eq(league.to_str()))
+        .filter(|s| s.is_supported() && s.league.as_deref() == Some(league.to_str()))
         .collect::<Vec<_>>();
 
     ingest(metrics, pool, league, asset_index, ingestable_stashes).await?;
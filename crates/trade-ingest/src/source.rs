@@ -1,35 +1,16 @@
 use std::{fs::File, io::BufReader, path::Path};
 
-use chrono::NaiveDateTime;
 use lapin::{
     options::{BasicConsumeOptions, QueueBindOptions, QueueDeclareOptions},
     types::FieldTable,
     Connection, ConnectionProperties, Consumer, Result,
 };
-use serde::Deserialize;
+use stash_api::common::StashRecord;
 use tracing::{error, info};
 use trade_common::league::League;
 
 use crate::config::Config;
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct StashRecord {
-    pub stash_id: String,
-    pub league: String,
-    pub account_name: String,
-    pub items: Vec<Item>,
-    pub created_at: NaiveDateTime,
-}
-
-#[derive(Debug, Clone, Deserialize)]
-pub struct Item {
-    pub id: String,
-    pub name: String,
-    pub type_line: String,
-    pub note: Option<String>,
-    pub stack_size: Option<u32>,
-}
-
 pub struct ExampleStream {
     stash_records: Vec<StashRecord>,
 }
@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
 use std::{fmt::Debug, hash::Hash};
 
-use crate::{note_parser::PriceParser, source::StashRecord};
+use stash_api::common::StashRecord;
+
+use crate::note_parser::PriceParser;
 
 type StashId = String;
 type ItemId = String;
@@ -23,7 +25,10 @@ pub struct Offer {
 
 impl From<StashRecord> for Vec<Offer> {
     fn from(stash: StashRecord) -> Self {
-        let account_name = stash.account_name;
+        let account_name = match stash.account_name {
+            Some(account_name) => account_name,
+            None => return vec![],
+        };
         let stash_id = stash.stash_id;
         let price_parser = PriceParser::new();
 